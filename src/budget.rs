@@ -0,0 +1,149 @@
+use crate::hal::constants::{EP_MEM_SIZE, EP_REGISTERS_SIZE};
+
+// buffer alignment of the endpoint memory allocator
+const ALIGN: usize = 64;
+
+/// USB RAM needed by an endpoint plan, computed the way the bus allocates
+/// it, so a plan that doesn't fit can be rejected at compile time with
+/// [`assert_usb_ram_fits!`](crate::assert_usb_ram_fits) rather than with
+/// `EndpointMemoryOverflow` during enumeration.
+///
+/// Add the endpoints in the order they are allocated: the classes' in the
+/// order they are created, then EP0, which `usb-device` allocates when the
+/// `UsbDevice` is built.
+///
+/// ```
+/// use lpc55_usbhs::{assert_usb_ram_fits, UsbRamPlan};
+///
+/// const PLAN: UsbRamPlan = UsbRamPlan::new()
+///     .out_endpoint(512, 0)
+///     .in_endpoint(512)
+///     .in_endpoint(8)
+///     .control(64);
+///
+/// assert_usb_ram_fits!(PLAN);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsbRamPlan {
+    used: usize,
+}
+
+impl UsbRamPlan {
+    /// USB RAM available to the bus, see the `usb-ram-section` feature.
+    pub const CAPACITY: usize = EP_MEM_SIZE;
+
+    /// Just the endpoint list.
+    pub const fn new() -> Self {
+        Self {
+            used: EP_REGISTERS_SIZE,
+        }
+    }
+
+    const fn buffer(self, size: usize) -> Self {
+        Self {
+            used: self.used.div_ceil(ALIGN) * ALIGN + size,
+        }
+    }
+
+    // continuing where a bus's allocator is at
+    pub(crate) const fn from_used(used: usize) -> Self {
+        Self { used }
+    }
+
+    /// EP0, with its OUT, SETUP and IN buffers, OUT with the spare byte of
+    /// [`Quirks::ep0_out_spare_byte`](crate::Quirks::ep0_out_spare_byte).
+    pub const fn control(self, max_packet_size: u16) -> Self {
+        let max_packet_size = max_packet_size as usize;
+        self.buffer(max_packet_size + 1)
+            .buffer(8)
+            .buffer(max_packet_size)
+    }
+
+    /// A non-control OUT endpoint on a bus with the given
+    /// [`out_buffer_size`](crate::UsbConfig::out_buffer_size).
+    pub const fn out_endpoint(self, max_packet_size: u16, out_buffer_size: usize) -> Self {
+        self.buffer(buffer_size(max_packet_size, out_buffer_size))
+    }
+
+    /// A non-control IN endpoint.
+    pub const fn in_endpoint(self, max_packet_size: u16) -> Self {
+        self.in_endpoint_buffered(max_packet_size, 0)
+    }
+
+    /// A non-control IN endpoint on a bus with the given
+    /// [`in_buffer_size`](crate::UsbConfig::in_buffer_size).
+    pub const fn in_endpoint_buffered(self, max_packet_size: u16, in_buffer_size: usize) -> Self {
+        self.buffer(buffer_size(max_packet_size, in_buffer_size))
+    }
+
+    /// Bytes of USB RAM the plan takes up.
+    pub const fn used(&self) -> usize {
+        self.used
+    }
+
+    pub const fn fits(&self) -> bool {
+        self.used <= Self::CAPACITY
+    }
+}
+
+// A non-control buffer of at least `min_size`, in whole packets
+pub(crate) const fn buffer_size(max_packet_size: u16, min_size: usize) -> usize {
+    let max_packet_size = max_packet_size as usize;
+    if min_size > max_packet_size && max_packet_size > 0 {
+        min_size.div_ceil(max_packet_size) * max_packet_size
+    } else {
+        max_packet_size
+    }
+}
+
+impl Default for UsbRamPlan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fail the build if a [`UsbRamPlan`] doesn't fit into USB RAM.
+#[macro_export]
+macro_rules! assert_usb_ram_fits {
+    ($plan:expr) => {
+        const _: () = ::core::assert!(
+            $crate::UsbRamPlan::fits(&$plan),
+            "endpoint plan exceeds USB RAM"
+        );
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_whole_packets() {
+        assert_eq!(buffer_size(512, 0), 512);
+        assert_eq!(buffer_size(512, 512), 512);
+        assert_eq!(buffer_size(512, 513), 1024);
+        assert_eq!(buffer_size(64, 1000), 1024);
+        assert_eq!(buffer_size(0, 100), 0);
+    }
+
+    #[test]
+    fn plan_aligns_every_buffer() {
+        // the 96 byte endpoint list, then 65, 8 and 64 bytes at 128, 256
+        // and 320
+        assert_eq!(UsbRamPlan::new().used(), 96);
+        assert_eq!(UsbRamPlan::new().control(64).used(), 384);
+        assert_eq!(UsbRamPlan::new().in_endpoint(8).in_endpoint(8).used(), 200);
+        assert_eq!(
+            UsbRamPlan::new().out_endpoint(64, 100).used(),
+            UsbRamPlan::new().in_endpoint(128).used()
+        );
+    }
+
+    #[test]
+    fn fits_up_to_capacity() {
+        assert!(UsbRamPlan::new().fits());
+        let full = UsbRamPlan::from_used(UsbRamPlan::CAPACITY);
+        assert!(full.fits());
+        assert!(!full.in_endpoint(8).fits());
+    }
+}
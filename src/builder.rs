@@ -0,0 +1,282 @@
+use cortex_m::peripheral::SYST;
+use embedded_hal::delay::DelayNs;
+use lpc55_hal::{
+    drivers::{clocks::Clocks, timer::Timer},
+    peripherals::ctimer,
+    time::DurationExtensions,
+    traits::wg::timer::CountDown,
+    typestates::init_state,
+    Anactrl, Pmc, Syscon, Usbhs,
+};
+
+use crate::{
+    error::ClockTooSlow,
+    phy::{Crystal, PhyTuning},
+    power::{DefaultPowerSequencer, PowerSequencer},
+    usbhs::{systick_delay, UsbHS},
+};
+
+/// Init options of [`UsbHS`].
+///
+/// The defaults are what [`UsbHS::new`] does: 16 MHz crystal, PHY trim left
+/// at its reset values and high speed. Policy that may change from session
+/// to session is in [`UsbConfig`](crate::UsbConfig).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct UsbHsBuilder<P = DefaultPowerSequencer> {
+    pub(crate) crystal: Crystal,
+    pub(crate) phy_tuning: Option<PhyTuning>,
+    pub(crate) force_fs: bool,
+    pub(crate) take_over_from_rom: bool,
+    pub(crate) watchdog: Option<WatchdogFeed>,
+    pub(crate) power: P,
+}
+
+/// Called during the driver's own busy-waits, see
+/// [`UsbHsBuilder::watchdog_feed`].
+pub type WatchdogFeed = fn();
+
+impl UsbHsBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<P: PowerSequencer> UsbHsBuilder<P> {
+    /// Slowest system (AHB) clock the controller works with.
+    pub const MIN_SYSTEM_HZ: u32 = 96_000_000;
+
+    /// Fail unless the system clock is at least [`MIN_SYSTEM_HZ`](Self::MIN_SYSTEM_HZ).
+    ///
+    /// The controller comes up with a slower clock just fine, but then
+    /// fails with bus errors in the middle of transfers. The `build` methods
+    /// check the clock they find in SYSCON anyway, this is for failing
+    /// before the peripherals are handed over.
+    pub fn check_system_frequency(self, hz: u32) -> Result<Self, ClockTooSlow> {
+        if hz < Self::MIN_SYSTEM_HZ {
+            return Err(ClockTooSlow {
+                actual_hz: Some(hz),
+            });
+        }
+        Ok(self)
+    }
+
+    /// Like [`check_system_frequency`](Self::check_system_frequency), for the
+    /// frozen HAL clock configuration.
+    pub fn check_clocks(self, clocks: &Clocks) -> Result<Self, ClockTooSlow> {
+        match clocks.support_usbhs_token() {
+            Some(_) => Ok(self),
+            None => Err(ClockTooSlow { actual_hz: None }),
+        }
+    }
+
+    /// Power up the PHY's clock source and the PHY with `power` instead of
+    /// the [`DefaultPowerSequencer`].
+    pub fn power_sequencer<Q: PowerSequencer>(self, power: Q) -> UsbHsBuilder<Q> {
+        UsbHsBuilder {
+            crystal: self.crystal,
+            phy_tuning: self.phy_tuning,
+            force_fs: self.force_fs,
+            take_over_from_rom: self.take_over_from_rom,
+            watchdog: self.watchdog,
+            power,
+        }
+    }
+
+    /// The crystal on `XTAL32M`, which clocks the USB PLL.
+    pub fn crystal(mut self, crystal: Crystal) -> Self {
+        self.crystal = crystal;
+        self
+    }
+
+    pub fn phy_tuning(mut self, tuning: PhyTuning) -> Self {
+        self.phy_tuning = Some(tuning);
+        self
+    }
+
+    /// Never chirp, i.e. stay at full speed (`DEVCMDSTAT.FORCE_FS`), e.g. for
+    /// bring-up or when the board's HS signal quality is in doubt.
+    ///
+    /// Only then may EP0 be allocated with a max packet size of 8, 16 or 32
+    /// bytes; high speed requires 64, which `UsbDeviceBuilder` has to be
+    /// told with `max_packet_size_0(64)` as it defaults to 8.
+    pub fn force_full_speed(mut self, force_fs: bool) -> Self {
+        self.force_fs = force_fs;
+        self
+    }
+
+    /// Have `feed` called at least every millisecond of the init delays, and
+    /// during the bus's own busy-waits later on (taking a buffer back from
+    /// the hardware to stall an endpoint, PLL relock on resume, the PHY
+    /// reinit and remote wakeup delays), for a tight window watchdog.
+    ///
+    /// The spins call it on every iteration, so with a windowed watchdog
+    /// `feed` has to skip calls that come before the window opens.
+    pub fn watchdog_feed(mut self, feed: WatchdogFeed) -> Self {
+        self.watchdog = Some(feed);
+        self
+    }
+
+    /// Quiesce what the boot ROM's USB ISP/DFU left behind before the normal
+    /// init, for firmware that may be started from it.
+    ///
+    /// The ROM leaves USB1 enabled, connected and with its interrupts enabled;
+    /// resetting it right away makes the pull-up blink for too short a time
+    /// for the host to notice, which shows up as flaky enumeration. With this
+    /// set, init first masks the USB1 interrupts in the NVIC, disables and
+    /// disconnects the controller and stays disconnected for 10 ms, before it
+    /// resets the controller, its endpoint list and the PHY as usual.
+    pub fn take_over_from_rom(mut self, take_over: bool) -> Self {
+        self.take_over_from_rom = take_over;
+        self
+    }
+
+    /// Fails before touching any peripheral if `known_hz`, or what SYSCON
+    /// says the system clock runs at, is below [`MIN_SYSTEM_HZ`](Self::MIN_SYSTEM_HZ).
+    /// Otherwise the system clock, or the minimum if it can't be told.
+    fn require_system_clock(&self, known_hz: Option<u32>) -> Result<u32, ClockTooSlow> {
+        match system_clock_hz(self.crystal).or(known_hz) {
+            Some(hz) if hz < Self::MIN_SYSTEM_HZ => Err(ClockTooSlow {
+                actual_hz: Some(hz),
+            }),
+            hz => Ok(hz.unwrap_or(Self::MIN_SYSTEM_HZ)),
+        }
+    }
+
+    /// Bring up the controller, timing the settling delays with a CTIMER.
+    ///
+    /// Fails if the system clock, as configured in SYSCON, is slower than
+    /// [`MIN_SYSTEM_HZ`](Self::MIN_SYSTEM_HZ). A configuration the driver
+    /// can't work out the frequency of (a fractional PLL0) isn't refused.
+    pub fn build(
+        mut self,
+        usb: Usbhs,
+        syscon: &mut Syscon,
+        pmc: &mut Pmc,
+        _anactrl: &Anactrl,
+        timer: &mut Timer<impl ctimer::Ctimer<init_state::Enabled>>,
+    ) -> Result<UsbHS, ClockTooSlow> {
+        let core_hz = self.require_system_clock(None)?;
+        Ok(UsbHS::init(usb, syscon, pmc, &mut self, core_hz, |us| {
+            timer.start(us.microseconds());
+            nb::block!(timer.wait()).ok();
+        }))
+    }
+
+    /// Like [`build`](Self::build), with the delays of [`UsbHS::new_with_systick`].
+    pub fn build_with_systick(
+        mut self,
+        usb: Usbhs,
+        syscon: &mut Syscon,
+        pmc: &mut Pmc,
+        _anactrl: &Anactrl,
+        syst: &mut SYST,
+        core_hz: u32,
+    ) -> Result<UsbHS, ClockTooSlow> {
+        let system_hz = self.require_system_clock(Some(core_hz))?;
+        Ok(UsbHS::init(usb, syscon, pmc, &mut self, system_hz, |us| {
+            systick_delay(syst, core_hz, us)
+        }))
+    }
+
+    /// Like [`build`](Self::build), timing the delays with any embedded-hal
+    /// 1.0 delay, e.g. one an RTIC v2 application already has, rather than a
+    /// CTIMER set up just for bring-up.
+    pub fn build_with_delay(
+        mut self,
+        usb: Usbhs,
+        syscon: &mut Syscon,
+        pmc: &mut Pmc,
+        _anactrl: &Anactrl,
+        delay: &mut impl DelayNs,
+    ) -> Result<UsbHS, ClockTooSlow> {
+        let core_hz = self.require_system_clock(None)?;
+        Ok(UsbHS::init(usb, syscon, pmc, &mut self, core_hz, |us| {
+            delay.delay_us(us)
+        }))
+    }
+
+    /// Like [`build`](Self::build), with the delays of [`UsbHS::new_with_cpu_freq`].
+    pub fn build_with_cpu_freq(
+        mut self,
+        usb: Usbhs,
+        syscon: &mut Syscon,
+        pmc: &mut Pmc,
+        _anactrl: &Anactrl,
+        cpu_hz: u32,
+    ) -> Result<UsbHS, ClockTooSlow> {
+        let core_hz = self.require_system_clock(Some(cpu_hz))?;
+        Ok(UsbHS::init(usb, syscon, pmc, &mut self, core_hz, |us| {
+            let cycles = u64::from(cpu_hz) * u64::from(us) / 1_000_000;
+            cortex_m::asm::delay(cycles.min(u64::from(u32::MAX)) as u32);
+        }))
+    }
+}
+
+/// System (AHB) clock frequency from the SYSCON clock selection, `CLKIN`
+/// being `crystal`. `None` for a PLL0 with spread spectrum or a fractional
+/// multiplier, or a clock source that doesn't exist.
+pub(crate) fn system_clock_hz(crystal: Crystal) -> Option<u32> {
+    // SAFETY: reads only
+    let syscon = unsafe { &*lpc55_hal::raw::SYSCON::ptr() };
+    let source = |sel: u8| match sel {
+        0 => Some(12_000_000),
+        1 => Some(crystal.hz()),
+        2 => Some(1_000_000),
+        3 => Some(32_768),
+        _ => None,
+    };
+    // Fout = Fin * M / N / (2 * P), with each step bypassable
+    let pll = |fin: u32, bypass: bool, prediv: bool, post: bool, post2: bool, m, n, p| {
+        if bypass {
+            return Some(fin);
+        }
+        let n = if prediv { 1 } else { u64::from(n) };
+        let mut fout = u64::from(fin).checked_mul(u64::from(m))?.checked_div(n)?;
+        if !post {
+            fout = fout.checked_div(u64::from(p))?;
+            if !post2 {
+                fout /= 2;
+            }
+        }
+        u32::try_from(fout).ok()
+    };
+
+    let main = match syscon.mainclkselb.read().sel().bits() {
+        0 => match syscon.mainclksela.read().sel().bits() {
+            3 => 96_000_000,
+            sel => source(sel)?,
+        },
+        1 => {
+            let sscg1 = syscon.pll0sscg1.read();
+            if !sscg1.sel_ext().bit() {
+                return None;
+            }
+            let ctrl = syscon.pll0ctrl.read();
+            pll(
+                source(syscon.pll0clksel.read().sel().bits())?,
+                ctrl.bypasspll().bit(),
+                ctrl.bypassprediv().bit(),
+                ctrl.bypasspostdiv().bit(),
+                ctrl.bypasspostdiv2().bit(),
+                sscg1.mdiv_ext().bits(),
+                syscon.pll0ndec.read().ndiv().bits(),
+                syscon.pll0pdec.read().pdiv().bits(),
+            )?
+        }
+        2 => {
+            let ctrl = syscon.pll1ctrl.read();
+            pll(
+                source(syscon.pll1clksel.read().sel().bits())?,
+                ctrl.bypasspll().bit(),
+                ctrl.bypassprediv().bit(),
+                ctrl.bypasspostdiv().bit(),
+                ctrl.bypasspostdiv2().bit(),
+                syscon.pll1mdec.read().mdiv().bits(),
+                syscon.pll1ndec.read().ndiv().bits(),
+                syscon.pll1pdec.read().pdiv().bits(),
+            )?
+        }
+        _ => 32_768,
+    };
+    Some(main / (u32::from(syscon.ahbclkdiv.read().div().bits()) + 1))
+}
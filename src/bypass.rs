@@ -0,0 +1,67 @@
+use crate::usbbus::UsbHSBus;
+use cortex_m::interrupt::CriticalSection;
+use usb_device::{endpoint::EndpointAddress, Result, UsbDirection};
+
+/// Control transfers handled outside of `usb-device`, e.g. for vendor
+/// protocols with out-of-spec control behaviour, see
+/// [`UsbHSBus::set_control_bypass`].
+///
+/// `poll()` offers every SETUP packet to `matches`. A matching request and
+/// the EP0 events that follow it, up to the next SETUP, go to `handler`
+/// instead of being reported to `usb-device`, which never sees the
+/// transfer. Both are called from `poll()` within its critical section.
+#[derive(Clone, Copy, Debug)]
+pub struct ControlBypass {
+    pub matches: fn(&[u8; 8]) -> bool,
+    pub handler: fn(ControlEvent, &mut RawControl<'_>),
+}
+
+/// What a [`ControlBypass`] handler is called for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ControlEvent {
+    /// The matching SETUP packet, which is consumed already.
+    Setup([u8; 8]),
+    /// A data stage or status stage packet is waiting to be read.
+    Out,
+    /// The host took what was written last.
+    InComplete,
+}
+
+/// Direct access to EP0 for a [`ControlBypass`] handler.
+///
+/// It is up to the handler to go through the data and status stages: write
+/// the data of an IN request and read the host's status ZLP, or read the
+/// data of an OUT request (or none) and write a ZLP as status.
+pub struct RawControl<'a> {
+    pub(crate) bus: &'a UsbHSBus,
+    pub(crate) cs: &'a CriticalSection,
+}
+
+impl RawControl<'_> {
+    /// Read the waiting OUT packet, see [`UsbBus::read`](usb_device::bus::UsbBus::read).
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let ep_addr = EndpointAddress::from_parts(0, UsbDirection::Out);
+        self.bus
+            .read_packet_in(self.cs, ep_addr, buf)
+            .map(|packet| packet.len)
+    }
+
+    /// Write an IN packet, see [`UsbBus::write`](usb_device::bus::UsbBus::write).
+    pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let ep_addr = EndpointAddress::from_parts(0, UsbDirection::In);
+        self.bus.write_in(self.cs, ep_addr, buf)
+    }
+
+    /// Abort the transfer and hand EP0 back to the hardware, see
+    /// [`BusTransaction::ack_setup`](crate::BusTransaction::ack_setup). The
+    /// packets the host still sends up to its next SETUP come to the
+    /// handler as before.
+    pub fn ack_setup(&mut self) {
+        self.bus.ack_setup_in(self.cs);
+    }
+
+    /// Refuse the request, until the next SETUP.
+    pub fn stall(&mut self) {
+        self.bus.stall_control(self.cs);
+    }
+}
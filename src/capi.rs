@@ -0,0 +1,272 @@
+//! `extern "C"` interface for firmware whose USB stack is written in C,
+//! behind the `capi` feature.
+//!
+//! It drives one bus, the device side of `usb-device`'s `UsbBus` without
+//! `usb-device` itself: the C stack allocates the endpoints (EP0 included),
+//! enables the bus, and then handles the `poll` results, including bus
+//! resets and `SET_ADDRESS`. Build the crate as a static library with
+//! `cargo rustc --release --features capi --crate-type staticlib`.
+//!
+//! Functions returning `int32_t` return a non-negative value on success and
+//! one of the `LPC55_USBHS_E*` codes otherwise. Endpoint addresses are USB
+//! addresses, with bit 7 set for IN.
+
+use crate::{UsbConfig, UsbHS, UsbHSBus, UsbHsBuilder};
+use core::{cell::RefCell, slice};
+use cortex_m::interrupt::{self, Mutex};
+use lpc55_hal::{raw, Anactrl, Pmc, Syscon, Usbhs};
+use usb_device::{
+    bus::{PollResult, UsbBus},
+    endpoint::{EndpointAddress, EndpointType},
+    UsbError,
+};
+
+pub const LPC55_USBHS_EWOULDBLOCK: i32 = -1;
+pub const LPC55_USBHS_EPARSE: i32 = -2;
+pub const LPC55_USBHS_EOVERFLOW: i32 = -3;
+pub const LPC55_USBHS_EENDPOINTS: i32 = -4;
+pub const LPC55_USBHS_ENOMEM: i32 = -5;
+pub const LPC55_USBHS_EINVALIDEP: i32 = -6;
+pub const LPC55_USBHS_EUNSUPPORTED: i32 = -7;
+pub const LPC55_USBHS_ESTATE: i32 = -8;
+/// `lpc55_usbhs_init` wasn't called, or was called twice.
+pub const LPC55_USBHS_EINIT: i32 = -9;
+/// The system clock is below the controller's 96 MHz minimum.
+pub const LPC55_USBHS_ECLOCK: i32 = -10;
+/// A NULL buffer with a non-zero length.
+pub const LPC55_USBHS_ENULL: i32 = -11;
+/// The `usb-ram-section` reservation doesn't start at USB1 SRAM.
+pub const LPC55_USBHS_EUSBRAM: i32 = -12;
+
+pub const LPC55_USBHS_POLL_NONE: u8 = 0;
+pub const LPC55_USBHS_POLL_DATA: u8 = 1;
+pub const LPC55_USBHS_POLL_RESET: u8 = 2;
+pub const LPC55_USBHS_POLL_SUSPEND: u8 = 3;
+pub const LPC55_USBHS_POLL_RESUME: u8 = 4;
+
+/// Result of [`lpc55_usbhs_poll`]. The endpoint bitmaps are only set for
+/// `LPC55_USBHS_POLL_DATA`, bit `n` standing for endpoint number `n`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lpc55UsbhsPoll {
+    pub event: u8,
+    pub ep_out: u16,
+    pub ep_in_complete: u16,
+    pub ep_setup: u16,
+}
+
+static BUS: Mutex<RefCell<Option<UsbHSBus>>> = Mutex::new(RefCell::new(None));
+
+fn error_code(err: UsbError) -> i32 {
+    match err {
+        UsbError::WouldBlock => LPC55_USBHS_EWOULDBLOCK,
+        UsbError::ParseError => LPC55_USBHS_EPARSE,
+        UsbError::BufferOverflow => LPC55_USBHS_EOVERFLOW,
+        UsbError::EndpointOverflow => LPC55_USBHS_EENDPOINTS,
+        UsbError::EndpointMemoryOverflow => LPC55_USBHS_ENOMEM,
+        UsbError::InvalidEndpoint => LPC55_USBHS_EINVALIDEP,
+        UsbError::Unsupported => LPC55_USBHS_EUNSUPPORTED,
+        UsbError::InvalidState => LPC55_USBHS_ESTATE,
+    }
+}
+
+fn with_bus(f: impl FnOnce(&mut UsbHSBus) -> i32) -> i32 {
+    interrupt::free(|cs| match BUS.borrow(cs).borrow_mut().as_mut() {
+        Some(bus) => f(bus),
+        None => LPC55_USBHS_EINIT,
+    })
+}
+
+/// Bring up the controller and PHY with the default [`UsbHsBuilder`] and
+/// [`UsbConfig`], busy-waiting the PHY delays at `cpu_hz`.
+///
+/// # Safety
+/// Takes the USB1, USBPHY, USBHSH, SYSCON, PMC and ANACTRL peripherals
+/// without their singletons, so no Rust code may own them. Fails with
+/// `LPC55_USBHS_ECLOCK`, before touching any of them, if the system clock
+/// runs slower than 96 MHz, and with `LPC55_USBHS_EUSBRAM` if the
+/// `usb-ram-section` reservation isn't at USB1 SRAM.
+#[no_mangle]
+pub unsafe extern "C" fn lpc55_usbhs_init(cpu_hz: u32) -> i32 {
+    if interrupt::free(|cs| BUS.borrow(cs).borrow().is_some()) {
+        return LPC55_USBHS_EINIT;
+    }
+
+    let pac = raw::Peripherals::steal();
+    let usb = Usbhs::from((pac.USBPHY, pac.USB1, pac.USBHSH));
+    let mut syscon = Syscon::from(pac.SYSCON);
+    let mut pmc = Pmc::from(pac.PMC);
+    let anactrl = Anactrl::from(pac.ANACTRL);
+    let usb: UsbHS =
+        match UsbHsBuilder::new().build_with_cpu_freq(usb, &mut syscon, &mut pmc, &anactrl, cpu_hz)
+        {
+            Ok(usb) => usb,
+            Err(_) => return LPC55_USBHS_ECLOCK,
+        };
+
+    let bus = match UsbHSBus::new_bus(usb, UsbConfig::default()) {
+        Ok(bus) => bus,
+        Err(_) => return LPC55_USBHS_EUSBRAM,
+    };
+    interrupt::free(|cs| BUS.borrow(cs).replace(Some(bus)));
+    0
+}
+
+/// Allocate the endpoint at `ep_addr`, of type `ep_type` as in the endpoint
+/// descriptor's `bmAttributes` (0 control, 1 isochronous, 2 bulk,
+/// 3 interrupt). Returns the endpoint address, or `LPC55_USBHS_EINVALIDEP`
+/// for an endpoint number the controller doesn't have.
+#[no_mangle]
+pub extern "C" fn lpc55_usbhs_alloc_ep(
+    ep_addr: u8,
+    ep_type: u8,
+    max_packet_size: u16,
+    interval: u8,
+) -> i32 {
+    let ep_type = match ep_type {
+        0 => EndpointType::Control,
+        1 => EndpointType::Isochronous,
+        2 => EndpointType::Bulk,
+        3 => EndpointType::Interrupt,
+        _ => return LPC55_USBHS_EUNSUPPORTED,
+    };
+    let ep_addr = EndpointAddress::from(ep_addr);
+    with_bus(|bus| {
+        match bus.alloc_ep(
+            ep_addr.direction(),
+            Some(ep_addr),
+            ep_type,
+            max_packet_size,
+            interval,
+        ) {
+            Ok(addr) => i32::from(u8::from(addr)),
+            Err(err) => error_code(err),
+        }
+    })
+}
+
+/// Enable the controller once all endpoints are allocated, and connect.
+#[no_mangle]
+pub extern "C" fn lpc55_usbhs_enable() -> i32 {
+    with_bus(|bus| {
+        bus.enable();
+        0
+    })
+}
+
+/// Disconnect and disable the controller. Endpoints allocated afterwards
+/// start from nothing, so the next `lpc55_usbhs_enable` can enumerate with
+/// a different set of them.
+#[no_mangle]
+pub extern "C" fn lpc55_usbhs_disable() -> i32 {
+    with_bus(|bus| {
+        bus.disable();
+        0
+    })
+}
+
+/// Reset the endpoints after `LPC55_USBHS_POLL_RESET`.
+#[no_mangle]
+pub extern "C" fn lpc55_usbhs_reset() -> i32 {
+    with_bus(|bus| {
+        bus.reset();
+        0
+    })
+}
+
+/// Apply the address of a `SET_ADDRESS` request right away, before its
+/// status stage, as `usb-device` does.
+#[no_mangle]
+pub extern "C" fn lpc55_usbhs_set_address(addr: u8) -> i32 {
+    with_bus(|bus| {
+        bus.set_device_address(addr);
+        0
+    })
+}
+
+/// # Safety
+/// `result` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn lpc55_usbhs_poll(result: *mut Lpc55UsbhsPoll) -> i32 {
+    with_bus(|bus| {
+        let mut poll = Lpc55UsbhsPoll::default();
+        poll.event = match bus.poll() {
+            PollResult::None => LPC55_USBHS_POLL_NONE,
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            } => {
+                poll.ep_out = ep_out;
+                poll.ep_in_complete = ep_in_complete;
+                poll.ep_setup = ep_setup;
+                LPC55_USBHS_POLL_DATA
+            }
+            PollResult::Reset => LPC55_USBHS_POLL_RESET,
+            PollResult::Suspend => LPC55_USBHS_POLL_SUSPEND,
+            PollResult::Resume => LPC55_USBHS_POLL_RESUME,
+        };
+        result.write(poll);
+        0
+    })
+}
+
+/// Read an OUT or SETUP packet into `buf`. Returns its length.
+///
+/// # Safety
+/// `buf` must be valid for writes of `len` bytes; it may be NULL if `len`
+/// is 0.
+#[no_mangle]
+pub unsafe extern "C" fn lpc55_usbhs_read(ep_addr: u8, buf: *mut u8, len: usize) -> i32 {
+    let buf: &mut [u8] = match (buf.is_null(), len) {
+        (_, 0) => &mut [],
+        (true, _) => return LPC55_USBHS_ENULL,
+        (false, len) => slice::from_raw_parts_mut(buf, len),
+    };
+    with_bus(|bus| match bus.read(EndpointAddress::from(ep_addr), buf) {
+        Ok(len) => len as i32,
+        Err(err) => error_code(err),
+    })
+}
+
+/// Write an IN packet. Returns its length.
+///
+/// # Safety
+/// `buf` must be valid for reads of `len` bytes; it may be NULL if `len`
+/// is 0, for a ZLP.
+#[no_mangle]
+pub unsafe extern "C" fn lpc55_usbhs_write(ep_addr: u8, buf: *const u8, len: usize) -> i32 {
+    let buf: &[u8] = match (buf.is_null(), len) {
+        (_, 0) => &[],
+        (true, _) => return LPC55_USBHS_ENULL,
+        (false, len) => slice::from_raw_parts(buf, len),
+    };
+    with_bus(|bus| match bus.write(EndpointAddress::from(ep_addr), buf) {
+        Ok(len) => len as i32,
+        Err(err) => error_code(err),
+    })
+}
+
+/// Abort the current control transfer and hand EP0 back to the hardware the
+/// way the user manual asks for after a SETUP: both buffers deactivated and
+/// unstalled, `DEVCMDSTAT.SETUP` cleared, OUT re-armed. A SETUP not read
+/// yet is dropped. `lpc55_usbhs_poll` does this on its own for every SETUP.
+#[no_mangle]
+pub extern "C" fn lpc55_usbhs_ack_setup() -> i32 {
+    with_bus(|bus| {
+        interrupt::free(|cs| bus.ack_setup_in(cs));
+        0
+    })
+}
+
+/// Fails with `LPC55_USBHS_EINVALIDEP` for an isochronous endpoint or one
+/// the controller doesn't have.
+#[no_mangle]
+pub extern "C" fn lpc55_usbhs_set_stalled(ep_addr: u8, stalled: bool) -> i32 {
+    with_bus(
+        |bus| match bus.set_endpoint_stalled(EndpointAddress::from(ep_addr), stalled) {
+            Ok(()) => 0,
+            Err(err) => error_code(err),
+        },
+    )
+}
@@ -0,0 +1,158 @@
+use crate::{lpm::LpmPolicy, phy::SuspendMode, power::PowerProfile};
+
+/// When `poll()` acknowledges endpoint interrupts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InterruptAck {
+    /// An interrupt stays set for as long as its event waits for the class:
+    /// OUT interrupts until `read()` consumes the packet. IN completions are
+    /// consumed by being reported, `usb-device` hands them on right away.
+    #[default]
+    OnConsume,
+    /// `poll()` acknowledges every endpoint interrupt it decodes. Unread OUT
+    /// packets are still reported on every poll, but no longer keep the USB
+    /// interrupt asserted, for classes that apply back-pressure by leaving
+    /// packets unread.
+    OnReport,
+}
+
+/// Disconnect while VBUS is gone, as self-powered devices must, see
+/// [`UsbConfig::vbus_detach`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VbusDetach {
+    /// How long VBUS has to be back before connecting again. Needs a clock,
+    /// see [`set_clock`](crate::UsbHSBus::set_clock); without one the bus
+    /// connects right away.
+    pub reconnect_debounce_ms: u32,
+}
+
+/// Take an endpoint out of service after too many anomalies in a row, see
+/// [`UsbConfig::error_limit`] for which ones count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorLimit {
+    /// Consecutive [`OutInterruptWhileActive`](crate::UsbHsError::OutInterruptWhileActive),
+    /// [`InInterruptWhileActive`](crate::UsbHsError::InInterruptWhileActive)
+    /// or [`AutoZlpFailed`](crate::UsbHsError::AutoZlpFailed) reports on
+    /// one endpoint direction that trip the limit, at least 1
+    pub errors: u8,
+    pub action: ErrorLimitAction,
+}
+
+/// What happens to an endpoint that hit its [`ErrorLimit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorLimitAction {
+    /// Halt it, until the host clears the halt or the class unstalls it.
+    /// Isochronous endpoints, which can't be halted, are disabled instead.
+    #[default]
+    Stall,
+    /// Disable it in the endpoint list, until the next bus reset or
+    /// [`enable_endpoint`](crate::UsbHSBus::enable_endpoint)
+    Disable,
+}
+
+/// Where `poll()` is called from, see
+/// [`set_service_mode`](crate::UsbHSBus::set_service_mode).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ServiceMode {
+    /// Bus and endpoint events raise `USB1`, whose handler polls
+    #[default]
+    Interrupt,
+    /// `USB1` is never raised (`INTEN` cleared), the main loop polls
+    Polled,
+}
+
+/// Per-session policy of a [`UsbHSBus`](crate::UsbHSBus), applied when the
+/// bus is enabled.
+///
+/// One-time hardware bring-up is up to [`UsbHsBuilder`](crate::UsbHsBuilder);
+/// this is what may differ between firmware modes that build a new bus on
+/// the same [`UsbHS`](crate::UsbHS), see [`UsbHS::reclaim`](crate::UsbHS::reclaim).
+/// The setters on the bus override it at runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UsbConfig {
+    /// Interrupt on NAKed EP0 IN tokens, see
+    /// [`set_control_nak_interrupts`](crate::UsbHSBus::set_control_nak_interrupts)
+    pub control_nak_in: bool,
+    /// Interrupt on NAKed EP0 OUT tokens
+    pub control_nak_out: bool,
+    /// Interrupt on NAKed IN tokens of the other endpoints, see
+    /// [`set_nak_interrupts`](crate::UsbHSBus::set_nak_interrupts)
+    pub nak_in: bool,
+    /// Interrupt on NAKed OUT tokens of the other endpoints
+    pub nak_out: bool,
+    /// Turn [`nak_in`](Self::nak_in) on whenever `poll()` finds nothing in
+    /// flight: no IN packet armed, no OUT packet unread, no control
+    /// transfer going on. Then only SETUPs, OUT data, bus events and the
+    /// host polling an IN endpoint raise `USB1`, so a battery device can
+    /// sleep until the host wants something, see
+    /// [`take_nak_wakes`](crate::UsbHSBus::take_nak_wakes). Off again as
+    /// soon as something is armed, so busy endpoints don't interrupt on
+    /// every NAK.
+    ///
+    /// Each IN endpoint wakes once: the host NAK-polls a pending read every
+    /// (micro)frame, so its interrupt stays masked from the first NAK until
+    /// it is written to. The SOF interrupt is off while idle too.
+    pub idle_nak_wake: bool,
+    pub lpm: LpmPolicy,
+    /// Follow every non-control IN write of a whole number of max-size
+    /// packets by a ZLP before reporting it complete, for classes that write each
+    /// transfer in one go and never end one on a packet boundary otherwise.
+    pub auto_zlp: bool,
+    pub suspend_mode: SuspendMode,
+    pub interrupt_ack: InterruptAck,
+    pub service_mode: ServiceMode,
+    /// Minimum size of non-control OUT buffers, see
+    /// [`new_with_out_buffer_size`](crate::UsbHSBus::new_with_out_buffer_size)
+    pub out_buffer_size: usize,
+    /// Minimum size of non-control IN buffers, rounded up to whole packets.
+    /// A `write()` of up to that many bytes goes out as back-to-back packets
+    /// with one completion, see
+    /// [`set_in_refill`](crate::UsbHSBus::set_in_refill) for keeping bulk IN
+    /// at wire speed.
+    pub in_buffer_size: usize,
+    /// `None` to have the bus power the HS PHY and its LDO up in the PMC in
+    /// `enable()`, restarting the PHY if a previous session powered it down,
+    /// and down again once disabled; hooks to call in their place instead.
+    pub power_profile: Option<PowerProfile>,
+    /// USB RAM actually present or left to the bus, if less than the 16 KiB
+    /// (or `LPC55_USBHS_RAM_SIZE`) assumed at build time. The part
+    /// information in `DEVICE_ID0` doesn't tell, so the application has to,
+    /// e.g. from the part number it was flashed for.
+    pub usb_ram_size: Option<usize>,
+    /// Watch `DEVCMDSTAT.VBUS_DEBOUNCED` in `poll()`: on VBUS loss, clear
+    /// `DCON` and broadcast [`BusEvent::Detached`](crate::BusEvent::Detached),
+    /// reconnect once it returns. Only for boards that route VBUS to
+    /// `USB1_VBUS`. A VBUS change doesn't raise the USB interrupt, so keep
+    /// polling while detached, e.g. from a timer.
+    pub vbus_detach: Option<VbusDetach>,
+    /// Stall or disable a non-control endpoint on which `poll()` sees the
+    /// same direction misbehave this many times without a good transfer in
+    /// between, and report [`UsbHsError::ErrorLimitReached`]. Keeps a
+    /// broken host or cable from tying the CPU up in endpoint interrupts.
+    ///
+    /// Only the anomalies the driver can pin on an endpoint count:
+    /// [`UsbHsError::OutInterruptWhileActive`],
+    /// [`UsbHsError::InInterruptWhileActive`] and
+    /// [`UsbHsError::AutoZlpFailed`]. Transaction errors (CRC, bit stuffing,
+    /// timeouts, data toggle, see [`UsbHsError::TransactionError`]) don't:
+    /// the controller only keeps the last one in `INFO.ERR_CODE`, for the
+    /// bus as a whole. A failed transaction doesn't complete the buffer, so
+    /// it raises no endpoint interrupt either; the host retries it.
+    ///
+    /// [`UsbHsError::OutInterruptWhileActive`]: crate::UsbHsError::OutInterruptWhileActive
+    /// [`UsbHsError::InInterruptWhileActive`]: crate::UsbHsError::InInterruptWhileActive
+    /// [`UsbHsError::AutoZlpFailed`]: crate::UsbHsError::AutoZlpFailed
+    /// [`UsbHsError::TransactionError`]: crate::UsbHsError::TransactionError
+    /// [`UsbHsError::ErrorLimitReached`]: crate::UsbHsError::ErrorLimitReached
+    pub error_limit: Option<ErrorLimit>,
+    /// Answer `SET_FEATURE(TEST_MODE)` in the bus, before usb-device or a
+    /// [`ControlBypass`](crate::ControlBypass) sees it, and enter the
+    /// selected [`TestMode`](crate::TestMode) once its status stage is
+    /// through. For compliance test firmware.
+    pub test_mode_requests: bool,
+    /// Keep the OUT endpoints other than EP0 NAKing after the host
+    /// configures the device, until
+    /// [`open_endpoints`](crate::UsbHSBus::open_endpoints), so nothing
+    /// arrives before the application is ready for it. IN endpoints NAK
+    /// until written anyway.
+    pub hold_endpoints: bool,
+}
@@ -0,0 +1,400 @@
+//! Diagnostics that are not needed for normal operation.
+
+use core::cell::Cell;
+use cortex_m::interrupt::{self, Mutex};
+use lpc55_hal::raw::{USB1, USBPHY};
+use usb_device::endpoint::EndpointAddress;
+
+/// NAKs the device sent on the control endpoint, counted while the
+/// corresponding interrupt-on-NAK is enabled.
+///
+/// A steadily growing count during enumeration means the host keeps retrying
+/// a stage the device never armed, the usual cause of enumeration timeouts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ControlNakCounts {
+    /// NAKed EP0 IN tokens (`DEVCMDSTAT.INTONNAK_CI`)
+    pub in_naks: u32,
+    /// NAKed EP0 OUT tokens (`DEVCMDSTAT.INTONNAK_CO`)
+    pub out_naks: u32,
+}
+
+/// Speed the controller negotiated at the last bus reset (`DEVCMDSTAT.SPEED`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkSpeed {
+    /// No reset seen yet
+    Unknown,
+    Full,
+    High,
+}
+
+/// Link status bits of the controller and PHY.
+///
+/// The part doesn't expose the D+/D- levels themselves, so some distinctions
+/// need more than one snapshot, see [`diagnose`](Self::diagnose).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineStatus {
+    /// VBUS detected, after debouncing (`DEVCMDSTAT.VBUS_DEBOUNCED`)
+    pub vbus: bool,
+    /// D+ pull-up enabled (`DEVCMDSTAT.DCON`)
+    pub connected: bool,
+    /// Bus suspended (`DEVCMDSTAT.DSUS`)
+    pub suspended: bool,
+    /// A bus reset happened since the previous snapshot (`DEVCMDSTAT.DRES_C`,
+    /// cleared by taking the snapshot). Not whether SE0 is held right now.
+    pub reset_seen: bool,
+    pub speed: LinkSpeed,
+    /// Resume signalling seen by the PHY (`USBPHY STATUS.RESUME_STATUS`)
+    pub resume: bool,
+    /// Error code of the last transaction (`INFO.ERR_CODE`), 0 for none
+    pub err_code: u8,
+}
+
+/// Best guess at why a device doesn't enumerate, from a [`LineStatus`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineDiagnosis {
+    /// No VBUS: no cable or no host. Only meaningful where the board routes
+    /// VBUS to `USB1_VBUS`.
+    NoHost,
+    /// The pull-up is off, so the host can't see the device.
+    Disconnected,
+    /// Connected, but the host hasn't reset the bus yet.
+    WaitingForReset,
+    /// The host reset the bus since the previous snapshot. Showing up on
+    /// every snapshot means the host keeps resetting the device, or SE0 is
+    /// held, e.g. a shorted or missing data line.
+    ResetSeen,
+    /// The bus came out of reset at full speed: the HS chirp handshake
+    /// failed, or the host or hub is full-speed only.
+    ChirpFailed,
+    /// Running at high speed; any problem is above the link layer.
+    HighSpeed,
+}
+
+impl LinkSpeed {
+    pub(crate) fn read(usb: &USB1) -> Self {
+        match usb.devcmdstat.read().speed().bits() {
+            1 => LinkSpeed::Full,
+            2 => LinkSpeed::High,
+            _ => LinkSpeed::Unknown,
+        }
+    }
+}
+
+impl LineStatus {
+    pub(crate) fn read(usb: &USB1, phy: &USBPHY) -> Self {
+        let devcmdstat = usb.devcmdstat.read();
+        Self {
+            vbus: devcmdstat.vbus_debounced().bit_is_set(),
+            connected: devcmdstat.dcon().bit_is_set(),
+            suspended: devcmdstat.dsus().bit_is_set(),
+            reset_seen: devcmdstat.dres_c().bit_is_set(),
+            speed: LinkSpeed::read(usb),
+            resume: phy.status.read().resume_status().bit_is_set(),
+            err_code: usb.info.read().err_code().bits(),
+        }
+    }
+
+    pub fn diagnose(&self) -> LineDiagnosis {
+        if !self.vbus {
+            LineDiagnosis::NoHost
+        } else if !self.connected {
+            LineDiagnosis::Disconnected
+        } else if self.reset_seen {
+            LineDiagnosis::ResetSeen
+        } else {
+            match self.speed {
+                LinkSpeed::Unknown => LineDiagnosis::WaitingForReset,
+                LinkSpeed::Full => LineDiagnosis::ChirpFailed,
+                LinkSpeed::High => LineDiagnosis::HighSpeed,
+            }
+        }
+    }
+}
+
+/// Raw copy of the PHY registers that decide whether the link comes up.
+///
+/// Meant for logging and comparing against the user manual or a known-good
+/// board; nothing is decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhyRegisters {
+    pub ctrl: u32,
+    pub pll_sic: u32,
+    pub pwd: u32,
+    pub tx: u32,
+    pub status: u32,
+}
+
+impl PhyRegisters {
+    pub(crate) fn read(phy: &USBPHY) -> Self {
+        Self {
+            ctrl: phy.ctrl.read().bits(),
+            pll_sic: phy.pll_sic.read().bits(),
+            pwd: phy.pwd.read().bits(),
+            tx: phy.tx.read().bits(),
+            status: phy.status.read().bits(),
+        }
+    }
+}
+
+/// How far the device got through enumeration since the last bus reset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnumerationState {
+    #[default]
+    Default,
+    Addressed,
+    Configured,
+}
+
+/// Enumeration progress since the last bus reset, for reporting the time
+/// to enumerate or spotting a host that gave up halfway.
+///
+/// Timestamps come from [`UsbHSBus::set_clock`](crate::UsbHSBus::set_clock)
+/// and are `None` without a clock, or for steps not reached yet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EnumerationRecord {
+    pub state: EnumerationState,
+    pub reset_ms: Option<u32>,
+    pub addressed_ms: Option<u32>,
+    pub configured_ms: Option<u32>,
+}
+
+impl EnumerationRecord {
+    /// Time from the bus reset to the device being configured.
+    pub fn time_to_configure_ms(&self) -> Option<u32> {
+        Some(self.configured_ms?.wrapping_sub(self.reset_ms?))
+    }
+}
+
+static ALLOCATION_FAILURE: Mutex<Cell<Option<AllocationFailure>>> = Mutex::new(Cell::new(None));
+
+/// An endpoint buffer that didn't fit into USB RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocationFailure {
+    pub ep_addr: EndpointAddress,
+    /// Bytes requested for the buffer
+    pub size: usize,
+    /// Bytes that were still free
+    pub remaining: usize,
+}
+
+/// The last endpoint allocation that failed with `EndpointMemoryOverflow`.
+///
+/// Kept outside the bus, as classes tend to panic on allocation errors
+/// before the application ever gets hold of it: read it from the panic
+/// handler or a debugger. See also [`UsbRamPlan`](crate::UsbRamPlan).
+pub fn allocation_failure() -> Option<AllocationFailure> {
+    interrupt::free(|cs| ALLOCATION_FAILURE.borrow(cs).get())
+}
+
+pub(crate) fn record_allocation_failure(failure: AllocationFailure) {
+    interrupt::free(|cs| ALLOCATION_FAILURE.borrow(cs).set(Some(failure)));
+}
+
+/// Cycles from `poll()` seeing an IN endpoint complete to the next `write()`
+/// re-arming it, counted by the DWT cycle counter.
+///
+/// At high speed a bulk endpoint that isn't re-armed within a microframe
+/// or so loses bandwidth. The time from the interrupt to `poll()` running
+/// isn't included, it depends on the application's interrupt priorities.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RearmLatency {
+    /// Number of re-arms measured, saturating
+    pub count: u32,
+    /// Shortest latency, in core clock cycles; 0 before the first
+    pub min: u32,
+    /// Longest latency, in core clock cycles
+    pub max: u32,
+    total: u64,
+}
+
+#[cfg(feature = "metrics")]
+impl RearmLatency {
+    /// Average latency in core clock cycles, `None` before the first
+    /// re-arm.
+    pub fn mean(&self) -> Option<u32> {
+        // never more than `max`, so it fits
+        Some(self.total.checked_div(u64::from(self.count))? as u32)
+    }
+
+    pub(crate) fn record(&mut self, cycles: u32) {
+        self.min = if self.count == 0 {
+            cycles
+        } else {
+            self.min.min(cycles)
+        };
+        self.max = self.max.max(cycles);
+        self.total += u64::from(cycles);
+        self.count = self.count.saturating_add(1);
+    }
+}
+
+/// Packets and bytes an endpoint moved in one direction, see
+/// [`UsbHSBus::throughput`](crate::UsbHSBus::throughput).
+/// Both counters wrap.
+///
+/// OUT counts what `read()` handed out, SETUP packets included, IN what the
+/// host acknowledged, ZLPs included.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Throughput {
+    pub packets: u32,
+    pub bytes: u32,
+}
+
+#[cfg(feature = "metrics")]
+impl Throughput {
+    pub(crate) fn record(&mut self, len: usize) {
+        self.packets = self.packets.wrapping_add(1);
+        self.bytes = self.bytes.wrapping_add(len as u32);
+    }
+}
+
+/// Number of timestamps a [`ResetHistory`] keeps.
+#[cfg(feature = "metrics")]
+pub const RESET_HISTORY_LEN: usize = 8;
+
+/// Bus resets, with the times of the last [`RESET_HISTORY_LEN`] on the
+/// [`set_clock`](crate::UsbHSBus::set_clock) clock, see
+/// [`UsbHSBus::reset_history`](crate::UsbHSBus::reset_history).
+///
+/// A host resets a device once or twice while enumerating it; resets
+/// piling up after that point to a bad cable or a marginal hub.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResetHistory {
+    /// Resets counted, wrapping, including those without a clock
+    pub count: u32,
+    times_ms: [u32; RESET_HISTORY_LEN],
+    stamped: u32,
+}
+
+#[cfg(feature = "metrics")]
+impl ResetHistory {
+    pub(crate) fn record(&mut self, now_ms: Option<u32>) {
+        self.count = self.count.wrapping_add(1);
+        if let Some(now_ms) = now_ms {
+            self.times_ms[self.stamped as usize % RESET_HISTORY_LEN] = now_ms;
+            self.stamped = self.stamped.wrapping_add(1);
+        }
+    }
+
+    /// Times of the last resets, oldest first.
+    pub fn times_ms(&self) -> impl Iterator<Item = u32> + '_ {
+        let count = (self.stamped as usize).min(RESET_HISTORY_LEN) as u32;
+        let first = self.stamped.wrapping_sub(count);
+        (0..count).map(move |i| self.times_ms[first.wrapping_add(i) as usize % RESET_HISTORY_LEN])
+    }
+
+    /// Resets among the kept ones in the `window_ms` up to `now_ms`.
+    pub fn recent(&self, now_ms: u32, window_ms: u32) -> usize {
+        self.times_ms()
+            .filter(|&t| now_ms.wrapping_sub(t) <= window_ms)
+            .count()
+    }
+}
+
+/// Number of SETUP packets a [`SetupCapture`] keeps.
+#[cfg(feature = "setup-capture")]
+pub const SETUP_CAPTURE_LEN: usize = 16;
+
+/// A SETUP packet, numbered in the order the host sent them.
+#[cfg(feature = "setup-capture")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CapturedSetup {
+    pub seq: u32,
+    pub packet: [u8; 8],
+}
+
+/// Ring of the last [`SETUP_CAPTURE_LEN`] SETUP packets, for finding the
+/// request a host rejected the device over.
+#[cfg(feature = "setup-capture")]
+#[derive(Clone, Copy, Debug)]
+pub struct SetupCapture {
+    entries: [CapturedSetup; SETUP_CAPTURE_LEN],
+    next_seq: u32,
+}
+
+#[cfg(feature = "setup-capture")]
+impl SetupCapture {
+    pub(crate) const fn new() -> Self {
+        Self {
+            entries: [CapturedSetup {
+                seq: 0,
+                packet: [0; 8],
+            }; SETUP_CAPTURE_LEN],
+            next_seq: 0,
+        }
+    }
+
+    pub(crate) fn push(&mut self, packet: [u8; 8]) {
+        let seq = self.next_seq;
+        self.entries[seq as usize % SETUP_CAPTURE_LEN] = CapturedSetup { seq, packet };
+        self.next_seq = seq.wrapping_add(1);
+    }
+
+    /// The captured packets, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &CapturedSetup> {
+        let count = (self.next_seq as usize).min(SETUP_CAPTURE_LEN) as u32;
+        let first = self.next_seq.wrapping_sub(count);
+        (0..count).map(move |i| &self.entries[first.wrapping_add(i) as usize % SETUP_CAPTURE_LEN])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[allow(unused_imports)]
+    use super::*;
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn reset_history_keeps_the_last_times() {
+        let mut history = ResetHistory::default();
+        assert_eq!(history.times_ms().count(), 0);
+
+        history.record(None);
+        for t in 1..=10 {
+            history.record(Some(t * 100));
+        }
+        assert_eq!(history.count, 11);
+        let times: std::vec::Vec<u32> = history.times_ms().collect();
+        assert_eq!(times, [300, 400, 500, 600, 700, 800, 900, 1000]);
+
+        assert_eq!(history.recent(1000, 250), 3);
+        // across a wrap of the clock
+        let mut history = ResetHistory::default();
+        history.record(Some(u32::MAX - 10));
+        history.record(Some(5));
+        assert_eq!(history.recent(20, 100), 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn rearm_latency_mean() {
+        let mut latency = RearmLatency::default();
+        assert_eq!(latency.mean(), None);
+        for cycles in [10, 20, 60] {
+            latency.record(cycles);
+        }
+        assert_eq!((latency.count, latency.min, latency.max), (3, 10, 60));
+        assert_eq!(latency.mean(), Some(30));
+    }
+
+    #[cfg(feature = "setup-capture")]
+    #[test]
+    fn setup_capture_wraps_oldest_first() {
+        let mut capture = SetupCapture::new();
+        assert_eq!(capture.iter().count(), 0);
+
+        for i in 0..SETUP_CAPTURE_LEN as u8 + 3 {
+            capture.push([i; 8]);
+        }
+        let seqs: std::vec::Vec<u32> = capture.iter().map(|setup| setup.seq).collect();
+        assert_eq!(
+            seqs,
+            (3..SETUP_CAPTURE_LEN as u32 + 3).collect::<std::vec::Vec<_>>()
+        );
+        let first = capture.iter().next().unwrap();
+        assert_eq!(first.packet, [3; 8]);
+    }
+}
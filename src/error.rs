@@ -0,0 +1,56 @@
+use usb_device::endpoint::EndpointAddress;
+
+/// Hardware anomalies the bus works around, reported to the handler set with
+/// [`UsbHSBus::on_error`](crate::UsbHSBus::on_error), and why the bus
+/// couldn't be built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UsbHsError {
+    /// An OUT interrupt on a non-control endpoint whose buffer is still
+    /// armed, with interrupt-on-NAK off. Reported to `usb-device` as usual,
+    /// `read()` then finds nothing to read.
+    OutInterruptWhileActive { index: u8 },
+    /// An IN interrupt on a non-control endpoint whose buffer is still
+    /// armed, with interrupt-on-NAK off. Left pending, it completes the
+    /// write once the hardware lets go of the buffer.
+    InInterruptWhileActive { index: u8 },
+    /// The ZLP following a full packet (see
+    /// [`UsbConfig::auto_zlp`](crate::UsbConfig::auto_zlp)) couldn't be
+    /// queued, the transfer is reported complete without it.
+    AutoZlpFailed { index: u8 },
+    /// The link came up at full speed without `FORCE_FS`, see
+    /// [`UsbHSBus::link_speed`](crate::UsbHSBus::link_speed).
+    FullSpeedFallback,
+    /// `INFO.ERR_CODE` changed to a non-zero value, see the user manual for
+    /// the codes. Not tied to an endpoint, so not counted against the
+    /// [`UsbConfig::error_limit`](crate::UsbConfig::error_limit).
+    TransactionError { err_code: u8 },
+    /// The endpoint hit the [`UsbConfig::error_limit`](crate::UsbConfig::error_limit)
+    /// and got stalled or disabled.
+    ErrorLimitReached { ep_addr: EndpointAddress },
+    /// The USB PLL didn't lock when coming out of a
+    /// [`SuspendMode::LowPower`](crate::SuspendMode::LowPower) suspend; the
+    /// PHY is running again, but at a frequency the host may not accept.
+    /// [`UsbHS::reinit_phy`](crate::UsbHS::reinit_phy) starts it over.
+    PllLockTimeout,
+    /// `poll()` was called from another execution context than the one
+    /// that owns the events, and returned nothing, see
+    /// [`UsbHSBus::release_poller`](crate::UsbHSBus::release_poller).
+    ForeignPoll,
+    /// The `.usb_ram` reservation of the `usb-ram-section` feature doesn't
+    /// start at USB1 SRAM, see the linker script. Returned by
+    /// [`UsbHSBus::new`](crate::UsbHSBus::new) and its variants.
+    UsbRamMisplaced,
+}
+
+pub type ErrorHandler = fn(UsbHsError);
+
+/// The system clock is slower than the controller needs, returned by
+/// [`UsbHsBuilder::build`](crate::UsbHsBuilder::build) and its variants,
+/// and [`UsbHsBuilder::check_system_frequency`](crate::UsbHsBuilder::check_system_frequency).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockTooSlow {
+    /// `None` when checked against a HAL clock configuration, which doesn't
+    /// expose it
+    pub actual_hz: Option<u32>,
+}
@@ -0,0 +1,71 @@
+use crate::{
+    error::UsbHsError,
+    lpm::{L1Exit, LpmRequest},
+};
+use core::ptr;
+
+/// Bus event broadcast to the listeners of
+/// [`UsbHSBus::subscribe`](crate::UsbHSBus::subscribe).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusEvent {
+    Reset,
+    /// L2 suspend
+    Suspend,
+    /// Resume from L2 suspend
+    Resume,
+    /// The link entered L1 after an LPM token; `poll()` reports it as a
+    /// suspend.
+    L1Entered(LpmRequest),
+    /// The link left L1; `poll()` reports it as a resume.
+    L1Exited(L1Exit),
+    /// See [`UsbHSBus::mark_configured`](crate::UsbHSBus::mark_configured).
+    Configured,
+    /// See [`UsbHSBus::on_error`](crate::UsbHSBus::on_error).
+    Error(UsbHsError),
+    /// VBUS went away and the bus disconnected, see
+    /// [`UsbConfig::vbus_detach`](crate::UsbConfig::vbus_detach).
+    Detached,
+    /// VBUS is back and the bus connected again.
+    Attached,
+}
+
+/// Receiver of [`BusEvent`]s. Listeners run inside the critical section of
+/// whatever raised the event, mostly `poll()`, so keep them short.
+pub type EventListener = fn(BusEvent);
+
+/// Number of listeners a bus takes.
+pub const MAX_LISTENERS: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Listeners([Option<EventListener>; MAX_LISTENERS]);
+
+impl Listeners {
+    // `false` if all slots are taken
+    pub fn subscribe(&mut self, listener: EventListener) -> bool {
+        match self.0.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(listener);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unsubscribe(&mut self, listener: EventListener) {
+        for slot in self.0.iter_mut() {
+            if slot.is_some_and(|l| ptr::fn_addr_eq(l, listener)) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(Option::is_none)
+    }
+
+    pub fn broadcast(&self, event: BusEvent) {
+        for listener in self.0.iter().flatten() {
+            listener(event);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::debug::LinkSpeed;
+
+/// A reading of the frame counter (`INFO.FRAME_NR`), see
+/// [`UsbHSBus::frame_number`](crate::UsbHSBus::frame_number).
+///
+/// It counts 1 ms frames at either speed: the eight microframes of a
+/// high-speed frame share its number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameNumber(pub u16);
+
+impl FrameNumber {
+    /// The counter wraps after this many frames.
+    pub const WRAP: u16 = 2048;
+
+    /// Milliseconds from `earlier` to `self`, across a wrap. Only right for
+    /// less than [`WRAP`](Self::WRAP) ms, see [`FrameStopwatch`] for more.
+    pub const fn ms_since(self, earlier: FrameNumber) -> u16 {
+        self.0.wrapping_sub(earlier.0) % Self::WRAP
+    }
+}
+
+/// Milliseconds of `sofs` start-of-frame interrupts, e.g. counted from
+/// `FRAME_INT`: eight per ms at high speed, one at full speed. `None` while
+/// the speed isn't known.
+pub const fn sofs_to_ms(sofs: u32, speed: LinkSpeed) -> Option<u32> {
+    match speed {
+        LinkSpeed::High => Some(sofs / 8),
+        LinkSpeed::Full => Some(sofs),
+        LinkSpeed::Unknown => None,
+    }
+}
+
+/// Milliseconds since a frame, for timeouts longer than the counter's
+/// 2048 ms wrap, e.g. an MSC command timeout. Keeps up as long as
+/// [`update`](Self::update) is called at least every 2 s, e.g. from
+/// `poll()`.
+///
+/// The counter only runs while the host sends SOFs, so time in suspend
+/// isn't counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameStopwatch {
+    last: FrameNumber,
+    elapsed_ms: u32,
+}
+
+impl FrameStopwatch {
+    pub const fn start(now: FrameNumber) -> Self {
+        Self {
+            last: now,
+            elapsed_ms: 0,
+        }
+    }
+
+    /// Add the time up to `now` and return the total.
+    pub fn update(&mut self, now: FrameNumber) -> u32 {
+        let delta = u32::from(now.ms_since(self.last));
+        self.elapsed_ms = self.elapsed_ms.saturating_add(delta);
+        self.last = now;
+        self.elapsed_ms
+    }
+
+    /// The total as of the last [`update`](Self::update).
+    pub const fn elapsed_ms(&self) -> u32 {
+        self.elapsed_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ms_since_across_the_wrap() {
+        assert_eq!(FrameNumber(10).ms_since(FrameNumber(4)), 6);
+        assert_eq!(FrameNumber(3).ms_since(FrameNumber(2045)), 6);
+        assert_eq!(FrameNumber(7).ms_since(FrameNumber(7)), 0);
+    }
+
+    #[test]
+    fn stopwatch_keeps_counting_past_the_wrap() {
+        let mut stopwatch = FrameStopwatch::start(FrameNumber(2000));
+        assert_eq!(stopwatch.update(FrameNumber(100)), 148);
+        assert_eq!(stopwatch.update(FrameNumber(1100)), 1148);
+        assert_eq!(stopwatch.update(FrameNumber(50)), 2146);
+        assert_eq!(stopwatch.elapsed_ms(), 2146);
+    }
+
+    #[test]
+    fn sofs_by_speed() {
+        assert_eq!(sofs_to_ms(80, LinkSpeed::High), Some(10));
+        assert_eq!(sofs_to_ms(80, LinkSpeed::Full), Some(80));
+        assert_eq!(sofs_to_ms(80, LinkSpeed::Unknown), None);
+    }
+}
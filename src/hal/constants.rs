@@ -7,5 +7,33 @@ pub const BYTES_PER_EP_REGISTER: usize = 4 * 4;
 
 pub const USB1_SRAM_ADDR: usize = 0x4010_0000;
 pub const EP_MEM_ADDR: usize = USB1_SRAM_ADDR;
+#[cfg(not(feature = "usb-ram-section"))]
 pub const EP_MEM_SIZE: usize = 0x4000;
+/// Size of the `.usb_ram` reservation, `LPC55_USBHS_RAM_SIZE` (in bytes) at
+/// build time or all of USB1 SRAM
+#[cfg(feature = "usb-ram-section")]
+pub const EP_MEM_SIZE: usize = match option_env!("LPC55_USBHS_RAM_SIZE") {
+    Some(size) => parse_size(size),
+    None => 0x4000,
+};
+
 pub const EP_REGISTERS_SIZE: usize = NUM_ENDPOINTS * BYTES_PER_EP_REGISTER;
+
+#[cfg(feature = "usb-ram-section")]
+const fn parse_size(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    assert!(!bytes.is_empty(), "LPC55_USBHS_RAM_SIZE is empty");
+    let mut size = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = bytes[i];
+        assert!(
+            digit.is_ascii_digit(),
+            "LPC55_USBHS_RAM_SIZE must be a decimal number"
+        );
+        size = size * 10 + (digit - b'0') as usize;
+        i += 1;
+    }
+    assert!(size <= 0x4000, "LPC55_USBHS_RAM_SIZE exceeds USB1 SRAM");
+    size
+}
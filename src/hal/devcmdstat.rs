@@ -0,0 +1,15 @@
+use lpc55_hal::raw::{usb1::devcmdstat::W, USB1};
+
+// SETUP, DCON_C, DSUS_C and DRES_C are write-one-to-clear
+const CHANGE_FLAGS: u32 = (1 << 8) | (1 << 24) | (1 << 25) | (1 << 26);
+
+/// Read-modify-write DEVCMDSTAT without acknowledging pending change flags.
+pub fn modify<F>(usb: &USB1, f: F)
+where
+    F: FnOnce(&mut W) -> &mut W,
+{
+    usb.devcmdstat.modify(|r, w| {
+        unsafe { w.bits(r.bits() & !CHANGE_FLAGS) };
+        f(w)
+    });
+}
@@ -1,5 +1,5 @@
 use super::constants::{UsbAccessType, EP_MEM_ADDR, EP_MEM_SIZE, EP_REGISTERS_SIZE};
-use core::{cmp::min, slice};
+use core::slice;
 use usb_device::{Result, UsbError};
 use vcell::VolatileCell;
 
@@ -16,18 +16,32 @@ impl EndpointBuffer {
         Self(mem)
     }
 
-    pub fn read(&self, buf: &mut [u8]) {
-        let count = min(buf.len(), self.0.len());
-        for (i, entry) in buf.iter_mut().enumerate().take(count) {
-            *entry = self.0[i].get();
+    pub fn read(&self, buf: &mut [u8]) -> Result<()> {
+        if buf.len() > self.0.len() {
+            return Err(UsbError::BufferOverflow);
         }
+        for (entry, cell) in buf.iter_mut().zip(self.0.iter()) {
+            *entry = cell.get();
+        }
+        Ok(())
     }
 
-    pub fn write(&self, buf: &[u8]) {
-        let count = min(buf.len(), self.0.len());
-        for (i, entry) in buf.iter().enumerate().take(count) {
-            self.0[i].set(*entry);
+    pub fn write(&self, buf: &[u8]) -> Result<()> {
+        if buf.len() > self.0.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+        for (entry, cell) in buf.iter().zip(self.0.iter()) {
+            cell.set(*entry);
         }
+        Ok(())
+    }
+
+    /// Fill the buffer in place. Only while the hardware doesn't own it.
+    pub fn fill<R>(&self, f: impl FnOnce(&mut [u8]) -> R) -> R {
+        // SAFETY: `VolatileCell<u8>` is a transparent `u8` cell, and the
+        // borrow is unique for as long as `f` runs
+        let bytes = unsafe { slice::from_raw_parts_mut(self.0.as_ptr() as *mut u8, self.0.len()) };
+        f(bytes)
     }
 
     pub fn offset(&self) -> usize {
@@ -48,8 +62,17 @@ impl EndpointBuffer {
     }
 }
 
+// Makes the linker aware of the endpoint region, so anything else placed in
+// USB1 SRAM through the same region can't silently overlap it.
+#[cfg(feature = "usb-ram-section")]
+#[link_section = ".usb_ram"]
+#[used]
+static mut USB_RAM: core::mem::MaybeUninit<[u8; EP_MEM_SIZE]> = core::mem::MaybeUninit::uninit();
+
 pub struct EndpointMemoryAllocator {
     next_free_offset: usize,
+    // end of the usable part of USB RAM
+    end: usize,
 }
 
 // NOTE: This is a bump allocator.
@@ -62,18 +85,50 @@ impl EndpointMemoryAllocator {
         // keep endpoint registers at top
         Self {
             next_free_offset: EP_REGISTERS_SIZE,
+            end: EP_MEM_SIZE,
         }
     }
 
-    pub fn allocate_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
-        let next_free_addr = EP_MEM_ADDR + self.next_free_offset;
+    /// Whether the `.usb_ram` reservation starts at USB1 SRAM, where the
+    /// offsets are counted from. Always without `usb-ram-section`.
+    pub fn is_placed() -> bool {
+        #[cfg(feature = "usb-ram-section")]
+        return core::ptr::addr_of!(USB_RAM) as usize == EP_MEM_ADDR;
+        #[cfg(not(feature = "usb-ram-section"))]
+        true
+    }
+
+    /// Only hand out the first `size` bytes of USB RAM, endpoint list
+    /// included.
+    pub fn limit(&mut self, size: usize) {
+        self.end = self.end.min(size);
+    }
 
-        // buffers have to be 64 byte aligned
+    // buffers have to be 64 byte aligned
+    fn next_aligned_offset(&self) -> usize {
+        let next_free_addr = EP_MEM_ADDR + self.next_free_offset;
         let addr = (next_free_addr + EndpointMemoryAllocator::ALIGN - 1)
             & !(EndpointMemoryAllocator::ALIGN - 1);
+        addr - EP_MEM_ADDR
+    }
 
-        let offset = addr - EP_MEM_ADDR;
-        if offset + size > EP_MEM_SIZE {
+    /// Bytes handed out so far, endpoint list included.
+    pub fn used(&self) -> usize {
+        self.next_free_offset
+    }
+
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// Largest buffer that can still be allocated.
+    pub fn remaining(&self) -> usize {
+        self.end.saturating_sub(self.next_aligned_offset())
+    }
+
+    pub fn allocate_buffer(&mut self, size: usize) -> Result<EndpointBuffer> {
+        let offset = self.next_aligned_offset();
+        if offset + size > self.end {
             return Err(UsbError::EndpointMemoryOverflow);
         }
 
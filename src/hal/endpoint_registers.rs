@@ -1,6 +1,9 @@
 #![allow(unused)]
+#![allow(clippy::upper_case_acronyms)]
 use super::constants::{EP_MEM_ADDR, NUM_ENDPOINTS};
 use core::marker::PhantomData;
+use lpc55_hal::raw::USB1;
+use usb_device::UsbDirection;
 
 static mut ENDPOINT_REGISTERS_ATTACHED: bool = false;
 
@@ -27,6 +30,82 @@ pub struct EP {
     pub ep_in: [EPR; 2],
 }
 
+/// One of the two buffers of a physical endpoint. Non-control endpoints only
+/// use the second one with double buffering, see [`set_double_buffered`];
+/// on EP0 OUT it is the SETUP buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferSlot {
+    First,
+    Second,
+}
+
+impl EP {
+    #[inline(always)]
+    pub fn out_buffer(&self, slot: BufferSlot) -> &EPR {
+        &self.ep_out[slot as usize]
+    }
+
+    #[inline(always)]
+    pub fn in_buffer(&self, slot: BufferSlot) -> &EPR {
+        &self.ep_in[slot as usize]
+    }
+}
+
+// Bit of a non-control physical endpoint in EPINUSE, EPBUFCFG and friends
+pub fn physical_endpoint_bit(index: usize, dir: UsbDirection) -> u32 {
+    debug_assert!(index != 0 && index < NUM_ENDPOINTS);
+    let physical = 2 * index
+        + match dir {
+            UsbDirection::Out => 0,
+            UsbDirection::In => 1,
+        };
+    1 << physical
+}
+
+/// The buffer the hardware uses for the next packet of a non-control
+/// endpoint (`EPINUSE`). It flips after every packet with double buffering.
+pub fn buffer_in_use(usb: &USB1, index: usize, dir: UsbDirection) -> BufferSlot {
+    if usb.epinuse.read().bits() & physical_endpoint_bit(index, dir) != 0 {
+        BufferSlot::Second
+    } else {
+        BufferSlot::First
+    }
+}
+
+/// Point the hardware at `slot` for the next packet. Only while neither
+/// buffer of the endpoint is active.
+pub fn set_buffer_in_use(usb: &USB1, index: usize, dir: UsbDirection, slot: BufferSlot) {
+    let bit = physical_endpoint_bit(index, dir);
+    usb.epinuse.modify(|r, w| unsafe {
+        w.bits(match slot {
+            BufferSlot::First => r.bits() & !bit,
+            BufferSlot::Second => r.bits() | bit,
+        })
+    });
+}
+
+/// Have the hardware alternate between both buffers of a non-control
+/// endpoint (`EPBUFCFG`), instead of using only the first.
+pub fn set_double_buffered(usb: &USB1, index: usize, dir: UsbDirection, double: bool) {
+    let bit = physical_endpoint_bit(index, dir);
+    usb.epbufcfg.modify(|r, w| unsafe {
+        w.bits(if double {
+            r.bits() | bit
+        } else {
+            r.bits() & !bit
+        })
+    });
+}
+
+/// Current data toggle of a non-control endpoint (`EPTOGGLE`), `true` for DATA1.
+pub fn data_toggle(usb: &USB1, index: usize, dir: UsbDirection) -> bool {
+    usb.eptoggle.read().bits() & physical_endpoint_bit(index, dir) != 0
+}
+
+pub fn is_double_buffered(usb: &USB1, index: usize, dir: UsbDirection) -> bool {
+    usb.epbufcfg.read().bits() & physical_endpoint_bit(index, dir) != 0
+}
+
 #[doc = "physical endpoint register"]
 pub struct EPR {
     register: vcell::VolatileCell<u32>,
@@ -40,15 +119,16 @@ impl core::ops::Deref for Instance {
     }
 }
 
+// SAFETY: the block lives at a fixed address in USB1 SRAM, so moving the
+// handle between contexts is fine; shared access is guarded by `Mutex`.
 unsafe impl Send for Instance {}
-unsafe impl Send for RegisterBlock {}
 
 impl Instance {
     pub fn addr(&self) -> u32 {
         self.addr
     }
 
-    fn reset(&mut self) {
+    pub fn reset(&self) {
         for ep in self.eps.iter() {
             ep.ep_out[0].reset();
             ep.ep_out[1].reset();
@@ -58,9 +138,17 @@ impl Instance {
     }
 }
 
-pub fn new(addr: u32) -> Instance {
-    let mut instance = Instance {
-        addr,
+// `EPLISTSTART` only holds address bits 31:8
+const _: () = assert!(
+    EP_MEM_ADDR.is_multiple_of(256),
+    "the endpoint list must be 256 byte aligned"
+);
+
+// The list is only ever placed at the start of USB RAM, so its alignment is
+// checked above rather than at runtime
+fn new() -> Instance {
+    let instance = Instance {
+        addr: EP_MEM_ADDR as u32,
         _marker: PhantomData,
     };
     instance.reset();
@@ -73,11 +161,19 @@ pub fn attach() -> Option<Instance> {
             None
         } else {
             ENDPOINT_REGISTERS_ATTACHED = true;
-            Some(new(EP_MEM_ADDR as u32))
+            Some(new())
         }
     })
 }
 
+/// Allow the next `attach()`.
+///
+/// # Safety
+/// The attached `Instance` must not be used anymore.
+pub unsafe fn detach() {
+    cortex_m::interrupt::free(|_| ENDPOINT_REGISTERS_ATTACHED = false);
+}
+
 /// Does not zero the memory
 pub unsafe fn steal() -> Instance {
     ENDPOINT_REGISTERS_ATTACHED = true;
@@ -516,6 +612,50 @@ pub mod epr {
         }
     }
 
+    pub struct _RFTVW<'a> {
+        w: &'a mut W,
+    }
+    impl<'a> _RFTVW<'a> {
+        #[inline]
+        pub fn set_bit(self) -> &'a mut W {
+            self.bit(true)
+        }
+        #[inline]
+        pub fn clear_bit(self) -> &'a mut W {
+            self.bit(false)
+        }
+        #[inline]
+        pub fn bit(self, value: bool) -> &'a mut W {
+            const MASK: bool = true;
+            const OFFSET: u8 = 27;
+            self.w.bits &= !((MASK as u32) << OFFSET);
+            self.w.bits |= ((value & MASK) as u32) << OFFSET;
+            self.w
+        }
+    }
+
+    pub struct _TRW<'a> {
+        w: &'a mut W,
+    }
+    impl<'a> _TRW<'a> {
+        #[inline]
+        pub fn set_bit(self) -> &'a mut W {
+            self.bit(true)
+        }
+        #[inline]
+        pub fn clear_bit(self) -> &'a mut W {
+            self.bit(false)
+        }
+        #[inline]
+        pub fn bit(self, value: bool) -> &'a mut W {
+            const MASK: bool = true;
+            const OFFSET: u8 = 28;
+            self.w.bits &= !((MASK as u32) << OFFSET);
+            self.w.bits |= ((value & MASK) as u32) << OFFSET;
+            self.w
+        }
+    }
+
     // pub struct SR {
     //     bits: bool,
     // }
@@ -567,7 +707,7 @@ pub mod epr {
         pub fn addroff(&self) -> ADDROFFR {
             let field = AddrOffField::new();
             ADDROFFR {
-                bits: ((self.bits >> field.offset) & field.mask as u32) as u16,
+                bits: ((self.bits >> field.offset) & field.mask) as u16,
             }
         }
         #[doc = "Bits 16:25 - Endpoint buffer NBytes while in full speed operation, or bits 11:25 for high speed operation."]
@@ -575,7 +715,7 @@ pub mod epr {
         pub fn nbytes(&self) -> NBYTESR {
             let field = NbytesField::new();
             NBYTESR {
-                bits: ((self.bits >> field.offset) & field.mask as u32) as u16,
+                bits: ((self.bits >> field.offset) & field.mask) as u16,
             }
         }
         #[doc = "Bit 26 - Endpoint type"]
@@ -650,7 +790,7 @@ pub mod epr {
         }
         #[doc = "Bits 0:15 - Endpoint buffer address offset for full speed, or bits 0:10 for high speed"]
         #[inline]
-        pub fn addroff(&mut self) -> _ADDROFFW {
+        pub fn addroff(&mut self) -> _ADDROFFW<'_> {
             _ADDROFFW {
                 w: self,
                 field: AddrOffField::new(),
@@ -658,7 +798,7 @@ pub mod epr {
         }
         #[doc = "Bits 16:25 - Endpoint buffer NBytes for full speed, or bits 25:11 for high speed"]
         #[inline]
-        pub fn nbytes(&mut self) -> _NBYTESW {
+        pub fn nbytes(&mut self) -> _NBYTESW<'_> {
             _NBYTESW {
                 w: self,
                 field: NbytesField::new(),
@@ -666,32 +806,32 @@ pub mod epr {
         }
         #[doc = "Bit 26 - Endpoint type"]
         #[inline]
-        pub fn t(&mut self) -> _TW {
+        pub fn t(&mut self) -> _TW<'_> {
             _TW { w: self }
         }
-        // #[doc = "Bit 27 - Rate Feedback mode / Toggle Value"]
-        // #[inline]
-        // pub fn rftv(&self) -> _RFTVW {
-        //     _RFTVW { w: self }
-        // }
-        // #[doc = "Bit 28 - Toggle reset"]
-        // #[inline]
-        // pub fn tr(&self) -> _TRW {
-        //     _TRW { w: self }
-        // }
+        #[doc = "Bit 27 - Rate Feedback mode / Toggle Value"]
+        #[inline]
+        pub fn rftv(&mut self) -> _RFTVW<'_> {
+            _RFTVW { w: self }
+        }
+        #[doc = "Bit 28 - Toggle reset"]
+        #[inline]
+        pub fn tr(&mut self) -> _TRW<'_> {
+            _TRW { w: self }
+        }
         #[doc = "Bit 29 - Stall"]
         #[inline]
-        pub fn s(&mut self) -> _SW {
+        pub fn s(&mut self) -> _SW<'_> {
             _SW { w: self }
         }
         #[doc = "Bit 30 - Disabled"]
         #[inline]
-        pub fn d(&mut self) -> _DW {
+        pub fn d(&mut self) -> _DW<'_> {
             _DW { w: self }
         }
         #[doc = "Bit 31 - Active"]
         #[inline]
-        pub fn a(&mut self) -> _AW {
+        pub fn a(&mut self) -> _AW<'_> {
             _AW { w: self }
         }
     }
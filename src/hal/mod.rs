@@ -1,4 +1,5 @@
 pub(crate) mod constants;
+pub(crate) mod devcmdstat;
 pub(crate) mod endpoint;
 pub(crate) mod endpoint_memory;
 pub(crate) mod endpoint_registers;
@@ -0,0 +1,108 @@
+use core::cell::RefCell;
+use cortex_m::{
+    interrupt::{self, Mutex},
+    peripheral::NVIC,
+};
+use lpc55_hal::raw::Interrupt;
+
+/// A value shared with the `USB1` handler that
+/// [`usbhs_interrupt!`](crate::usbhs_interrupt) generates, empty until
+/// [`put`](Self::put).
+pub struct UsbSlot<T>(Mutex<RefCell<Option<T>>>);
+
+impl<T> UsbSlot<T> {
+    pub const fn new() -> Self {
+        Self(Mutex::new(RefCell::new(None)))
+    }
+
+    /// Hand `value` over, returning what was there before.
+    pub fn put(&self, value: T) -> Option<T> {
+        interrupt::free(|cs| self.0.borrow(cs).replace(Some(value)))
+    }
+
+    /// Take the value back, e.g. to rebuild the device.
+    pub fn take(&self) -> Option<T> {
+        interrupt::free(|cs| self.0.borrow(cs).take())
+    }
+
+    /// Run `f` on the value, if there is one, with interrupts masked.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        interrupt::free(|cs| self.0.borrow(cs).borrow_mut().as_mut().map(f))
+    }
+
+    #[doc(hidden)]
+    pub fn borrow<'cs>(&'cs self, cs: &'cs interrupt::CriticalSection) -> &'cs RefCell<Option<T>> {
+        self.0.borrow(cs)
+    }
+}
+
+impl<T> Default for UsbSlot<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Unmask `USB1` in the NVIC, once the slots of
+/// [`usbhs_interrupt!`](crate::usbhs_interrupt) are filled.
+///
+/// Fine from inside a critical section too: a pending `USB1` then only
+/// runs once the critical section ends.
+pub fn unmask_usb_interrupt() {
+    // every access the driver and the generated handler make goes through
+    // `interrupt::free`, which doesn't rely on USB1 staying masked
+    unsafe { NVIC::unmask(Interrupt::USB1) };
+}
+
+/// Define the `USB1` interrupt handler, polling a `UsbDevice` on a
+/// [`UsbHSBus`](crate::UsbHSBus) and its classes from [`UsbSlot`] statics
+/// of the given names.
+///
+/// The handler does nothing until every slot is filled; this is the whole
+/// ISR, so the main loop only touches the classes through
+/// [`UsbSlot::with`].
+///
+/// ```ignore
+/// lpc55_usbhs::usbhs_interrupt!(USB_DEVICE, SERIAL: SerialPort<'static, UsbHSBus>);
+///
+/// SERIAL.put(SerialPort::new(usb_bus));
+/// USB_DEVICE.put(
+///     UsbDeviceBuilder::new(usb_bus, vid_pid)
+///         .max_packet_size_0(64)
+///         .build(),
+/// );
+/// lpc55_usbhs::unmask_usb_interrupt();
+///
+/// loop {
+///     SERIAL.with(|serial| serial.write(b"hello").ok());
+/// }
+/// ```
+#[macro_export]
+macro_rules! usbhs_interrupt {
+    ($device:ident $(, $class:ident: $ty:ty)* $(,)?) => {
+        static $device: $crate::UsbSlot<
+            $crate::__private::UsbDevice<'static, $crate::UsbHSBus>,
+        > = $crate::UsbSlot::new();
+        $(static $class: $crate::UsbSlot<$ty> = $crate::UsbSlot::new();)*
+
+        const _: () = {
+            // out of the statics' scope, so the locals can take their names
+            mod handler {
+                use $crate::__private::interrupt;
+
+                #[interrupt]
+                #[allow(non_snake_case)]
+                fn USB1() {
+                    $crate::__private::free(|cs| {
+                        let mut device = super::$device.borrow(cs).borrow_mut();
+                        $(let mut $class = super::$class.borrow(cs).borrow_mut();)*
+                        if let (Some(device), $(Some($class),)*) =
+                            (device.as_mut(), $($class.as_mut(),)*)
+                        {
+                            device.poll(&mut [$($class),*]);
+                        }
+                    });
+                }
+            }
+        };
+    };
+}
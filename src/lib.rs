@@ -1,8 +1,146 @@
+//! USB high-speed device driver for the LPC55's USB1 controller.
+//!
+//! # Features
+//!
+//! - `setup-capture`: keep the last SETUP packets, see
+//!   [`UsbHSBus::captured_setups`](crate::UsbHSBus).
+//! - `usb-ram-section`: reserve the endpoint list and buffers in a
+//!   `.usb_ram` linker section instead of assuming all of USB1 SRAM is ours,
+//!   so the linker catches application buffers that would overlap. The
+//!   reservation is 16 KiB, or `LPC55_USBHS_RAM_SIZE` bytes if set at build
+//!   time; the section has to start at USB1 SRAM, e.g. in `memory.x`:
+//!
+//!   ```text
+//!   MEMORY { USB_RAM : ORIGIN = 0x40100000, LENGTH = 16K }
+//!   SECTIONS {
+//!     .usb_ram (NOLOAD) : {
+//!       KEEP(*(.usb_ram));
+//!       *(.usb_ram.*);
+//!     } > USB_RAM
+//!   } INSERT AFTER .uninit;
+//!   ```
+//!
+//!   with application buffers in `.usb_ram.<name>` sections. Constructing
+//!   the bus fails with [`UsbHsError::UsbRamMisplaced`] if the reservation
+//!   ended up anywhere else.
+//! - `metrics`: time how long IN endpoints sit idle after completing, see
+//!   [`UsbHSBus::rearm_latency`](crate::UsbHSBus::rearm_latency), and count
+//!   the packets and bytes each endpoint moved, see `UsbHSBus::throughput`,
+//!   and the bus resets, see `UsbHSBus::reset_history`, and time low-power
+//!   resumes, see `UsbHSBus::resume_cycles`.
+//! - `capi`: `extern "C"` functions for firmware with a USB stack in C,
+//!   see the `capi` module.
+//! - `sim`: an in-memory `UsbBus` with a host model, for testing
+//!   application and class logic on the build machine, see the `sim`
+//!   module. Needs `std`.
+//!
+//! # Rebuilding the device
+//!
+//! `usb-device` 0.2 freezes the `UsbBusAllocator` for good when the first
+//! `UsbDevice` is built on it, so no second device (nor its control
+//! endpoints) can be allocated from the same allocator. To swap descriptors
+//! or the endpoint layout, drop the device, the classes and the allocator,
+//! then build the next bus on the controller from
+//! [`UsbHS::reclaim`](crate::UsbHS::reclaim): dropping the bus disconnects
+//! it and frees all of USB RAM, without a chip reset. An allocator from
+//! [`UsbHSBus::init_static`](crate::UsbHSBus::init_static) is never dropped,
+//! so it can't be rebuilt this way. A bus driven without `usb-device`, like
+//! the one of the `capi` module, can be
+//! [`disable`](crate::UsbHSBus::disable)d, allocated anew and enabled again
+//! instead.
+//!
+//! # EP0 size
+//!
+//! High speed only allows a 64 byte EP0, and the bus refuses any other size
+//! unless [`force_full_speed`](crate::UsbHsBuilder::force_full_speed) is
+//! set. `UsbDeviceBuilder` defaults to 8, with which `build()` panics, so
+//! set it explicitly:
+//!
+//! ```ignore
+//! let device = UsbDeviceBuilder::new(usb_bus, vid_pid)
+//!     .max_packet_size_0(64)
+//!     .build();
+//! ```
+//!
+//! Earlier versions accepted 8 and then failed to enumerate at high speed.
+//!
+//! # Latency
+//!
+//! `poll()`, `read()` and `write()` each run in a single critical section,
+//! which at high speed has to fit well within the 125 µs microframe along
+//! with everything else the interrupt does. Their cost depends on the core
+//! clock, the flash wait states and how many endpoints are allocated, so
+//! budget it by measuring on the target, e.g. with the DWT cycle counter:
+//!
+//! ```ignore
+//! core.DCB.enable_trace();
+//! core.DWT.enable_cycle_counter();
+//! let start = DWT::cycle_count();
+//! usb_dev.poll(&mut [&mut serial]);
+//! let cycles = DWT::cycle_count().wrapping_sub(start);
+//! ```
+//!
+//! The crate doesn't publish cycle counts of its own, as no single number
+//! holds across those variables. Endpoint numbers from outside the driver
+//! (`usb-device`, [`UsbHSBus`] methods) are range-checked
+//! rather than trusted, so a bad one is an error, not a panic.
+
 #![no_std]
 
+#[cfg(any(test, feature = "sim"))]
+extern crate std;
+
+mod budget;
+mod builder;
+mod bypass;
+#[cfg(feature = "capi")]
+pub mod capi;
+mod config;
+pub mod debug;
+mod error;
+mod events;
+mod frame;
 mod hal;
+mod irq;
+mod lpm;
+pub mod marker;
+mod phy;
+mod pins;
+mod power;
+mod quirks;
+#[cfg(feature = "sim")]
+pub mod sim;
+mod supervisor;
+mod table;
+mod test_mode;
+mod transfer;
 mod usbbus;
 mod usbhs;
 
-pub use usbbus::UsbHSBus;
+pub use budget::UsbRamPlan;
+pub use builder::{UsbHsBuilder, WatchdogFeed};
+pub use bypass::{ControlBypass, ControlEvent, RawControl};
+pub use config::{ErrorLimit, ErrorLimitAction, InterruptAck, ServiceMode, UsbConfig, VbusDetach};
+pub use error::{ClockTooSlow, ErrorHandler, UsbHsError};
+pub use events::{BusEvent, EventListener, MAX_LISTENERS};
+pub use frame::{sofs_to_ms, FrameNumber, FrameStopwatch};
+pub use hal::endpoint::OutPacket;
+pub use irq::{unmask_usb_interrupt, UsbSlot};
+pub use lpm::{L1Exit, LpmPolicy, LpmRequest};
+pub use phy::{ClockInfo, Crystal, PhyClockGating, PhyPowerDown, PhyTuning, SuspendMode};
+pub use pins::{configure_vbus_pin, VbusPin};
+pub use power::{DefaultPowerSequencer, PowerProfile, PowerSequencer};
+pub use quirks::{Quirks, SiliconRevision};
+pub use supervisor::{EnumerationRetry, EnumerationSupervisor, SupervisorEvent};
+pub use table::{AllocFailure, EndpointSpec, EndpointTable, TableEndpoint, TableError};
+pub use test_mode::TestMode;
+pub use transfer::TransferReader;
+pub use usbbus::{BusTransaction, Clock, InRefill, PollEvents, StaticUsbBus, UsbHSBus};
 pub use usbhs::UsbHS;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use cortex_m::interrupt::free;
+    pub use lpc55_hal::raw::interrupt;
+    pub use usb_device::device::UsbDevice;
+}
@@ -0,0 +1,79 @@
+use crate::hal::devcmdstat;
+use lpc55_hal::raw::USB1;
+
+/// How the device answers LPM tokens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LpmPolicy {
+    /// ACK every LPM token and enter L1.
+    #[default]
+    Accept,
+    /// NYET every LPM token: LPM is supported, but the device has data
+    /// pending and stays in L0 (`LPM.DATA_PENDING`).
+    Nyet,
+    /// Don't support LPM at all (`DEVCMDSTAT.LPM_SUP` cleared), for products
+    /// that must never enter L1.
+    Reject,
+}
+
+impl LpmPolicy {
+    pub(crate) fn read(usb: &USB1) -> Self {
+        if !usb.devcmdstat.read().lpm_sup().bit_is_set() {
+            LpmPolicy::Reject
+        } else if usb.lpm.read().data_pending().bit_is_set() {
+            LpmPolicy::Nyet
+        } else {
+            LpmPolicy::Accept
+        }
+    }
+
+    pub(crate) fn write(&self, usb: &USB1) {
+        usb.lpm
+            .modify(|_, w| w.data_pending().bit(*self == LpmPolicy::Nyet));
+        devcmdstat::modify(usb, |w| w.lpm_sup().bit(*self != LpmPolicy::Reject));
+    }
+}
+
+/// Who ended an L1 sleep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum L1Exit {
+    /// The host resumed the link.
+    Host,
+    /// The device did, through [`remote_wakeup`](crate::UsbHSBus::remote_wakeup).
+    Device,
+}
+
+/// Attributes of the LPM token that put the link into L1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LpmRequest {
+    /// BESL (formerly HIRD) value sent by the host (`LPM.HIRD_HW`).
+    pub besl: u8,
+    /// The host allows the device to initiate the L1 exit (`bRemoteWake`).
+    pub remote_wake: bool,
+}
+
+impl LpmRequest {
+    /// Best-effort service latency the host granted, in microseconds, as per
+    /// the BESL table of the USB 2.0 LPM ECN.
+    pub fn besl_us(&self) -> u32 {
+        match self.besl {
+            0 => 125,
+            1 => 150,
+            2 => 200,
+            3 => 300,
+            4 => 400,
+            5 => 500,
+            n => 1000 * (n as u32 - 5),
+        }
+    }
+
+    pub(crate) fn read(usb: &USB1) -> Option<Self> {
+        let devcmdstat = usb.devcmdstat.read();
+        if !devcmdstat.lpm_sus().bit_is_set() {
+            return None;
+        }
+        Some(Self {
+            besl: usb.lpm.read().hird_hw().bits(),
+            remote_wake: devcmdstat.lpm_rewp().bit_is_set(),
+        })
+    }
+}
@@ -0,0 +1,62 @@
+//! `Send`/`Sync` bookkeeping for the driver's handles.
+//!
+//! | Handle                            | `Send` | `Sync` |
+//! |-----------------------------------|--------|--------|
+//! | [`UsbHS`](crate::UsbHS)           | yes    | no     |
+//! | [`UsbHSBus`](crate::UsbHSBus)     | yes    | yes    |
+//! | `endpoint_registers::Instance`    | yes    | no     |
+//! | `Endpoint`                        | yes    | yes    |
+//! | `EndpointBuffer`                  | yes    | no     |
+//!
+//! `UsbHS` owns the raw peripherals and may be moved to whichever context
+//! builds the bus, but never shared. `UsbHSBus` has to be `Sync` for
+//! `usb-device`; every access it makes goes through
+//! `cortex_m::interrupt::free`. That critical section only masks interrupts
+//! on the *current* core, so on the dual-core LPC55S6x the bus (and every
+//! `usb_device::endpoint::Endpoint` borrowed from it) must only be used from
+//! one core. Splitting endpoints across tasks or interrupt priorities on the
+//! same core is fine.
+//!
+//! The table is enforced below, so a field change that silently adds or
+//! removes one of these auto traits fails to compile.
+
+use core::{cell::Cell, marker::PhantomData};
+
+/// Zero-sized marker that opts a handle out of `Sync` while keeping `Send`.
+pub(crate) type NotSync = PhantomData<Cell<()>>;
+
+macro_rules! assert_impl {
+    ($t:ty: $($tr:path),+) => {
+        const _: fn() = || {
+            fn check<T: ?Sized $(+ $tr)+>() {}
+            check::<$t>();
+        };
+    };
+}
+
+macro_rules! assert_not_impl {
+    ($t:ty: $tr:path) => {
+        const _: fn() = || {
+            // ambiguous (and thus an error) iff `$t: $tr`
+            trait Ambiguous<A> {
+                fn some_item() {}
+            }
+            impl<T: ?Sized> Ambiguous<()> for T {}
+            impl<T: ?Sized + $tr> Ambiguous<u8> for T {}
+            let _ = <$t as Ambiguous<_>>::some_item;
+        };
+    };
+}
+
+assert_impl!(crate::UsbHS: Send);
+assert_not_impl!(crate::UsbHS: Sync);
+
+assert_impl!(crate::UsbHSBus: Send, Sync);
+
+assert_impl!(crate::hal::endpoint_registers::Instance: Send);
+assert_not_impl!(crate::hal::endpoint_registers::Instance: Sync);
+
+assert_impl!(crate::hal::endpoint::Endpoint: Send, Sync);
+
+assert_impl!(crate::hal::endpoint_memory::EndpointBuffer: Send);
+assert_not_impl!(crate::hal::endpoint_memory::EndpointBuffer: Sync);
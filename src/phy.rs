@@ -0,0 +1,415 @@
+use crate::builder::WatchdogFeed;
+use cortex_m::peripheral::DWT;
+use lpc55_hal::raw::USBPHY;
+
+/// Power-down state of the individual HS PHY blocks (USBPHY `PWD`).
+///
+/// `true` means the block is powered *down*. While the bus is suspended and
+/// `CTRL.ENAUTOCLR_PHY_PWD` is set, the PHY clears all of these bits by itself
+/// on a wakeup event, so the wakeup detector keeps working no matter which
+/// blocks are powered down here.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhyPowerDown {
+    /// Full-speed transmitter drivers
+    pub tx_fs: bool,
+    /// Transmitter current bias
+    pub tx_ibias: bool,
+    /// Transmitter V-to-I converter and current mirror
+    pub tx_v2i: bool,
+    /// HS receiver envelope (squelch) detector
+    pub rx_envelope: bool,
+    /// FS single-ended receivers (also used for line state)
+    pub rx_fs_se: bool,
+    /// FS differential receiver
+    pub rx_fs_diff: bool,
+    /// HS receiver
+    pub rx_hs: bool,
+}
+
+impl PhyPowerDown {
+    /// Everything powered, as required for normal operation.
+    pub const ALL_ON: Self = Self {
+        tx_fs: false,
+        tx_ibias: false,
+        tx_v2i: false,
+        rx_envelope: false,
+        rx_fs_se: false,
+        rx_fs_diff: false,
+        rx_hs: false,
+    };
+
+    /// Every transmitter and receiver block powered down; only the
+    /// always-on wakeup logic remains.
+    pub const ALL_OFF: Self = Self {
+        tx_fs: true,
+        tx_ibias: true,
+        tx_v2i: true,
+        rx_envelope: true,
+        rx_fs_se: true,
+        rx_fs_diff: true,
+        rx_hs: true,
+    };
+
+    pub(crate) fn read(phy: &USBPHY) -> Self {
+        let r = phy.pwd.read();
+        Self {
+            tx_fs: r.txpwdfs().bit_is_set(),
+            tx_ibias: r.txpwdibias().bit_is_set(),
+            tx_v2i: r.txpwdv2i().bit_is_set(),
+            rx_envelope: r.rxpwdenv().bit_is_set(),
+            rx_fs_se: r.rxpwd1pt1().bit_is_set(),
+            rx_fs_diff: r.rxpwddiff().bit_is_set(),
+            rx_hs: r.rxpwdrx().bit_is_set(),
+        }
+    }
+
+    pub(crate) fn write(&self, phy: &USBPHY) {
+        phy.pwd.write(|w| {
+            w.txpwdfs()
+                .bit(self.tx_fs)
+                .txpwdibias()
+                .bit(self.tx_ibias)
+                .txpwdv2i()
+                .bit(self.tx_v2i)
+                .rxpwdenv()
+                .bit(self.rx_envelope)
+                .rxpwd1pt1()
+                .bit(self.rx_fs_se)
+                .rxpwddiff()
+                .bit(self.rx_fs_diff)
+                .rxpwdrx()
+                .bit(self.rx_hs)
+        });
+    }
+}
+
+/// Wakeup behaviour of the PHY clock gate (USBPHY `CTRL`).
+///
+/// Both options only act on a wakeup event (resume signalling, reset or
+/// disconnect) while the bus is suspended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhyClockGating {
+    /// Ungate the UTMI clocks automatically on wakeup (`ENAUTOCLR_CLKGATE`).
+    ///
+    /// Lets firmware gate the PHY clock on suspend without having to race
+    /// the host's resume signalling. Leaving it off saves nothing, but means
+    /// a gated PHY stays deaf until software ungates it.
+    pub auto_ungate: bool,
+    /// Power up all `PWD` blocks automatically on wakeup (`ENAUTOCLR_PHY_PWD`).
+    ///
+    /// Lets firmware power down the PHY on suspend and have it restored in
+    /// hardware. The flip side is that hardware, not software, then owns the
+    /// `PWD` register across suspend: a deliberate power-down made through
+    /// [`PhyPowerDown`] is undone by the next wakeup.
+    pub auto_power_up: bool,
+}
+
+impl Default for PhyClockGating {
+    fn default() -> Self {
+        Self {
+            auto_ungate: true,
+            auto_power_up: false,
+        }
+    }
+}
+
+impl PhyClockGating {
+    pub(crate) fn read(phy: &USBPHY) -> Self {
+        let r = phy.ctrl.read();
+        Self {
+            auto_ungate: r.enautoclr_clkgate().bit_is_set(),
+            auto_power_up: r.enautoclr_phy_pwd().bit_is_set(),
+        }
+    }
+
+    pub(crate) fn write(&self, phy: &USBPHY) {
+        phy.ctrl.modify(|_, w| {
+            w.enautoclr_clkgate()
+                .bit(self.auto_ungate)
+                .enautoclr_phy_pwd()
+                .bit(self.auto_power_up)
+        });
+    }
+}
+
+/// Crystal on the 32 MHz oscillator, which is the USB PLL's reference; the
+/// PLL multiplies it up to 480 MHz.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Crystal {
+    #[default]
+    Mhz16,
+    Mhz19_2,
+    Mhz24,
+    Mhz30,
+    Mhz32,
+}
+
+impl Crystal {
+    /// `PLL_SIC.PLL_DIV_SEL` for a 480 MHz output.
+    pub(crate) fn pll_div_sel(self) -> u8 {
+        match self {
+            Crystal::Mhz16 => 6,   // /30
+            Crystal::Mhz19_2 => 5, // /25
+            Crystal::Mhz24 => 3,   // /20
+            Crystal::Mhz30 => 2,   // /16
+            Crystal::Mhz32 => 1,   // /15
+        }
+    }
+
+    /// Nominal frequency.
+    pub const fn hz(self) -> u32 {
+        match self {
+            Crystal::Mhz16 => 16_000_000,
+            Crystal::Mhz19_2 => 19_200_000,
+            Crystal::Mhz24 => 24_000_000,
+            Crystal::Mhz30 => 30_000_000,
+            Crystal::Mhz32 => 32_000_000,
+        }
+    }
+}
+
+/// How the USB PLL is set up, as read back from the hardware, see
+/// [`UsbHS::clock_info`](crate::UsbHS::clock_info).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ClockInfo {
+    /// Crystal the driver was configured for
+    pub crystal: Crystal,
+    /// The 32 MHz oscillator feeds the PLL (`ANACTRL XO32M_CTRL.ENABLE_PLL_USB_OUT`)
+    pub reference_enabled: bool,
+    /// Reference divided by 2 first (`PLL_SIC.PLL_PREDIV`)
+    pub prediv: bool,
+    /// `PLL_SIC.PLL_DIV_SEL`
+    pub div_sel: u8,
+    /// Feedback divider `div_sel` stands for
+    pub multiplier: u32,
+    /// PLL output for `crystal` and the dividers
+    pub pll_hz: u32,
+    /// `PLL_SIC.PLL_LOCK`
+    pub locked: bool,
+    /// PLL output reaches the PHY (`PLL_SIC.PLL_EN_USB_CLKS`)
+    pub usb_clks_enabled: bool,
+}
+
+impl ClockInfo {
+    /// What the PHY needs for both full and high speed.
+    pub const PHY_HZ: u32 = 480_000_000;
+
+    pub(crate) fn read(phy: &USBPHY, crystal: Crystal) -> Self {
+        // SAFETY: read-only register
+        let anactrl = unsafe { &*lpc55_hal::raw::ANACTRL::ptr() };
+        let pll_sic = phy.pll_sic.read();
+        let prediv = pll_sic.pll_prediv().bit_is_set();
+        let div_sel = pll_sic.pll_div_sel().bits();
+        let multiplier = match div_sel {
+            0 => 13,
+            1 => 15,
+            2 => 16,
+            3 => 20,
+            4 => 22,
+            5 => 25,
+            6 => 30,
+            _ => 240,
+        };
+        let reference_hz = crystal.hz() >> u32::from(prediv);
+        Self {
+            crystal,
+            reference_enabled: anactrl.xo32m_ctrl.read().enable_pll_usb_out().bit_is_set(),
+            prediv,
+            div_sel,
+            multiplier,
+            pll_hz: (u64::from(reference_hz) * u64::from(multiplier)).min(u64::from(u32::MAX))
+                as u32,
+            locked: pll_sic.pll_lock().bit_is_set(),
+            usb_clks_enabled: pll_sic.pll_en_usb_clks().bit_is_set(),
+        }
+    }
+
+    /// Whether the PHY gets a locked 480 MHz clock, assuming `crystal` is
+    /// the one on the board.
+    pub fn is_ok(&self) -> bool {
+        self.reference_enabled
+            && self.pll_hz == Self::PHY_HZ
+            && self.locked
+            && self.usb_clks_enabled
+    }
+}
+
+/// Analog trim of the PHY (USBPHY `TX` and `RX`), for boards whose signal
+/// quality measurements call for it. Values are masked to the field widths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhyTuning {
+    /// HS drive current trim, 4 bits (`TX.D_CAL`)
+    pub d_cal: u8,
+    /// D+ series termination trim, 4 bits (`TX.TXCAL45DP`)
+    pub txcal45dp: u8,
+    /// D- series termination trim, 4 bits (`TX.TXCAL45DM`)
+    pub txcal45dm: u8,
+    /// Squelch (envelope detector) trip point, 3 bits (`RX.ENVADJ`)
+    pub envadj: u8,
+    /// Disconnect detector trip point, 3 bits (`RX.DISCONADJ`)
+    pub disconadj: u8,
+}
+
+impl PhyTuning {
+    pub(crate) fn write(&self, phy: &USBPHY) {
+        phy.tx.modify(|_, w| unsafe {
+            w.d_cal()
+                .bits(self.d_cal & 0xf)
+                .txcal45dp()
+                .bits(self.txcal45dp & 0xf)
+                .txcal45dm()
+                .bits(self.txcal45dm & 0xf)
+        });
+        phy.rx.modify(|_, w| unsafe {
+            w.envadj()
+                .bits(self.envadj & 0x7)
+                .disconadj()
+                .bits(self.disconadj & 0x7)
+        });
+    }
+}
+
+/// Gate or ungate the UTMI clocks (USBPHY `CTRL.CLKGATE`).
+pub(crate) fn set_clock_gated(phy: &USBPHY, gated: bool) {
+    if gated {
+        phy.ctrl_set.write(|w| w.clkgate().set_bit());
+    } else {
+        phy.ctrl_clr.write(|w| w.clkgate().set_bit());
+    }
+}
+
+pub(crate) fn is_clock_gated(phy: &USBPHY) -> bool {
+    phy.ctrl.read().clkgate().bit_is_set()
+}
+
+/// What the bus does with the PHY while the host has the bus suspended.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SuspendMode {
+    /// Leave the PHY and its PLL running.
+    #[default]
+    Normal,
+    /// Gate the UTMI clocks, power down every PHY block and stop the USB PLL.
+    ///
+    /// The PHY's wakeup logic ungates and powers itself back up on resume
+    /// signalling or reset; the PLL is restarted by the next
+    /// [`poll()`](usb_device::bus::UsbBus::poll) that sees the wakeup, so
+    /// `poll()` has to run from the `USB1_PHY` interrupt (or keep being
+    /// called) while suspended. Restoring busy-waits for PLL lock for at
+    /// most 1 ms, counted in core clock cycles with the DWT cycle counter
+    /// (which gets enabled for it), well within the 10 ms the spec gives a
+    /// device to recover from resume; a PLL that doesn't lock in that time
+    /// is reported as [`UsbHsError::PllLockTimeout`](crate::UsbHsError::PllLockTimeout).
+    /// The cycles are those of the system clock as read at init, or of
+    /// the 96 MHz minimum if it couldn't be read.
+    /// With the `metrics` feature, the time it actually took is kept, see
+    /// `UsbHSBus::resume_cycles`.
+    LowPower,
+}
+
+/// PHY settings that are overridden for a low-power suspend.
+#[derive(Clone, Copy)]
+pub(crate) struct SuspendedPhy {
+    power_down: PhyPowerDown,
+    gating: PhyClockGating,
+    wakeup_irq_enabled: bool,
+}
+
+// Longest wait for PLL_LOCK, leaving most of the 10 ms resume recovery
+// time to the rest of the resume
+const PLL_LOCK_TIMEOUT_US: u32 = 1_000;
+
+pub(crate) fn enter_low_power(phy: &USBPHY) -> SuspendedPhy {
+    let saved = SuspendedPhy {
+        power_down: PhyPowerDown::read(phy),
+        gating: PhyClockGating::read(phy),
+        wakeup_irq_enabled: phy.ctrl.read().enirqwakeup().bit_is_set(),
+    };
+
+    PhyClockGating {
+        auto_ungate: true,
+        auto_power_up: true,
+    }
+    .write(phy);
+    phy.ctrl_clr.write(|w| w.wakeup_irq().set_bit());
+    phy.ctrl_set.write(|w| w.enirqwakeup().set_bit());
+
+    set_clock_gated(phy, true);
+    PhyPowerDown::ALL_OFF.write(phy);
+    phy.pll_sic_clr
+        .write(|w| w.pll_en_usb_clks().set_bit().pll_power().set_bit());
+
+    saved
+}
+
+/// Raise `USB1_PHY` when a suspended PHY sees resume signalling, a D+/D-
+/// or a VBUS change.
+pub(crate) fn enable_wakeup_interrupt(phy: &USBPHY) {
+    phy.ctrl_set.write(|w| {
+        w.enirqwakeup()
+            .set_bit()
+            .endpdmchg_wkup()
+            .set_bit()
+            .envbuschg_wkup()
+            .set_bit()
+    });
+}
+
+pub(crate) fn clear_wakeup(phy: &USBPHY) {
+    if wakeup_pending(phy) {
+        phy.ctrl_clr.write(|w| w.wakeup_irq().set_bit());
+    }
+}
+
+pub(crate) fn wakeup_pending(phy: &USBPHY) -> bool {
+    phy.ctrl.read().wakeup_irq().bit_is_set()
+}
+
+/// Undo `enter_low_power`; `false` if the PLL didn't lock within
+/// `PLL_LOCK_TIMEOUT_US` of `core_hz` cycles, in which case the PHY is
+/// restored all the same.
+pub(crate) fn exit_low_power(
+    phy: &USBPHY,
+    saved: SuspendedPhy,
+    feed: Option<WatchdogFeed>,
+    core_hz: u32,
+) -> bool {
+    // timed with the cycle counter, so flash wait states or a slower core
+    // clock don't stretch the wait
+    if !DWT::cycle_counter_enabled() {
+        // SAFETY: only turns on tracing and the cycle counter, which nothing
+        // relies on being off
+        let mut core = unsafe { cortex_m::Peripherals::steal() };
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+    }
+    let timeout = core_hz / 1_000_000 * PLL_LOCK_TIMEOUT_US;
+    let start = DWT::cycle_count();
+
+    phy.pll_sic_set.write(|w| w.pll_power().set_bit());
+    let mut locked = false;
+    loop {
+        if phy.pll_sic.read().pll_lock().is_value1() {
+            locked = true;
+            break;
+        }
+        if DWT::cycle_count().wrapping_sub(start) >= timeout {
+            break;
+        }
+        if let Some(feed) = feed {
+            feed();
+        }
+    }
+    phy.pll_sic_set.write(|w| w.pll_en_usb_clks().set_bit());
+
+    saved.power_down.write(phy);
+    set_clock_gated(phy, false);
+
+    phy.ctrl_clr.write(|w| {
+        w.enirqwakeup()
+            .bit(!saved.wakeup_irq_enabled)
+            .wakeup_irq()
+            .set_bit()
+    });
+    saved.gating.write(phy);
+    locked
+}
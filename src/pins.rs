@@ -0,0 +1,25 @@
+use lpc55_hal::{
+    drivers::pins::Pio0_22,
+    peripherals::iocon::Iocon,
+    typestates::{
+        init_state::Enabled,
+        pin::{
+            function::USB0_VBUS,
+            state::{Special, Unused},
+        },
+    },
+    Pin,
+};
+
+/// PIO0_22 in its VBUS-detect function.
+pub type VbusPin = Pin<Pio0_22, Special<USB0_VBUS>>;
+
+/// Mux PIO0_22 to VBUS detect (IOCON `FUNC7`, digital input, no pull).
+///
+/// That function is wired to USB0's controller: USB1 senses VBUS on its
+/// dedicated `USB1_VBUS` pin, which needs no IOCON setup. On boards that
+/// route the connector's VBUS to PIO0_22 this keeps the pin from being
+/// driven or pulled, and the returned pin marks it as taken.
+pub fn configure_vbus_pin(pin: Pin<Pio0_22, Unused>, iocon: &mut Iocon<Enabled>) -> VbusPin {
+    pin.into_usb0_vbus_pin(iocon)
+}
@@ -0,0 +1,93 @@
+use core::ptr;
+use lpc55_hal::{raw::USBPHY, Pmc};
+
+/// Power-up steps of [`UsbHS`](crate::UsbHS) init for the PHY and its clock
+/// source, for boards that share the 32 MHz oscillator or sequence power
+/// externally. Override the steps that differ, see
+/// [`UsbHsBuilder::power_sequencer`](crate::UsbHsBuilder::power_sequencer).
+pub trait PowerSequencer {
+    /// Power on the 32 MHz crystal oscillator and its LDO.
+    fn power_up_xtal32m(&mut self) {
+        // SAFTEY: We can have two references to the same peripheral, there aren't any mut references alive
+        let pmc_raw = unsafe { &lpc55_hal::raw::Peripherals::steal().PMC };
+        pmc_raw
+            .pdruncfg0
+            .modify(|_, w| w.pden_xtal32m().poweredon());
+        pmc_raw
+            .pdruncfg0
+            .modify(|_, w| w.pden_ldoxo32m().poweredon());
+    }
+
+    /// Connect the crystal to the USB PLL.
+    fn enable_usb_pll_clock(&mut self) {
+        // SAFTEY: We can have two references to the same peripheral, there aren't any mut references alive
+        let anactrl_raw = unsafe { &lpc55_hal::raw::Peripherals::steal().ANACTRL };
+        anactrl_raw
+            .xo32m_ctrl
+            .modify(|_, w| w.enable_pll_usb_out().set_bit());
+    }
+
+    /// Power on the PHY and wait for it to be ready.
+    fn power_up_phy(&mut self, pmc: &mut Pmc, phy: &mut USBPHY, delay_us: &mut dyn FnMut(u32)) {
+        pmc.power_on(phy);
+
+        // Give long delay for PHY to be ready
+        delay_us(PHY_POWER_UP_US);
+    }
+}
+
+/// The power-up sequence of the LPC55S69-EVK and similar boards.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultPowerSequencer;
+
+impl PowerSequencer for DefaultPowerSequencer {}
+
+pub(crate) const PHY_POWER_UP_US: u32 = 5 * 1000;
+
+/// Power the HS PHY and its LDO (`PMC.PDRUNCFG0`) up for an enabled bus;
+/// `true` if they were down, so the PHY has to be started again.
+pub(crate) fn acquire_usb_domains() -> bool {
+    cortex_m::interrupt::free(|_| {
+        // SAFTEY: Only the USB domains are touched, in a critical section
+        let pmc_raw = unsafe { &lpc55_hal::raw::Peripherals::steal().PMC };
+        let cfg = pmc_raw.pdruncfg0.read();
+        let was_down = cfg.pden_usbhsphy().is_poweredoff() || cfg.pden_ldousbhs().is_poweredoff();
+        pmc_raw
+            .pdruncfg0
+            .modify(|_, w| w.pden_usbhsphy().poweredon().pden_ldousbhs().poweredon());
+        was_down
+    })
+}
+
+/// Power the HS PHY and its LDO down again once the bus is disabled.
+pub(crate) fn release_usb_domains() {
+    cortex_m::interrupt::free(|_| {
+        // SAFTEY: as in `acquire_usb_domains`
+        let pmc_raw = unsafe { &lpc55_hal::raw::Peripherals::steal().PMC };
+        pmc_raw
+            .pdruncfg0
+            .modify(|_, w| w.pden_usbhsphy().poweredoff().pden_ldousbhs().poweredoff());
+    });
+}
+
+/// Power hooks that replace the driver's own power handling, see
+/// [`UsbConfig::power_profile`](crate::UsbConfig::power_profile), e.g. for
+/// a power manager that also raises the core voltage through the SDK's
+/// power library, which the PMC registers the driver knows don't cover.
+/// They then own the PHY's power domains too.
+#[derive(Clone, Copy, Debug)]
+pub struct PowerProfile {
+    /// Called as the bus gets enabled, before the controller is.
+    pub acquire: fn(),
+    /// Called once the bus got disabled or dropped.
+    pub release: fn(),
+}
+
+// same hooks, as far as function addresses tell
+impl PartialEq for PowerProfile {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::fn_addr_eq(self.acquire, other.acquire) && ptr::fn_addr_eq(self.release, other.release)
+    }
+}
+
+impl Eq for PowerProfile {}
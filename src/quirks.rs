@@ -0,0 +1,55 @@
+/// Silicon revision of the part, from `SYSCON.DIEID`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SiliconRevision {
+    /// Chip number, `0x426B` on the LPC55S6x
+    pub chip: u32,
+    /// Metal revision
+    pub rev_id: u8,
+}
+
+impl SiliconRevision {
+    pub(crate) fn read() -> Self {
+        // SAFETY: read-only register
+        let syscon = unsafe { &*lpc55_hal::raw::SYSCON::ptr() };
+        let dieid = syscon.dieid.read();
+        Self {
+            chip: dieid.mco_num_in_die_id().bits(),
+            rev_id: dieid.rev_id().bits(),
+        }
+    }
+
+    /// Revision as marked on the package, if known.
+    pub fn marking(&self) -> Option<&'static str> {
+        match self.rev_id {
+            0 => Some("0A"),
+            1 => Some("1B"),
+            _ => None,
+        }
+    }
+}
+
+/// The workarounds the driver applies, selected from the [`SiliconRevision`]
+/// read when the controller is brought up, see
+/// [`UsbHS::quirks`](crate::UsbHS::quirks).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Quirks {
+    pub revision: SiliconRevision,
+    /// Allocate EP0 OUT one byte over its max packet size, the "ZLP NYET
+    /// fix" this driver has always applied. On for 0A and 1B, and for
+    /// revisions not known yet, until one is known not to need it.
+    pub ep0_out_spare_byte: bool,
+}
+
+impl Quirks {
+    pub(crate) fn select(revision: SiliconRevision) -> Self {
+        let ep0_out_spare_byte = match revision.marking() {
+            Some("0A" | "1B") | None => true,
+            Some(_) => false,
+        };
+        Self {
+            revision,
+            ep0_out_spare_byte,
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! In-memory stand-in for the bus, for testing application and class logic
+//! off target.
+//!
+//! [`SimBus`] implements `usb-device`'s `UsbBus` the way [`UsbHSBus`](crate::UsbHSBus)
+//! behaves towards it: same endpoint numbering and allocation rules, set
+//! address before the status stage, completions reported until the next
+//! write. The [`SimHost`] it is created with plays the host, one
+//! transaction at a time, while the test keeps calling `UsbDevice::poll`.
+//! Of the bus's own extensions it has the ones for application data flow,
+//! [`frame_number`](SimBus::frame_number), [`read_packet`](SimBus::read_packet),
+//! [`peek_len`](SimBus::peek_len), [`prime_out`](SimBus::prime_out),
+//! [`set_in_refill`](SimBus::set_in_refill) and
+//! [`set_endpoint_stalled`](SimBus::set_endpoint_stalled):
+//!
+//! ```ignore
+//! let (bus, host) = SimBus::new();
+//! let alloc = UsbBusAllocator::new(bus);
+//! let mut serial = SerialPort::new(&alloc);
+//! let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001))
+//!     .max_packet_size_0(64)
+//!     .build();
+//!
+//! host.reset();
+//! dev.poll(&mut [&mut serial]);
+//! host.setup([0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00]);
+//! dev.poll(&mut [&mut serial]);
+//! let descriptor = host.take_in(0).unwrap();
+//! ```
+
+use crate::{frame::FrameNumber, hal::constants::NUM_ENDPOINTS, InRefill, OutPacket};
+use std::{
+    sync::{Arc, Mutex},
+    vec::Vec,
+};
+use usb_device::{
+    bus::{PollResult, UsbBus},
+    endpoint::{EndpointAddress, EndpointType},
+    Result, UsbDirection, UsbError,
+};
+
+#[derive(Default)]
+struct SimEndpoint {
+    ep_type: Option<EndpointType>,
+    max_packet_size: u16,
+    allocated: [bool; 2],
+    stalled: [bool; 2],
+    setup: Option<[u8; 8]>,
+    // packet from the host, not read yet, and the frame it came in; with
+    // `prime_out`, the transfer, gathered in `out_partial` until complete
+    out: Option<Vec<u8>>,
+    out_frame: u16,
+    out_transfer_len: usize,
+    out_partial: Vec<u8>,
+    // packet written by the device, not taken by the host yet
+    in_: Option<Vec<u8>>,
+    in_complete: bool,
+    refill: Option<InRefill>,
+}
+
+#[derive(Default)]
+struct SimState {
+    endpoints: [SimEndpoint; NUM_ENDPOINTS],
+    frame: u16,
+    enabled: bool,
+    address: u8,
+    reset: bool,
+    // suspend state to report, and whether it changed since the last poll
+    suspended: bool,
+    suspend_changed: bool,
+}
+
+impl SimState {
+    fn endpoint(&mut self, index: u8) -> Result<&mut SimEndpoint> {
+        self.endpoints
+            .get_mut(usize::from(index))
+            .ok_or(UsbError::InvalidEndpoint)
+    }
+}
+
+/// Why the [`SimHost`] couldn't complete a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimError {
+    /// No such endpoint, or it isn't allocated in that direction
+    InvalidEndpoint,
+    /// The device would STALL
+    Stall,
+    /// The device would NAK: the last OUT packet is unread, or no IN packet
+    /// is armed
+    Nak,
+    /// More than the endpoint's max packet size
+    Babble,
+}
+
+/// Software model of the USB1 controller, see the [module docs](self).
+pub struct SimBus {
+    state: Arc<Mutex<SimState>>,
+}
+
+/// The host side of a [`SimBus`].
+#[derive(Clone)]
+pub struct SimHost {
+    state: Arc<Mutex<SimState>>,
+}
+
+impl SimBus {
+    pub fn new() -> (SimBus, SimHost) {
+        let state = Arc::new(Mutex::new(SimState::default()));
+        (
+            SimBus {
+                state: state.clone(),
+            },
+            SimHost { state },
+        )
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut SimState) -> R) -> R {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    /// See [`UsbHSBus::frame_number`](crate::UsbHSBus::frame_number); it
+    /// advances with [`SimHost::sof`].
+    pub fn frame_number(&self) -> FrameNumber {
+        self.with(|state| FrameNumber(state.frame))
+    }
+
+    /// See [`UsbHSBus::read_packet`](crate::UsbHSBus::read_packet).
+    pub fn read_packet(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<OutPacket> {
+        self.with(|state| {
+            let ep = state.endpoint(ep_addr.index() as u8)?;
+            if !ep_addr.is_out() || !ep.allocated[0] {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            if ep.setup.is_some() {
+                return read_from(ep, buf).map(|len| OutPacket {
+                    len,
+                    short: true,
+                    frame: None,
+                });
+            }
+            let frame = ep.out_frame;
+            let max_packet_size = usize::from(ep.max_packet_size);
+            read_from(ep, buf).map(|len| OutPacket {
+                len,
+                short: len == 0 || len % max_packet_size.max(1) != 0,
+                frame: Some(frame),
+            })
+        })
+    }
+
+    /// See [`UsbHSBus::peek_len`](crate::UsbHSBus::peek_len).
+    pub fn peek_len(&self, ep_addr: EndpointAddress) -> Result<usize> {
+        self.with(|state| {
+            let ep = state.endpoint(ep_addr.index() as u8)?;
+            if !ep_addr.is_out() || !ep.allocated[0] {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            match (&ep.setup, &ep.out) {
+                (Some(_), _) => Ok(8),
+                (None, Some(out)) => Ok(out.len()),
+                (None, None) => Err(UsbError::WouldBlock),
+            }
+        })
+    }
+
+    /// See [`UsbHSBus::prime_out`](crate::UsbHSBus::prime_out). There is no
+    /// OUT buffer size to fit here, any `len` goes.
+    pub fn prime_out(&self, ep_addr: EndpointAddress, len: usize) -> Result<()> {
+        let index = ep_addr.index();
+        if !ep_addr.is_out() || index == 0 || index >= NUM_ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.with(|state| {
+            let ep = &mut state.endpoints[index];
+            if !ep.allocated[0] {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            ep.out_transfer_len = len;
+            Ok(())
+        })
+    }
+
+    /// See [`UsbHSBus::set_in_refill`](crate::UsbHSBus::set_in_refill).
+    /// Refills are one max-size packet long.
+    pub fn set_in_refill(&self, ep_addr: EndpointAddress, refill: Option<InRefill>) -> Result<()> {
+        let index = ep_addr.index();
+        if !ep_addr.is_in() || index == 0 || index >= NUM_ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.with(|state| state.endpoints[index].refill = refill);
+        Ok(())
+    }
+
+    /// See [`UsbHSBus::set_endpoint_stalled`](crate::UsbHSBus::set_endpoint_stalled).
+    pub fn set_endpoint_stalled(&self, ep_addr: EndpointAddress, stalled: bool) -> Result<()> {
+        self.with(|state| {
+            let ep = state.endpoint(ep_addr.index() as u8)?;
+            if ep.ep_type == Some(EndpointType::Isochronous) {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            ep.stalled[usize::from(ep_addr.is_in())] = stalled;
+            Ok(())
+        })
+    }
+}
+
+// Hand out the waiting SETUP or OUT packet, unless it doesn't fit
+fn read_from(ep: &mut SimEndpoint, buf: &mut [u8]) -> Result<usize> {
+    if let Some(setup) = ep.setup {
+        let dest = buf.get_mut(..8).ok_or(UsbError::BufferOverflow)?;
+        dest.copy_from_slice(&setup);
+        ep.setup = None;
+        return Ok(8);
+    }
+    let len = ep.out.as_ref().ok_or(UsbError::WouldBlock)?.len();
+    let dest = buf.get_mut(..len).ok_or(UsbError::BufferOverflow)?;
+    dest.copy_from_slice(ep.out.as_ref().unwrap());
+    ep.out = None;
+    Ok(len)
+}
+
+impl SimHost {
+    fn with<R>(&self, f: impl FnOnce(&mut SimState) -> R) -> R {
+        f(&mut self.state.lock().unwrap())
+    }
+
+    /// Reset the bus, reported by the next poll.
+    pub fn reset(&self) {
+        self.with(|state| {
+            state.reset = true;
+            state.suspended = false;
+            state.suspend_changed = false;
+        });
+    }
+
+    /// Let `frames` frames go by, advancing the frame number. Does nothing
+    /// while suspended, like the counter on the bus.
+    pub fn sof(&self, frames: u16) {
+        self.with(|state| {
+            if !state.suspended {
+                state.frame = (state.frame + frames % FrameNumber::WRAP) % FrameNumber::WRAP;
+            }
+        });
+    }
+
+    /// Stop sending SOFs, or start again.
+    pub fn set_suspended(&self, suspended: bool) {
+        self.with(|state| {
+            if state.suspended != suspended {
+                state.suspended = suspended;
+                state.suspend_changed = true;
+            }
+        });
+    }
+
+    /// Send a SETUP packet to EP0, which supersedes whatever control
+    /// transfer was going on, as on the wire.
+    pub fn setup(&self, packet: [u8; 8]) {
+        self.with(|state| {
+            let ep0 = &mut state.endpoints[0];
+            ep0.setup = Some(packet);
+            ep0.out = None;
+            ep0.in_ = None;
+            ep0.in_complete = false;
+            ep0.stalled = [false; 2];
+        });
+    }
+
+    /// Send an OUT packet. After [`SimBus::prime_out`] the device only sees
+    /// the packets once the primed length or a short packet is in.
+    pub fn out(&self, ep: u8, data: &[u8]) -> core::result::Result<(), SimError> {
+        self.with(|state| {
+            let frame = state.frame;
+            let ep = state.endpoint(ep).map_err(|_| SimError::InvalidEndpoint)?;
+            if !ep.allocated[0] {
+                return Err(SimError::InvalidEndpoint);
+            }
+            if ep.stalled[0] {
+                return Err(SimError::Stall);
+            }
+            if ep.out.is_some() || ep.setup.is_some() {
+                return Err(SimError::Nak);
+            }
+            let max_packet_size = usize::from(ep.max_packet_size);
+            let remaining = match ep.out_transfer_len {
+                0 => max_packet_size,
+                len => len.saturating_sub(ep.out_partial.len()),
+            };
+            if data.len() > max_packet_size.min(remaining) {
+                return Err(SimError::Babble);
+            }
+            ep.out_partial.extend_from_slice(data);
+            if data.len() < max_packet_size || data.len() == remaining {
+                ep.out = Some(core::mem::take(&mut ep.out_partial));
+            }
+            ep.out_frame = frame;
+            Ok(())
+        })
+    }
+
+    /// Collect the IN packet the device armed, which completes it.
+    pub fn take_in(&self, ep: u8) -> core::result::Result<Vec<u8>, SimError> {
+        self.with(|state| {
+            let ep = state.endpoint(ep).map_err(|_| SimError::InvalidEndpoint)?;
+            if !ep.allocated[1] {
+                return Err(SimError::InvalidEndpoint);
+            }
+            if ep.stalled[1] {
+                return Err(SimError::Stall);
+            }
+            let packet = ep.in_.take().ok_or(SimError::Nak)?;
+            ep.in_complete = true;
+            Ok(packet)
+        })
+    }
+
+    /// Address the device got assigned, 0 before `SET_ADDRESS`.
+    pub fn address(&self) -> u8 {
+        self.with(|state| state.address)
+    }
+
+    /// Whether the device soft-connected, i.e. `UsbDevice` enabled the bus.
+    pub fn is_connected(&self) -> bool {
+        self.with(|state| state.enabled)
+    }
+}
+
+impl UsbBus for SimBus {
+    const QUIRK_SET_ADDRESS_BEFORE_STATUS: bool = true;
+
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let dir = usize::from(ep_dir == UsbDirection::In);
+        let range = match ep_addr {
+            Some(addr) => addr.index()..addr.index() + 1,
+            None => 1..NUM_ENDPOINTS,
+        };
+        self.with(|state| {
+            for index in range {
+                let Some(ep) = state.endpoints.get_mut(index) else {
+                    break;
+                };
+                if ep.ep_type.is_some_and(|t| t != ep_type) || ep.allocated[dir] {
+                    continue;
+                }
+                ep.ep_type = Some(ep_type);
+                ep.max_packet_size = max_packet_size;
+                ep.allocated[dir] = true;
+                return Ok(EndpointAddress::from_parts(index, ep_dir));
+            }
+            Err(match ep_addr {
+                Some(_) => UsbError::InvalidEndpoint,
+                None => UsbError::EndpointOverflow,
+            })
+        })
+    }
+
+    fn enable(&mut self) {
+        self.with(|state| state.enabled = true);
+    }
+
+    fn reset(&self) {
+        self.with(|state| {
+            state.address = 0;
+            for ep in state.endpoints.iter_mut() {
+                ep.stalled = [false; 2];
+                ep.setup = None;
+                ep.out = None;
+                ep.out_partial.clear();
+                ep.in_ = None;
+                ep.in_complete = false;
+            }
+        });
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.with(|state| state.address = addr);
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        self.with(|state| {
+            let ep = state.endpoint(ep_addr.index() as u8)?;
+            if !ep_addr.is_in() || !ep.allocated[1] {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            if ep.in_.is_some() {
+                return Err(UsbError::WouldBlock);
+            }
+            if buf.len() > usize::from(ep.max_packet_size) {
+                return Err(UsbError::BufferOverflow);
+            }
+            ep.in_ = Some(buf.to_vec());
+            ep.in_complete = false;
+            Ok(buf.len())
+        })
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        self.with(|state| {
+            let ep = state.endpoint(ep_addr.index() as u8)?;
+            if !ep_addr.is_out() || !ep.allocated[0] {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            read_from(ep, buf)
+        })
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        self.with(|state| {
+            if let Ok(ep) = state.endpoint(ep_addr.index() as u8) {
+                ep.stalled[usize::from(ep_addr.is_in())] = stalled;
+            }
+        });
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.with(|state| {
+            state
+                .endpoint(ep_addr.index() as u8)
+                .is_ok_and(|ep| ep.stalled[usize::from(ep_addr.is_in())])
+        })
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        self.with(|state| {
+            if state.reset {
+                state.reset = false;
+                return PollResult::Reset;
+            }
+            if state.suspend_changed {
+                state.suspend_changed = false;
+                return match state.suspended {
+                    true => PollResult::Suspend,
+                    false => PollResult::Resume,
+                };
+            }
+            if state.suspended {
+                return PollResult::None;
+            }
+
+            let (mut ep_out, mut ep_in_complete, mut ep_setup) = (0, 0, 0);
+            for (i, ep) in state.endpoints.iter_mut().enumerate() {
+                let bit = 1 << i;
+                if ep.setup.is_some() {
+                    ep_setup |= bit;
+                } else if ep.out.is_some() {
+                    ep_out |= bit;
+                }
+                // a refill keeps the endpoint armed instead of completing
+                if let (true, Some(refill)) = (ep.in_complete, ep.refill) {
+                    let mut packet = std::vec![0; usize::from(ep.max_packet_size)];
+                    if let Some(len) = refill(&mut packet) {
+                        packet.truncate(len);
+                        ep.in_ = Some(packet);
+                        ep.in_complete = false;
+                    }
+                }
+                // EP0's completions are consumed by usb-device's control pipe
+                if ep.in_complete {
+                    ep_in_complete |= bit;
+                    if i == 0 {
+                        ep.in_complete = false;
+                    }
+                }
+            }
+            if ep_out | ep_in_complete | ep_setup == 0 {
+                return PollResult::None;
+            }
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        })
+    }
+}
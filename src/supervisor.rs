@@ -0,0 +1,244 @@
+use crate::UsbHSBus;
+use usb_device::device::UsbDeviceState;
+
+/// Retry policy of an [`EnumerationSupervisor`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnumerationRetry {
+    /// How long the device may sit in the default state after a bus reset
+    /// before it counts as stuck.
+    pub timeout_ms: u32,
+    /// Reconnects to attempt before giving up.
+    pub max_retries: u8,
+    /// How long to stay disconnected on the first retry; doubled on every
+    /// further one.
+    pub backoff_ms: u32,
+}
+
+impl Default for EnumerationRetry {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 1000,
+            max_retries: 3,
+            backoff_ms: 100,
+        }
+    }
+}
+
+/// What an [`EnumerationSupervisor`] did, for the application to log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SupervisorEvent {
+    /// Enumeration timed out; disconnected for `backoff_ms`.
+    Retry { attempt: u8, backoff_ms: u32 },
+    /// Connected again after the backoff of `attempt`.
+    Reconnected { attempt: u8 },
+    /// All retries failed; the device stays connected and is left alone.
+    GaveUp,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Watch {
+    Idle { resets: u32 },
+    Waiting { since: u32 },
+    Disconnected { until: u32 },
+    GaveUp,
+}
+
+/// Detects a device that is stuck in the default state (bus resets, but no
+/// `SET_ADDRESS` or `SET_CONFIGURATION` follows) and soft-disconnects and
+/// reconnects it a bounded number of times, with exponential backoff.
+///
+/// Call [`poll`](Self::poll) regularly, e.g. after every `UsbDevice::poll`,
+/// with a millisecond timestamp from any free-running clock; it may wrap.
+pub struct EnumerationSupervisor {
+    retry: EnumerationRetry,
+    watch: Watch,
+    retries: u8,
+}
+
+impl EnumerationSupervisor {
+    pub fn new(retry: EnumerationRetry) -> Self {
+        Self {
+            retry,
+            watch: Watch::Idle { resets: 0 },
+            retries: 0,
+        }
+    }
+
+    /// Retries made since the device was last configured.
+    pub fn retries(&self) -> u8 {
+        self.retries
+    }
+
+    pub fn poll(
+        &mut self,
+        bus: &UsbHSBus,
+        state: UsbDeviceState,
+        now_ms: u32,
+    ) -> Option<SupervisorEvent> {
+        let event = self.step(bus.reset_count(), state, now_ms);
+        match event {
+            Some(SupervisorEvent::Retry { .. }) => bus.set_connected(false),
+            Some(SupervisorEvent::Reconnected { .. }) => bus.set_connected(true),
+            _ => {}
+        }
+        event
+    }
+
+    // `poll` without the bus: the reset count in, the event out, telling
+    // whether to disconnect or reconnect
+    fn step(&mut self, resets: u32, state: UsbDeviceState, now_ms: u32) -> Option<SupervisorEvent> {
+        match self.watch {
+            Watch::GaveUp => None,
+
+            Watch::Disconnected { until } => {
+                if (now_ms.wrapping_sub(until) as i32) < 0 {
+                    return None;
+                }
+                self.watch = Watch::Idle { resets };
+                Some(SupervisorEvent::Reconnected {
+                    attempt: self.retries,
+                })
+            }
+
+            _ if matches!(
+                state,
+                UsbDeviceState::Addressed | UsbDeviceState::Configured
+            ) =>
+            {
+                if state == UsbDeviceState::Configured {
+                    self.retries = 0;
+                }
+                self.watch = Watch::Idle { resets };
+                None
+            }
+
+            Watch::Idle { resets: seen } => {
+                if resets != seen {
+                    self.watch = Watch::Waiting { since: now_ms };
+                }
+                None
+            }
+
+            // some hosts and hubs suspend in the middle of enumeration; that
+            // isn't stuck, so the timeout starts over once they resume
+            Watch::Waiting { .. } if state == UsbDeviceState::Suspend => {
+                self.watch = Watch::Waiting { since: now_ms };
+                None
+            }
+
+            // further resets don't restart the timeout, the host retrying
+            // on its own is exactly what a stuck enumeration looks like
+            Watch::Waiting { since } => {
+                if now_ms.wrapping_sub(since) < self.retry.timeout_ms {
+                    return None;
+                }
+                if self.retries >= self.retry.max_retries {
+                    self.watch = Watch::GaveUp;
+                    return Some(SupervisorEvent::GaveUp);
+                }
+
+                let backoff_ms = self
+                    .retry
+                    .backoff_ms
+                    .saturating_mul(1 << self.retries.min(31));
+                self.retries += 1;
+                self.watch = Watch::Disconnected {
+                    until: now_ms.wrapping_add(backoff_ms),
+                };
+                Some(SupervisorEvent::Retry {
+                    attempt: self.retries,
+                    backoff_ms,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRY: EnumerationRetry = EnumerationRetry {
+        timeout_ms: 1000,
+        max_retries: 2,
+        backoff_ms: 100,
+    };
+
+    #[test]
+    fn retries_with_backoff_then_gives_up() {
+        let mut supervisor = EnumerationSupervisor::new(RETRY);
+        let default = UsbDeviceState::Default;
+        assert_eq!(supervisor.step(0, default, 0), None);
+        // a reset starts the timeout
+        assert_eq!(supervisor.step(1, default, 10), None);
+        assert_eq!(supervisor.step(2, default, 1009), None);
+        assert_eq!(
+            supervisor.step(2, default, 1010),
+            Some(SupervisorEvent::Retry {
+                attempt: 1,
+                backoff_ms: 100
+            })
+        );
+        assert_eq!(supervisor.step(2, default, 1109), None);
+        assert_eq!(
+            supervisor.step(2, default, 1110),
+            Some(SupervisorEvent::Reconnected { attempt: 1 })
+        );
+
+        assert_eq!(supervisor.step(3, default, 1200), None);
+        assert_eq!(
+            supervisor.step(3, default, 2200),
+            Some(SupervisorEvent::Retry {
+                attempt: 2,
+                backoff_ms: 200
+            })
+        );
+        assert_eq!(
+            supervisor.step(3, default, 2400),
+            Some(SupervisorEvent::Reconnected { attempt: 2 })
+        );
+        assert_eq!(supervisor.step(4, default, 2500), None);
+        assert_eq!(
+            supervisor.step(4, default, 3500),
+            Some(SupervisorEvent::GaveUp)
+        );
+        assert_eq!(supervisor.step(5, default, 9000), None);
+        assert_eq!(supervisor.retries(), 2);
+    }
+
+    #[test]
+    fn configured_starts_over() {
+        let mut supervisor = EnumerationSupervisor::new(RETRY);
+        supervisor.step(1, UsbDeviceState::Default, 0);
+        assert!(supervisor.step(1, UsbDeviceState::Default, 1000).is_some());
+        supervisor.step(1, UsbDeviceState::Default, 1100);
+        assert_eq!(supervisor.retries(), 1);
+
+        // addressed stops the timeout, configured also forgets the retries
+        assert_eq!(supervisor.step(2, UsbDeviceState::Addressed, 1200), None);
+        assert_eq!(supervisor.retries(), 1);
+        assert_eq!(supervisor.step(2, UsbDeviceState::Configured, 1300), None);
+        assert_eq!(supervisor.retries(), 0);
+        assert_eq!(supervisor.step(2, UsbDeviceState::Default, 5000), None);
+    }
+
+    #[test]
+    fn suspend_restarts_the_timeout() {
+        let mut supervisor = EnumerationSupervisor::new(RETRY);
+        supervisor.step(0, UsbDeviceState::Default, 0);
+        supervisor.step(1, UsbDeviceState::Default, 0);
+        assert_eq!(supervisor.step(1, UsbDeviceState::Suspend, 900), None);
+        assert_eq!(supervisor.step(1, UsbDeviceState::Suspend, 5000), None);
+        assert_eq!(supervisor.step(1, UsbDeviceState::Default, 5999), None);
+        assert!(supervisor.step(1, UsbDeviceState::Default, 6000).is_some());
+    }
+
+    #[test]
+    fn timestamps_may_wrap() {
+        let mut supervisor = EnumerationSupervisor::new(RETRY);
+        supervisor.step(0, UsbDeviceState::Default, u32::MAX - 500);
+        supervisor.step(1, UsbDeviceState::Default, u32::MAX - 500);
+        assert_eq!(supervisor.step(1, UsbDeviceState::Default, 498), None);
+        assert!(supervisor.step(1, UsbDeviceState::Default, 499).is_some());
+    }
+}
@@ -0,0 +1,451 @@
+use crate::{
+    budget::UsbRamPlan,
+    config::UsbConfig,
+    hal::{constants::NUM_ENDPOINTS, endpoint_memory::EndpointMemoryAllocator},
+    UsbHSBus,
+};
+use core::cell::Cell;
+use cortex_m::interrupt::{self, Mutex};
+use usb_device::{
+    class_prelude::{EndpointIn, EndpointOut, UsbBusAllocator},
+    endpoint::{EndpointAddress, EndpointType},
+    Result, UsbDirection, UsbError,
+};
+
+/// One endpoint of an [`EndpointTable`], e.g. from provisioning data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EndpointSpec {
+    pub direction: UsbDirection,
+    /// Endpoint number to use, or `None` for the next free one.
+    pub number: Option<u8>,
+    pub ep_type: EndpointType,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+/// An endpoint allocated from an [`EndpointSpec`].
+pub enum TableEndpoint<'a> {
+    In(EndpointIn<'a, UsbHSBus>),
+    Out(EndpointOut<'a, UsbHSBus>),
+}
+
+/// Why an [`EndpointTable`] can't be allocated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TableError {
+    /// Index of the offending spec, `None` if it's the table as a whole
+    pub spec: Option<usize>,
+    pub failure: AllocFailure,
+}
+
+/// Why an endpoint can't be allocated, see [`EndpointTable::check`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocFailure {
+    /// Control endpoints can't be allocated from a table (`Unsupported`).
+    UnsupportedType,
+    /// Above what the type allows at high speed (`InvalidState`).
+    PacketTooLarge { limit: u16 },
+    /// 0, or beyond the controller's endpoints (`InvalidEndpoint`). The
+    /// number is the one on the wire: the controller indexes its endpoint
+    /// list by it, so there is no remapping them.
+    NumberOutOfRange,
+    /// That direction of the number is taken already, or the number has an
+    /// endpoint of another type (`InvalidEndpoint`).
+    NumberTaken { number: u8 },
+    /// No free endpoint left for a spec without a number
+    /// (`EndpointOverflow`).
+    OutOfEndpoints,
+    /// The endpoints' buffers and EP0's need more USB RAM than there is
+    /// (`EndpointMemoryOverflow`).
+    OutOfMemory { needed: usize, available: usize },
+}
+
+impl From<AllocFailure> for UsbError {
+    fn from(failure: AllocFailure) -> Self {
+        match failure {
+            AllocFailure::UnsupportedType => UsbError::Unsupported,
+            AllocFailure::PacketTooLarge { .. } => UsbError::InvalidState,
+            AllocFailure::NumberOutOfRange | AllocFailure::NumberTaken { .. } => {
+                UsbError::InvalidEndpoint
+            }
+            AllocFailure::OutOfEndpoints => UsbError::EndpointOverflow,
+            AllocFailure::OutOfMemory { .. } => UsbError::EndpointMemoryOverflow,
+        }
+    }
+}
+
+// what alloc_ep() did with each index: type and directions taken
+type Slots = [(Option<EndpointType>, [bool; 2]); NUM_ENDPOINTS];
+
+// What the bus has allocated, for `EndpointTable::allocate`, which only gets
+// the `UsbBusAllocator` and can't ask the bus. There is one controller, so
+// there is only ever one live bus to keep track of.
+#[derive(Clone, Copy)]
+struct Allocated {
+    slots: Slots,
+    used: usize,
+    end: usize,
+}
+
+static ALLOCATED: Mutex<Cell<Allocated>> = Mutex::new(Cell::new(Allocated {
+    slots: [(None, [false; 2]); NUM_ENDPOINTS],
+    used: UsbRamPlan::new().used(),
+    end: UsbRamPlan::CAPACITY,
+}));
+
+// A new bus, nothing allocated yet
+pub(crate) fn track_bus(allocator: &EndpointMemoryAllocator) {
+    track(allocator, |slots| {
+        *slots = [(None, [false; 2]); NUM_ENDPOINTS]
+    });
+}
+
+pub(crate) fn track_endpoint(
+    allocator: &EndpointMemoryAllocator,
+    index: usize,
+    ep_type: EndpointType,
+    direction: UsbDirection,
+) {
+    track(allocator, |slots| {
+        slots[index].0 = Some(ep_type);
+        slots[index].1[usize::from(direction == UsbDirection::In)] = true;
+    });
+}
+
+fn track(allocator: &EndpointMemoryAllocator, f: impl FnOnce(&mut Slots)) {
+    interrupt::free(|cs| {
+        let cell = ALLOCATED.borrow(cs);
+        let mut allocated = cell.get();
+        f(&mut allocated.slots);
+        allocated.used = allocator.used();
+        allocated.end = allocator.end();
+        cell.set(allocated);
+    });
+}
+
+/// Endpoints allocated in one go from a table built at runtime, in the
+/// order of the specs, see [`allocate`](Self::allocate).
+pub struct EndpointTable<'a> {
+    endpoints: [Option<TableEndpoint<'a>>; 2 * (NUM_ENDPOINTS - 1)],
+}
+
+impl<'a> EndpointTable<'a> {
+    /// Check `specs` against what the bus supports, as if they were all
+    /// that gets allocated besides an EP0 of `control_max_packet_size`, and
+    /// return the USB RAM they need.
+    ///
+    /// Like [`check`](Self::check), with the [`AllocFailure`] turned into
+    /// the `UsbError` that allocating would fail with.
+    pub fn validate(
+        specs: &[EndpointSpec],
+        config: &UsbConfig,
+        control_max_packet_size: u16,
+    ) -> Result<UsbRamPlan> {
+        Self::check(specs, config, control_max_packet_size).map_err(|e| e.failure.into())
+    }
+
+    /// [`validate`](Self::validate), telling which spec can't be allocated
+    /// and why.
+    pub fn check(
+        specs: &[EndpointSpec],
+        config: &UsbConfig,
+        control_max_packet_size: u16,
+    ) -> core::result::Result<UsbRamPlan, TableError> {
+        let capacity = config
+            .usb_ram_size
+            .unwrap_or(UsbRamPlan::CAPACITY)
+            .min(UsbRamPlan::CAPACITY);
+        Self::check_from(
+            specs,
+            config,
+            control_max_packet_size,
+            Allocated {
+                slots: [(None, [false; 2]); NUM_ENDPOINTS],
+                used: UsbRamPlan::new().used(),
+                end: capacity,
+            },
+        )
+    }
+
+    // `check` on top of what is allocated already, EP0 included if it is
+    fn check_from(
+        specs: &[EndpointSpec],
+        config: &UsbConfig,
+        control_max_packet_size: u16,
+        allocated: Allocated,
+    ) -> core::result::Result<UsbRamPlan, TableError> {
+        let mut slots = allocated.slots;
+        let mut plan = UsbRamPlan::from_used(allocated.used);
+
+        for (i, spec) in specs.iter().enumerate() {
+            let fail = |failure| TableError {
+                spec: Some(i),
+                failure,
+            };
+            let limit = match spec.ep_type {
+                EndpointType::Bulk => 512,
+                EndpointType::Interrupt | EndpointType::Isochronous => 1024,
+                EndpointType::Control => return Err(fail(AllocFailure::UnsupportedType)),
+            };
+            if spec.max_packet_size > limit {
+                return Err(fail(AllocFailure::PacketTooLarge { limit }));
+            }
+
+            let dir = usize::from(spec.direction == UsbDirection::In);
+            let range = match spec.number {
+                Some(0) => return Err(fail(AllocFailure::NumberOutOfRange)),
+                Some(n) if usize::from(n) >= NUM_ENDPOINTS => {
+                    return Err(fail(AllocFailure::NumberOutOfRange))
+                }
+                Some(n) => usize::from(n)..usize::from(n) + 1,
+                None => 1..NUM_ENDPOINTS,
+            };
+            let slot = slots[range]
+                .iter_mut()
+                .find(|(ep_type, taken)| ep_type.is_none_or(|t| t == spec.ep_type) && !taken[dir])
+                .ok_or(fail(match spec.number {
+                    Some(number) => AllocFailure::NumberTaken { number },
+                    None => AllocFailure::OutOfEndpoints,
+                }))?;
+            slot.0 = Some(spec.ep_type);
+            slot.1[dir] = true;
+
+            plan = match spec.direction {
+                UsbDirection::Out => {
+                    plan.out_endpoint(spec.max_packet_size, config.out_buffer_size)
+                }
+                UsbDirection::In => {
+                    plan.in_endpoint_buffered(spec.max_packet_size, config.in_buffer_size)
+                }
+            };
+        }
+
+        if slots[0].0.is_none() {
+            plan = plan.control(control_max_packet_size);
+        }
+        if plan.used() > allocated.end {
+            return Err(TableError {
+                spec: None,
+                failure: AllocFailure::OutOfMemory {
+                    needed: plan.used(),
+                    available: allocated.end,
+                },
+            });
+        }
+        Ok(plan)
+    }
+
+    /// Validate `specs` against what `alloc` has left, i.e. the endpoint
+    /// numbers and USB RAM other classes took already, then allocate them
+    /// from it. `config` has to be the one `alloc` was built with. Nothing
+    /// is allocated if the table doesn't fit.
+    pub fn allocate(
+        alloc: &'a UsbBusAllocator<UsbHSBus>,
+        specs: &[EndpointSpec],
+        config: &UsbConfig,
+        control_max_packet_size: u16,
+    ) -> Result<Self> {
+        let allocated = interrupt::free(|cs| ALLOCATED.borrow(cs).get());
+        Self::check_from(specs, config, control_max_packet_size, allocated)
+            .map_err(|e| UsbError::from(e.failure))?;
+
+        let mut table = EndpointTable {
+            endpoints: Default::default(),
+        };
+        if specs.len() > table.endpoints.len() {
+            return Err(UsbError::EndpointOverflow);
+        }
+        for (slot, spec) in table.endpoints.iter_mut().zip(specs) {
+            let ep_addr = spec
+                .number
+                .map(|n| EndpointAddress::from_parts(n.into(), spec.direction));
+            let (ep_type, size, interval) = (spec.ep_type, spec.max_packet_size, spec.interval);
+            *slot = Some(match spec.direction {
+                UsbDirection::In => {
+                    TableEndpoint::In(alloc.alloc(ep_addr, ep_type, size, interval)?)
+                }
+                UsbDirection::Out => {
+                    TableEndpoint::Out(alloc.alloc(ep_addr, ep_type, size, interval)?)
+                }
+            });
+        }
+        Ok(table)
+    }
+
+    /// The endpoint allocated for `specs[i]`.
+    pub fn get(&self, i: usize) -> Option<&TableEndpoint<'a>> {
+        self.endpoints.get(i)?.as_ref()
+    }
+
+    /// Take the endpoint allocated for `specs[i]` out of the table, e.g. to
+    /// hand it to a class.
+    pub fn take(&mut self, i: usize) -> Option<TableEndpoint<'a>> {
+        self.endpoints.get_mut(i)?.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(number: Option<u8>, ep_type: EndpointType, max_packet_size: u16) -> EndpointSpec {
+        EndpointSpec {
+            direction: UsbDirection::In,
+            number,
+            ep_type,
+            max_packet_size,
+            interval: 1,
+        }
+    }
+
+    fn fresh() -> Allocated {
+        Allocated {
+            slots: [(None, [false; 2]); NUM_ENDPOINTS],
+            used: UsbRamPlan::new().used(),
+            end: UsbRamPlan::CAPACITY,
+        }
+    }
+
+    #[test]
+    fn check_reports_the_spec() {
+        let config = UsbConfig::default();
+        let bulk = spec(None, EndpointType::Bulk, 512);
+        let cases = [
+            (
+                spec(None, EndpointType::Control, 64),
+                AllocFailure::UnsupportedType,
+            ),
+            (
+                spec(None, EndpointType::Bulk, 1024),
+                AllocFailure::PacketTooLarge { limit: 512 },
+            ),
+            (
+                spec(Some(0), EndpointType::Bulk, 64),
+                AllocFailure::NumberOutOfRange,
+            ),
+            (
+                spec(Some(NUM_ENDPOINTS as u8), EndpointType::Bulk, 64),
+                AllocFailure::NumberOutOfRange,
+            ),
+        ];
+        for (bad, failure) in cases {
+            let err = EndpointTable::check(&[bulk, bad], &config, 64).unwrap_err();
+            assert_eq!(
+                err,
+                TableError {
+                    spec: Some(1),
+                    failure
+                }
+            );
+        }
+
+        // an OUT and an IN share a number, two INs don't
+        let out = EndpointSpec {
+            direction: UsbDirection::Out,
+            ..spec(Some(1), EndpointType::Bulk, 512)
+        };
+        let in_ = spec(Some(1), EndpointType::Bulk, 512);
+        assert!(EndpointTable::check(&[out, in_], &config, 64).is_ok());
+        let err = EndpointTable::check(&[out, in_, in_], &config, 64).unwrap_err();
+        assert_eq!(err.failure, AllocFailure::NumberTaken { number: 1 });
+        // nor do endpoints of different types
+        let interrupt = spec(Some(1), EndpointType::Interrupt, 64);
+        let err = EndpointTable::check(&[out, interrupt], &config, 64).unwrap_err();
+        assert_eq!(err.failure, AllocFailure::NumberTaken { number: 1 });
+
+        let err = EndpointTable::check(&[bulk; NUM_ENDPOINTS], &config, 64).unwrap_err();
+        assert_eq!(err.failure, AllocFailure::OutOfEndpoints);
+        assert!(matches!(
+            EndpointTable::validate(&[bulk; NUM_ENDPOINTS], &config, 64),
+            Err(UsbError::EndpointOverflow)
+        ));
+    }
+
+    #[test]
+    fn check_plans_the_memory() {
+        let specs = [
+            EndpointSpec {
+                direction: UsbDirection::Out,
+                ..spec(None, EndpointType::Bulk, 512)
+            },
+            spec(None, EndpointType::Bulk, 512),
+        ];
+        let config = UsbConfig {
+            out_buffer_size: 1024,
+            ..UsbConfig::default()
+        };
+        let plan = EndpointTable::check(&specs, &config, 64).unwrap();
+        let expected = UsbRamPlan::new()
+            .out_endpoint(512, 1024)
+            .in_endpoint(512)
+            .control(64);
+        assert_eq!(plan, expected);
+
+        let config = UsbConfig {
+            usb_ram_size: Some(expected.used() - 1),
+            ..config
+        };
+        let err = EndpointTable::check(&specs, &config, 64).unwrap_err();
+        assert_eq!(
+            err,
+            TableError {
+                spec: None,
+                failure: AllocFailure::OutOfMemory {
+                    needed: expected.used(),
+                    available: expected.used() - 1,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn isochronous_is_accepted() {
+        let specs = [spec(None, EndpointType::Isochronous, 1024)];
+        let plan = EndpointTable::check(&specs, &UsbConfig::default(), 64).unwrap();
+        assert_eq!(plan, UsbRamPlan::new().in_endpoint(1024).control(64));
+
+        let specs = [spec(None, EndpointType::Isochronous, 1025)];
+        let err = EndpointTable::check(&specs, &UsbConfig::default(), 64).unwrap_err();
+        assert_eq!(err.failure, AllocFailure::PacketTooLarge { limit: 1024 });
+    }
+
+    #[test]
+    fn taken_numbers_are_skipped() {
+        let mut allocated = fresh();
+        allocated.slots[1] = (Some(EndpointType::Bulk), [false, true]);
+        let config = UsbConfig::default();
+
+        let specs = [spec(Some(1), EndpointType::Bulk, 512)];
+        let err = EndpointTable::check_from(&specs, &config, 64, allocated).unwrap_err();
+        assert_eq!(err.failure, AllocFailure::NumberTaken { number: 1 });
+
+        // the next free number, as alloc_ep() would pick
+        let specs = [spec(None, EndpointType::Interrupt, 64)];
+        assert!(EndpointTable::check_from(&specs, &config, 64, allocated).is_ok());
+        allocated.slots[2..].fill((Some(EndpointType::Bulk), [true; 2]));
+        let err = EndpointTable::check_from(&specs, &config, 64, allocated).unwrap_err();
+        assert_eq!(err.failure, AllocFailure::OutOfEndpoints);
+    }
+
+    #[test]
+    fn memory_already_used_counts() {
+        let config = UsbConfig::default();
+        let specs = [spec(None, EndpointType::Bulk, 512)];
+        assert!(EndpointTable::check_from(&specs, &config, 64, fresh()).is_ok());
+
+        // other classes took all but 256 bytes
+        let mut allocated = fresh();
+        allocated.used = UsbRamPlan::CAPACITY - 256;
+        let err = EndpointTable::check_from(&specs, &config, 64, allocated).unwrap_err();
+        assert!(matches!(err.failure, AllocFailure::OutOfMemory { .. }));
+        assert_eq!(err.spec, None);
+    }
+
+    #[test]
+    fn allocated_control_endpoint_is_not_planned_again() {
+        let mut allocated = fresh();
+        allocated.slots[0] = (Some(EndpointType::Control), [true; 2]);
+        allocated.used = UsbRamPlan::new().control(64).used();
+        let plan = EndpointTable::check_from(&[], &UsbConfig::default(), 64, allocated).unwrap();
+        assert_eq!(plan, UsbRamPlan::new().control(64));
+    }
+}
@@ -0,0 +1,82 @@
+use crate::bypass::{ControlBypass, ControlEvent, RawControl};
+
+/// USB 2.0 test modes (USB 2.0 §7.1.20), driven by `DEVCMDSTAT.PHY_TEST_MODE`,
+/// see [`UsbHSBus::enter_test_mode`](crate::UsbHSBus::enter_test_mode).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestMode {
+    J = 1,
+    K = 2,
+    Se0Nak = 3,
+    /// Send the test packet over and over
+    Packet = 4,
+    ForceEnable = 5,
+}
+
+impl TestMode {
+    /// The mode of a `SET_FEATURE(TEST_MODE)` test selector.
+    pub fn from_selector(selector: u8) -> Option<Self> {
+        Some(match selector {
+            1 => TestMode::J,
+            2 => TestMode::K,
+            3 => TestMode::Se0Nak,
+            4 => TestMode::Packet,
+            5 => TestMode::ForceEnable,
+            _ => return None,
+        })
+    }
+}
+
+// SET_FEATURE(TEST_MODE) to the device, the selector in wIndex's high byte.
+// Anything else, a non-zero wLength included (USB 2.0 9.4.9), is left to
+// usb-device, which stalls it.
+fn is_test_mode_request(setup: &[u8; 8]) -> bool {
+    setup[..4] == [0x00, 0x03, 0x02, 0x00] && setup[4] == 0 && setup[6..] == [0, 0]
+}
+
+fn handle(event: ControlEvent, raw: &mut RawControl<'_>) {
+    match event {
+        ControlEvent::Setup(setup) => match TestMode::from_selector(setup[5]) {
+            Some(mode) => {
+                raw.bus.pending_test_mode.borrow(raw.cs).set(Some(mode));
+                raw.write(&[]).ok();
+            }
+            None => raw.stall(),
+        },
+        // the test mode has to start after the status stage
+        ControlEvent::InComplete => {
+            if let Some(mode) = raw.bus.pending_test_mode.borrow(raw.cs).take() {
+                raw.bus.enter_test_mode_in(raw.cs, mode);
+            }
+        }
+        ControlEvent::Out => {}
+    }
+}
+
+/// Answers `SET_FEATURE(TEST_MODE)` for
+/// [`UsbConfig::test_mode_requests`](crate::UsbConfig::test_mode_requests).
+pub(crate) const TEST_MODE_BYPASS: ControlBypass = ControlBypass {
+    matches: is_test_mode_request,
+    handler: handle,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_request_needs_zero_length() {
+        assert!(is_test_mode_request(&[
+            0x00, 0x03, 0x02, 0x00, 0x00, 0x04, 0x00, 0x00
+        ]));
+        assert!(!is_test_mode_request(&[
+            0x00, 0x03, 0x02, 0x00, 0x00, 0x04, 0x01, 0x00
+        ]));
+        assert!(!is_test_mode_request(&[
+            0x00, 0x03, 0x02, 0x00, 0x00, 0x04, 0x00, 0x01
+        ]));
+        // a feature other than TEST_MODE
+        assert!(!is_test_mode_request(&[
+            0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00
+        ]));
+    }
+}
@@ -0,0 +1,104 @@
+use usb_device::{bus::UsbBus, endpoint::EndpointOut, Result};
+
+/// Reassembles an OUT transfer from its packets, for class code that deals
+/// in transfers (MSC, DFU) rather than packets.
+///
+/// A transfer ends with a short packet, a zero-length one included, or
+/// when the expected length is in, whichever comes first:
+///
+/// ```ignore
+/// let mut reader = TransferReader::with_length(&mut block, 512);
+/// // on every `endpoint_out` for the endpoint
+/// if reader.read(&ep_out)? {
+///     write_block(reader.data());
+///     reader.reset();
+/// }
+/// ```
+pub struct TransferReader<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    expected: Option<usize>,
+    complete: bool,
+}
+
+impl<'a> TransferReader<'a> {
+    /// Read a transfer of unknown length into `buf`, up to a short packet.
+    ///
+    /// A transfer that exactly fills `buf` needs the host's trailing ZLP to
+    /// complete.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            buf,
+            len: 0,
+            expected: None,
+            complete: false,
+        }
+    }
+
+    /// Read a transfer of `expected` bytes, at most `buf.len()`. A short
+    /// packet still ends it early.
+    pub fn with_length(buf: &'a mut [u8], expected: usize) -> Self {
+        let mut reader = Self::new(buf);
+        reader.reset_with_length(expected);
+        reader
+    }
+
+    /// Read the next packet from `ep`, if the transfer isn't complete yet;
+    /// `true` once it is.
+    ///
+    /// Errors are `ep.read`'s: `WouldBlock` while there is no packet,
+    /// `BufferOverflow` if a packet doesn't fit the rest of the buffer,
+    /// which leaves it to be read elsewhere.
+    pub fn read<B: UsbBus>(&mut self, ep: &EndpointOut<'_, B>) -> Result<bool> {
+        if self.complete {
+            return Ok(true);
+        }
+        let end = self.expected.unwrap_or(self.buf.len());
+        let count = ep.read(&mut self.buf[self.len..end])?;
+        self.len += count;
+        // a primed endpoint hands over several packets per read, see
+        // `OutPacket::short`
+        let max_packet_size = usize::from(ep.max_packet_size());
+        let short = max_packet_size == 0 || count == 0 || !count.is_multiple_of(max_packet_size);
+        self.complete = short || Some(self.len) == self.expected;
+        Ok(self.complete)
+    }
+
+    /// Whether the transfer is complete.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// What was received so far.
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Start over for the next transfer, of unknown length.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.expected = None;
+        self.complete = false;
+    }
+
+    /// Start over for the next transfer, of `expected` bytes.
+    pub fn reset_with_length(&mut self, expected: usize) {
+        let expected = expected.min(self.buf.len());
+        self.len = 0;
+        self.expected = Some(expected);
+        self.complete = expected == 0;
+    }
+
+    /// Give the buffer back.
+    pub fn into_buffer(self) -> &'a mut [u8] {
+        self.buf
+    }
+}
@@ -1,13 +1,46 @@
+#[cfg(feature = "setup-capture")]
+use crate::debug::SetupCapture;
+#[cfg(feature = "metrics")]
+use crate::debug::{RearmLatency, ResetHistory, Throughput};
 use crate::{
+    budget,
+    bypass::{ControlBypass, ControlEvent, RawControl},
+    config::{ErrorLimitAction, InterruptAck, ServiceMode, UsbConfig, VbusDetach},
+    debug::{
+        self, AllocationFailure, ControlNakCounts, EnumerationRecord, EnumerationState, LineStatus,
+        LinkSpeed, PhyRegisters,
+    },
+    error::{ErrorHandler, UsbHsError},
+    events::{BusEvent, EventListener, Listeners},
+    frame::FrameNumber,
     hal::{
         constants::{EP_MEM_ADDR, NUM_ENDPOINTS},
-        endpoint::Endpoint,
-        endpoint_memory::EndpointMemoryAllocator,
+        devcmdstat,
+        endpoint::{Endpoint, OutPacket},
+        endpoint_memory::{EndpointBuffer, EndpointMemoryAllocator},
         endpoint_registers,
     },
-    usbhs::UsbHS,
+    lpm::{L1Exit, LpmPolicy, LpmRequest},
+    phy::{self, PhyClockGating, PhyPowerDown, SuspendMode, SuspendedPhy},
+    power,
+    quirks::Quirks,
+    table,
+    test_mode::{TestMode, TEST_MODE_BYPASS},
+    usbhs::{self, UsbHS},
+};
+#[cfg(feature = "setup-capture")]
+use core::cell::RefCell;
+use core::{
+    cell::{Cell, UnsafeCell},
+    mem::{ManuallyDrop, MaybeUninit},
+    ptr,
+    sync::atomic::{AtomicBool, Ordering},
 };
-use cortex_m::interrupt::{self, Mutex};
+use cortex_m::{
+    interrupt::{self, CriticalSection, Mutex},
+    peripheral::{scb::VectActive, SCB},
+};
+use lpc55_hal::raw::{USB1, USBHSH, USBPHY};
 use usb_device::{
     bus::{PollResult, UsbBus},
     class_prelude::UsbBusAllocator,
@@ -15,34 +48,1963 @@ use usb_device::{
     Result, UsbDirection, UsbError,
 };
 
-pub struct UsbHSBus {
-    usb_regs: Mutex<UsbHS>,
-    ep_regs: Mutex<endpoint_registers::Instance>,
-    endpoints: [Endpoint; NUM_ENDPOINTS],
-    ep_allocator: EndpointMemoryAllocator,
-    max_endpoint: usize,
-}
+/// Storage for a bus allocator in an application `static`, built in const
+/// context and filled once the controller is up:
+///
+/// ```ignore
+/// static USB_BUS: StaticUsbBus = StaticUsbBus::new();
+///
+/// let usb_bus = USB_BUS.init(usb, UsbConfig::default())?.unwrap();
+/// let mut serial = SerialPort::new(usb_bus);
+/// ```
+///
+/// [`UsbHSBus::init_static`] does the same with a `static` inside the crate.
+pub struct StaticUsbBus {
+    taken: AtomicBool,
+    allocator: UnsafeCell<MaybeUninit<UsbBusAllocator<UsbHSBus>>>,
+}
+
+// SAFETY: `allocator` is written exactly once, by whoever flipped `taken`,
+// and the only reference to it is handed out right after; as that reference
+// is `!Send`, the allocator is never shared between contexts.
+unsafe impl Sync for StaticUsbBus {}
+
+impl StaticUsbBus {
+    pub const fn new() -> Self {
+        Self {
+            taken: AtomicBool::new(false),
+            allocator: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Build the bus like [`UsbHSBus::new_with_config`], failing the same
+    /// way, and keep its allocator here. `None` if called before.
+    ///
+    /// The bus then lives forever, so it can't be dropped to
+    /// [`UsbHS::reclaim`] the controller.
+    pub fn init(
+        &'static self,
+        usb_device: UsbHS,
+        config: UsbConfig,
+    ) -> core::result::Result<Option<&'static UsbBusAllocator<UsbHSBus>>, UsbHsError> {
+        if !EndpointMemoryAllocator::is_placed() {
+            return Err(UsbHsError::UsbRamMisplaced);
+        }
+        if self.taken.swap(true, Ordering::AcqRel) {
+            return Ok(None);
+        }
+        let bus = UsbHSBus::new_with_config(usb_device, config)?;
+        // SAFETY: see `impl Sync`
+        let allocator = unsafe { &mut *self.allocator.get() };
+        Ok(Some(allocator.write(bus)))
+    }
+}
+
+impl Default for StaticUsbBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Backing storage of `UsbHSBus::init_static`
+static ALLOCATOR: StaticUsbBus = StaticUsbBus::new();
+
+/// Millisecond timestamp source, see [`UsbHSBus::set_clock`].
+pub type Clock = fn() -> u32;
+
+/// Generator of the next IN transfer of an endpoint, see
+/// [`UsbHSBus::set_in_refill`]. Writes it straight into the endpoint's USB
+/// RAM buffer, one max-size packet or
+/// [`in_buffer_size`](crate::UsbConfig::in_buffer_size) long, and returns
+/// its length, or `None` to let the endpoint go idle.
+pub type InRefill = fn(&mut [u8]) -> Option<usize>;
+
+/// What [`UsbHSBus::poll_fast`] found: the endpoint bitmasks of
+/// `PollResult::Data` and the bus events as flags. At most one flag is set,
+/// and none along with endpoint bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PollEvents {
+    pub ep_out: u16,
+    pub ep_in_complete: u16,
+    pub ep_setup: u16,
+    pub flags: u8,
+}
+
+impl PollEvents {
+    pub const RESET: u8 = 1 << 0;
+    pub const SUSPEND: u8 = 1 << 1;
+    pub const RESUME: u8 = 1 << 2;
+
+    fn flag(flag: u8) -> Self {
+        Self {
+            flags: flag,
+            ..Self::default()
+        }
+    }
+
+    /// Nothing happened.
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl From<PollEvents> for PollResult {
+    fn from(events: PollEvents) -> Self {
+        match events.flags {
+            PollEvents::RESET => PollResult::Reset,
+            PollEvents::SUSPEND => PollResult::Suspend,
+            PollEvents::RESUME => PollResult::Resume,
+            _ if (events.ep_out | events.ep_in_complete | events.ep_setup) != 0 => {
+                PollResult::Data {
+                    ep_out: events.ep_out,
+                    ep_in_complete: events.ep_in_complete,
+                    ep_setup: events.ep_setup,
+                }
+            }
+            _ => PollResult::None,
+        }
+    }
+}
+
+// INTEN bits of all physical endpoints, EP0 OUT to EP5 IN
+const ALL_EP_INTS: u32 = (1 << (2 * NUM_ENDPOINTS)) - 1;
+
+type ServiceOrder = [u8; NUM_ENDPOINTS - 1];
+
+const DEFAULT_SERVICE_ORDER: ServiceOrder = {
+    let mut order = [0; NUM_ENDPOINTS - 1];
+    let mut i = 0;
+    while i < order.len() {
+        order[i] = i as u8 + 1;
+        i += 1;
+    }
+    order
+};
+
+// VBUS as tracked for `UsbConfig::vbus_detach`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VbusState {
+    Present,
+    Lost,
+    Returned { since: Option<u32> },
+}
+
+pub struct UsbHSBus {
+    // taken out on drop, see `UsbHS::reclaim`
+    usb_regs: Mutex<ManuallyDrop<UsbHS>>,
+    ep_regs: Mutex<endpoint_registers::Instance>,
+    endpoints: [Endpoint; NUM_ENDPOINTS],
+    ep_allocator: EndpointMemoryAllocator,
+    max_endpoint: usize,
+    enabled: bool,
+    // set by `disable()`, the next session starts with nothing allocated
+    disabled: Mutex<Cell<bool>>,
+    config: UsbConfig,
+    suspend_mode: Mutex<Cell<SuspendMode>>,
+    suspended_phy: Mutex<Cell<Option<SuspendedPhy>>>,
+    control_naks: Mutex<Cell<ControlNakCounts>>,
+    resets: Mutex<Cell<u32>>,
+    #[cfg(feature = "metrics")]
+    reset_history: Mutex<Cell<ResetHistory>>,
+    // cycles the last low-power resume took
+    #[cfg(feature = "metrics")]
+    resume_cycles: Mutex<Cell<Option<u32>>>,
+    // suspend state last reported by poll()
+    link_suspended: Mutex<Cell<bool>>,
+    // `DRES_C` cleared by `line_status()`, still to be reported by poll()
+    reset_latched: Mutex<Cell<bool>>,
+    clock: Mutex<Cell<Option<Clock>>>,
+    // when `DSUS` was first seen set, for debouncing
+    suspend_since: Mutex<Cell<Option<u32>>>,
+    enumeration: Mutex<Cell<EnumerationRecord>>,
+    // execution context whose polls get the events, see `release_poller`
+    poller: Mutex<Cell<Option<VectActive>>>,
+    error_handler: Mutex<Cell<Option<ErrorHandler>>>,
+    // speed of the current session, known from its first SETUP on
+    speed: Mutex<Cell<LinkSpeed>>,
+    // last INFO.ERR_CODE seen by `poll()`
+    err_code: Mutex<Cell<u8>>,
+    // consecutive anomalies per endpoint, OUT and IN, for `error_limit`
+    ep_errors: Mutex<Cell<[[u8; 2]; NUM_ENDPOINTS]>>,
+    // whether the session's power (`config.power_profile` or the PMC
+    // domains) got acquired and not yet released
+    power_held: Mutex<Cell<bool>>,
+    // non-control endpoints in the order poll() services them
+    service_order: Mutex<Cell<ServiceOrder>>,
+    control_bypass: Mutex<Cell<Option<ControlBypass>>>,
+    in_refill: Mutex<Cell<[Option<InRefill>; NUM_ENDPOINTS]>>,
+    listeners: Mutex<Cell<Listeners>>,
+    vbus: Mutex<Cell<VbusState>>,
+    // whether the current suspend is L1, and who ended the last one
+    in_l1: Mutex<Cell<bool>>,
+    l1_wake_requested: Mutex<Cell<bool>>,
+    last_l1_exit: Mutex<Cell<Option<L1Exit>>>,
+    // whether the current control transfer goes to `control_bypass`
+    bypass_active: Mutex<Cell<Option<ControlBypass>>>,
+    // `INTONNAK_AI` as set by the application, whether `poll()` found the
+    // bus idle, the IN endpoints NAKed while nothing was armed on them, and
+    // the physical IN bits kept out of INTEN since their first idle NAK
+    nak_in: Mutex<Cell<bool>>,
+    idle: Mutex<Cell<bool>>,
+    nak_wakes: Mutex<Cell<u16>>,
+    nak_masked: Mutex<Cell<u32>>,
+    service_mode: Mutex<Cell<ServiceMode>>,
+    // physical endpoint bits kept out of INTEN
+    ep_int_masked: Mutex<Cell<u32>>,
+    // selected by SET_FEATURE(TEST_MODE), entered after its status stage
+    pub(crate) pending_test_mode: Mutex<Cell<Option<TestMode>>>,
+    #[cfg(feature = "setup-capture")]
+    setup_capture: Mutex<RefCell<SetupCapture>>,
+}
+
+impl UsbHSBus {
+    /// Build the bus on an initialized controller. [`UsbHsError::UsbRamMisplaced`]
+    /// if the `usb-ram-section` reservation isn't where the bus expects it.
+    pub fn new(usb_device: UsbHS) -> core::result::Result<UsbBusAllocator<UsbHSBus>, UsbHsError> {
+        Self::new_with_config(usb_device, UsbConfig::default())
+    }
+
+    /// Like [`new`](Self::new), but give every non-control OUT endpoint a
+    /// buffer of at least `out_buffer_size` bytes (rounded up to whole
+    /// packets), so a whole transfer can be received with one
+    /// [`prime_out`](Self::prime_out) instead of one packet at a time.
+    pub fn new_with_out_buffer_size(
+        usb_device: UsbHS,
+        out_buffer_size: usize,
+    ) -> core::result::Result<UsbBusAllocator<UsbHSBus>, UsbHsError> {
+        Self::new_with_config(
+            usb_device,
+            UsbConfig {
+                out_buffer_size,
+                ..UsbConfig::default()
+            },
+        )
+    }
+
+    /// Like [`new`](Self::new), with the session policy in `config`, which
+    /// takes effect in `enable()`.
+    pub fn new_with_config(
+        usb_device: UsbHS,
+        config: UsbConfig,
+    ) -> core::result::Result<UsbBusAllocator<UsbHSBus>, UsbHsError> {
+        Self::new_bus(usb_device, config).map(UsbBusAllocator::new)
+    }
+
+    // the bus itself, for users that drive it without `usb-device`
+    pub(crate) fn new_bus(
+        usb_device: UsbHS,
+        config: UsbConfig,
+    ) -> core::result::Result<UsbHSBus, UsbHsError> {
+        if !EndpointMemoryAllocator::is_placed() {
+            return Err(UsbHsError::UsbRamMisplaced);
+        }
+        let ep_regs = endpoint_registers::attach().unwrap();
+        let ep_allocator = new_allocator(&config);
+        table::track_bus(&ep_allocator);
+        Ok(UsbHSBus {
+            usb_regs: Mutex::new(ManuallyDrop::new(usb_device)),
+            ep_allocator,
+            max_endpoint: 0,
+            enabled: false,
+            disabled: Mutex::new(Cell::new(false)),
+            config,
+            suspend_mode: Mutex::new(Cell::new(config.suspend_mode)),
+            suspended_phy: Mutex::new(Cell::new(None)),
+            control_naks: Mutex::new(Cell::new(ControlNakCounts {
+                in_naks: 0,
+                out_naks: 0,
+            })),
+            resets: Mutex::new(Cell::new(0)),
+            #[cfg(feature = "metrics")]
+            reset_history: Mutex::new(Cell::new(ResetHistory::default())),
+            #[cfg(feature = "metrics")]
+            resume_cycles: Mutex::new(Cell::new(None)),
+            link_suspended: Mutex::new(Cell::new(false)),
+            reset_latched: Mutex::new(Cell::new(false)),
+            clock: Mutex::new(Cell::new(None)),
+            suspend_since: Mutex::new(Cell::new(None)),
+            enumeration: Mutex::new(Cell::new(EnumerationRecord::default())),
+            poller: Mutex::new(Cell::new(None)),
+            error_handler: Mutex::new(Cell::new(None)),
+            speed: Mutex::new(Cell::new(LinkSpeed::Unknown)),
+            err_code: Mutex::new(Cell::new(0)),
+            ep_errors: Mutex::new(Cell::new([[0; 2]; NUM_ENDPOINTS])),
+            power_held: Mutex::new(Cell::new(false)),
+            service_order: Mutex::new(Cell::new(DEFAULT_SERVICE_ORDER)),
+            control_bypass: Mutex::new(Cell::new(None)),
+            in_refill: Mutex::new(Cell::new([None; NUM_ENDPOINTS])),
+            listeners: Mutex::new(Cell::new(Listeners::default())),
+            vbus: Mutex::new(Cell::new(VbusState::Present)),
+            in_l1: Mutex::new(Cell::new(false)),
+            l1_wake_requested: Mutex::new(Cell::new(false)),
+            last_l1_exit: Mutex::new(Cell::new(None)),
+            bypass_active: Mutex::new(Cell::new(None)),
+            nak_in: Mutex::new(Cell::new(config.nak_in)),
+            idle: Mutex::new(Cell::new(false)),
+            nak_wakes: Mutex::new(Cell::new(0)),
+            nak_masked: Mutex::new(Cell::new(0)),
+            service_mode: Mutex::new(Cell::new(config.service_mode)),
+            ep_int_masked: Mutex::new(Cell::new(0)),
+            pending_test_mode: Mutex::new(Cell::new(None)),
+            #[cfg(feature = "setup-capture")]
+            setup_capture: Mutex::new(RefCell::new(SetupCapture::new())),
+            endpoints: new_endpoints(&ep_regs, config.hold_endpoints),
+            ep_regs: Mutex::new(ep_regs),
+        })
+    }
+
+    // The PMC side of `enable()`, bringing a PHY that the last session
+    // powered down back up like init does
+    fn acquire_power(&self) {
+        if !power::acquire_usb_domains() {
+            return;
+        }
+        let core_hz = interrupt::free(|cs| self.usb_regs.borrow(cs).core_hz);
+        let delay_us = |us: u32| cortex_m::asm::delay(core_hz / 1_000_000 * us);
+        delay_us(power::PHY_POWER_UP_US);
+        interrupt::free(|cs| self.usb_regs.borrow(cs).reinit_phy(delay_us));
+    }
+
+    // After `disable()`, free every endpoint and all of USB RAM, so the
+    // next session allocates from scratch
+    fn start_session(&mut self) {
+        if !interrupt::free(|cs| self.disabled.borrow(cs).replace(false)) {
+            return;
+        }
+        self.ep_allocator = new_allocator(&self.config);
+        table::track_bus(&self.ep_allocator);
+        self.max_endpoint = 0;
+        self.enabled = false;
+        let hold = self.config.hold_endpoints;
+        self.endpoints = interrupt::free(|cs| {
+            self.in_refill.borrow(cs).set([None; NUM_ENDPOINTS]);
+            self.ep_errors.borrow(cs).set([[0; 2]; NUM_ENDPOINTS]);
+            self.ep_int_masked.borrow(cs).set(0);
+            self.nak_masked.borrow(cs).set(0);
+            self.nak_wakes.borrow(cs).set(0);
+            self.idle.borrow(cs).set(false);
+            self.speed.borrow(cs).set(LinkSpeed::Unknown);
+            self.enumeration
+                .borrow(cs)
+                .set(EnumerationRecord::default());
+            self.bypass_active.borrow(cs).set(None);
+            self.pending_test_mode.borrow(cs).set(None);
+            new_endpoints(self.ep_regs.borrow(cs), hold)
+        });
+    }
+
+    /// Like [`new_with_config`](Self::new_with_config), but keep the allocator
+    /// in a `static` inside the crate, so it outlives the `UsbDevice` and the
+    /// classes without an `unsafe` `static mut` in the application.
+    ///
+    /// `Ok(None)` if called before. The bus then lives forever, so it can't
+    /// be dropped to [`UsbHS::reclaim`] the controller. See [`StaticUsbBus`]
+    /// for a `static` of the application's own.
+    pub fn init_static(
+        usb_device: UsbHS,
+        config: UsbConfig,
+    ) -> core::result::Result<Option<&'static UsbBusAllocator<UsbHSBus>>, UsbHsError> {
+        ALLOCATOR.init(usb_device, config)
+    }
+
+    // poll() only walks endpoints up to `max_endpoint`, so keep it current
+    // even for allocations after enable(), which also have to be armed
+    fn track_allocation(&mut self, index: usize, ep_dir: UsbDirection) {
+        self.max_endpoint = self.max_endpoint.max(index);
+        if let Some(ep_type) = self.endpoints[index].ep_type() {
+            table::track_endpoint(&self.ep_allocator, index, ep_type, ep_dir);
+        }
+        if !self.enabled {
+            return;
+        }
+        interrupt::free(|cs| {
+            let eps = self.ep_regs.borrow(cs);
+            let ep = &self.endpoints[index];
+            match ep_dir {
+                UsbDirection::Out => {
+                    ep.reset_out_buf(cs, eps);
+                    if index == 0 {
+                        ep.reset_setup_buf(cs, eps);
+                    }
+                }
+                UsbDirection::In => ep.reset_in_buf(cs, eps),
+            }
+        });
+    }
+
+    /// End the session: disconnect from the host, disable the controller and
+    /// its interrupts and deactivate every endpoint, then power the PHY down
+    /// or release the [`power_profile`](UsbConfig::power_profile).
+    ///
+    /// The next `alloc_ep()` or `enable()` then starts a new session, with
+    /// every endpoint and all of USB RAM free again, so a different set of
+    /// endpoints can be allocated and enabled, e.g. to switch between a
+    /// DFU-only and a composite personality. `usb-device` can't allocate
+    /// from a `UsbBusAllocator` a device was built on, so that is for buses
+    /// driven without it, like the one of the `capi` module. With
+    /// `usb-device`, drop the `UsbDevice`, the classes and the allocator
+    /// instead, and build a new bus from [`UsbHS::reclaim`].
+    /// Dropping the bus disables it as well.
+    pub fn disable(&self) {
+        interrupt::free(|cs| {
+            self.disabled.borrow(cs).set(true);
+            let usb = self.usb_regs.borrow(cs);
+            if let Some(saved) = self.suspended_phy.borrow(cs).take() {
+                self.exit_low_power(cs, usb, saved);
+            }
+
+            usb.dev.inten.write(|w| unsafe { w.bits(0) });
+            // also clears all pending change flags
+            usb.dev.devcmdstat.modify(|_, w| unsafe {
+                w.dev_en()
+                    .clear_bit()
+                    .dcon()
+                    .clear_bit()
+                    .dev_addr()
+                    .bits(0)
+                    .setup()
+                    .set_bit()
+                    .dcon_c()
+                    .set_bit()
+                    .dsus_c()
+                    .set_bit()
+                    .dres_c()
+                    .set_bit()
+            });
+            self.ep_regs.borrow(cs).reset();
+            usb.dev.intstat.write(|w| unsafe { w.bits(!0) });
+        });
+
+        if interrupt::free(|cs| self.power_held.borrow(cs).replace(false)) {
+            match self.config.power_profile {
+                Some(profile) => (profile.release)(),
+                None => power::release_usb_domains(),
+            }
+        }
+    }
+
+    /// Soft-connect or -disconnect (`DEVCMDSTAT.DCON`), i.e. enable or
+    /// disable the D+ pull-up. The bus connects in `enable()`.
+    pub fn set_connected(&self, connected: bool) {
+        interrupt::free(|cs| {
+            devcmdstat::modify(&self.usb_regs.borrow(cs).dev, |w| w.dcon().bit(connected))
+        });
+    }
+
+    /// Make sure every event `poll()` acts on also raises an interrupt, so a
+    /// main loop can `wfi()` between polls.
+    ///
+    /// Bus reset, suspend, resume and endpoint events raise `USB1` (enabled
+    /// by `enable()` already), a PHY wakeup from suspend through resume
+    /// signalling, D+/D- or VBUS changes raises `USB1_PHY`. Both have to be
+    /// unmasked in the NVIC, and `poll()` called after either fired.
+    pub fn enable_wakeup_interrupts(&self) {
+        interrupt::free(|cs| {
+            self.update_inten(cs);
+            phy::enable_wakeup_interrupt(&self.usb_regs.borrow(cs).phy);
+        });
+    }
+
+    /// Switch between servicing the bus from the `USB1` handler and polling
+    /// it from the main loop, e.g. to keep `USB1` quiet in a low-power state
+    /// that polls now and then. [`ServiceMode::Polled`] clears `INTEN`
+    /// (`USB1_PHY` isn't affected), [`ServiceMode::Interrupt`] restores it
+    /// with the masks of [`set_endpoint_interrupt`](Self::set_endpoint_interrupt).
+    ///
+    /// Also [`release_poller`](Self::release_poller)s, so the events go to
+    /// whichever context polls next.
+    pub fn set_service_mode(&self, mode: ServiceMode) {
+        interrupt::free(|cs| {
+            self.service_mode.borrow(cs).set(mode);
+            self.update_inten(cs);
+            self.poller.borrow(cs).set(None);
+        });
+    }
+
+    pub fn service_mode(&self) -> ServiceMode {
+        interrupt::free(|cs| self.service_mode.borrow(cs).get())
+    }
+
+    // Program INTEN for the service mode: the device interrupt, the
+    // endpoints that aren't masked, and SOF while a write waits for it and
+    // the bus isn't idle
+    fn update_inten(&self, cs: &CriticalSection) {
+        let usb = self.usb_regs.borrow(cs);
+        // not before `enable()` nor after `disable()`
+        if usb.dev.devcmdstat.read().dev_en().bit_is_clear() {
+            return;
+        }
+        if self.service_mode.borrow(cs).get() == ServiceMode::Polled {
+            usb.dev.inten.write(|w| unsafe { w.bits(0) });
+            return;
+        }
+        let ep_ints =
+            ALL_EP_INTS & !self.ep_int_masked.borrow(cs).get() & !self.nak_masked.borrow(cs).get();
+        let sof = !self.idle.borrow(cs).get()
+            && self.endpoints[1..=self.max_endpoint]
+                .iter()
+                .any(|ep| ep.is_in_deferred(cs));
+        usb.dev.inten.write(|w| {
+            unsafe { w.bits(ep_ints) }
+                .dev_int_en()
+                .set_bit()
+                .frame_int_en()
+                .bit(sof)
+        });
+    }
+
+    /// Wake the host up from suspend (remote wakeup).
+    ///
+    /// From L2 suspend, the controller drives resume (K) when `DSUS` is
+    /// cleared; this then waits `duration_ms`, clamped to the 1 to 15 ms the
+    /// spec allows, with `delay_us` and outside any critical section, so
+    /// nothing else touches the bus before the host takes over the resume.
+    /// From L1, resume is signalled for the fixed 50 us of the LPM ECN and
+    /// `duration_ms` is ignored.
+    ///
+    /// Nothing is driven if the bus has left suspend already, e.g. because
+    /// the host started resuming concurrently; either way `poll()` reports
+    /// the resume as usual. Only allowed if the host enabled remote wakeup,
+    /// which `usb-device` tracks: `InvalidState` if an L1 host didn't.
+    pub fn remote_wakeup(&self, duration_ms: u8, delay_us: impl FnMut(u32)) -> Result<()> {
+        let mut watchdog = None;
+        let signalled = interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            watchdog = usb.watchdog;
+            let devcmdstat = usb.dev.devcmdstat.read();
+
+            if devcmdstat.lpm_sus().bit_is_set() {
+                if !devcmdstat.lpm_rewp().bit_is_set() {
+                    return Err(UsbError::InvalidState);
+                }
+                devcmdstat::modify(&usb.dev, |w| w.lpm_sus().clear_bit());
+                self.l1_wake_requested.borrow(cs).set(true);
+                return Ok(false);
+            }
+            // the host got there first
+            if !devcmdstat.dsus().bit_is_set() || usb.phy.status.read().resume_status().bit_is_set()
+            {
+                return Ok(false);
+            }
+
+            if let Some(saved) = self.suspended_phy.borrow(cs).take() {
+                self.exit_low_power(cs, usb, saved);
+            }
+            devcmdstat::modify(&usb.dev, |w| w.dsus().clear_bit());
+            Ok(true)
+        })?;
+
+        if signalled {
+            let mut delay_us = usbhs::feeding(watchdog, delay_us);
+            delay_us(u32::from(duration_ms.clamp(1, 15)) * 1000);
+        }
+        Ok(())
+    }
+
+    /// Have `handler` called on the anomalies listed in [`UsbHsError`].
+    ///
+    /// It runs inside `poll()`'s critical section, so keep it short, e.g.
+    /// log or count the error and decide on recovery elsewhere.
+    pub fn on_error(&self, handler: ErrorHandler) {
+        interrupt::free(|cs| self.error_handler.borrow(cs).set(Some(handler)));
+    }
+
+    /// Re-arm a non-control IN endpoint from `poll()` as soon as the host
+    /// took the last packet, with whatever `refill` generates, e.g. to send
+    /// a HID report every polling interval regardless of how long the
+    /// class takes to get to it. `None` turns it off again.
+    ///
+    /// With `poll()` in the `USB1` handler and an
+    /// [`in_buffer_size`](crate::UsbConfig::in_buffer_size) of several
+    /// packets, this keeps bulk IN close to wire speed: each completion
+    /// interrupt pulls the next transfer from the application's queue in
+    /// place, without a copy, and the hardware sends it back to back.
+    ///
+    /// The first packet is still up to `write()`. While `refill` keeps the
+    /// endpoint armed, its completions aren't reported, so the class must
+    /// not write to it as well. `refill` runs inside `poll()`'s critical
+    /// section.
+    pub fn set_in_refill(&self, ep_addr: EndpointAddress, refill: Option<InRefill>) -> Result<()> {
+        let index = ep_addr.index();
+        if !ep_addr.is_in() || index == 0 || index >= NUM_ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        interrupt::free(|cs| {
+            let cell = self.in_refill.borrow(cs);
+            let mut in_refill = cell.get();
+            in_refill[index] = refill;
+            cell.set(in_refill);
+        });
+        Ok(())
+    }
+
+    // Arm the next packet from the endpoint's `InRefill`, if any
+    fn refill_in(&self, cs: &CriticalSection, ep: &Endpoint) -> bool {
+        let index = usize::from(ep.index());
+        let Some(refill) = self.in_refill.borrow(cs).get()[index] else {
+            return false;
+        };
+        let eps = self.ep_regs.borrow(cs);
+        let Ok(len) = ep.refill(cs, eps, refill) else {
+            return false;
+        };
+        if self.config.auto_zlp {
+            ep.queue_zlp(cs, len);
+        }
+        true
+    }
+
+    // Undo a low-power suspend, timing it and reporting a PLL that didn't lock
+    fn exit_low_power(&self, cs: &CriticalSection, usb: &UsbHS, saved: SuspendedPhy) {
+        #[cfg(feature = "metrics")]
+        let start = cortex_m::peripheral::DWT::cycle_count();
+        let locked = phy::exit_low_power(&usb.phy, saved, usb.watchdog, usb.core_hz);
+        #[cfg(feature = "metrics")]
+        self.resume_cycles.borrow(cs).set(Some(
+            cortex_m::peripheral::DWT::cycle_count().wrapping_sub(start),
+        ));
+        if !locked {
+            self.report(cs, UsbHsError::PllLockTimeout);
+        }
+    }
+
+    fn report(&self, cs: &CriticalSection, error: UsbHsError) {
+        if let Some(handler) = self.error_handler.borrow(cs).get() {
+            handler(error);
+        }
+        self.broadcast(cs, BusEvent::Error(error));
+    }
+
+    // EP0 is 64 bytes at high speed; smaller sizes only work if the link
+    // can never come up at high speed
+    fn check_control_max_packet_size(&self, max_packet_size: u16) -> Result<()> {
+        let full_speed_only = interrupt::free(|cs| {
+            self.usb_regs
+                .borrow(cs)
+                .dev
+                .devcmdstat
+                .read()
+                .force_fs()
+                .bit()
+        });
+        match max_packet_size {
+            64 => Ok(()),
+            8 | 16 | 32 if full_speed_only => Ok(()),
+            _ => Err(UsbError::InvalidState),
+        }
+    }
+
+    /// Load an IN packet now but only hand it to the hardware on the next
+    /// SOF, for a fixed phase to the (micro)frame, e.g. on isochronous or
+    /// timing-sensitive interrupt endpoints.
+    ///
+    /// Arming takes a `poll()`, which the SOF interrupt (enabled while a
+    /// packet waits) triggers, so poll from the `USB1` handler. It completes
+    /// like any other write; `WouldBlock` while the last one is deferred or
+    /// in flight.
+    pub fn write_at_next_sof(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let index = ep_addr.index();
+        if !ep_addr.is_in() || index == 0 || index > self.max_endpoint {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        interrupt::free(|cs| {
+            let eps = self.ep_regs.borrow(cs);
+            let written = self.endpoints[index].write_deferred(buf, cs, eps)?;
+            self.unmask_nak_wake(cs, index);
+            self.update_inten(cs);
+            Ok(written)
+        })
+    }
+
+    // Arm everything `write_at_next_sof` loaded, which leaves no reason for
+    // another SOF interrupt
+    fn arm_deferred(&self, cs: &CriticalSection) {
+        let eps = self.ep_regs.borrow(cs);
+        for ep in &self.endpoints[1..=self.max_endpoint] {
+            ep.arm_deferred(cs, eps);
+        }
+        let usb = self.usb_regs.borrow(cs);
+        usb.dev.inten.modify(|_, w| w.frame_int_en().clear_bit());
+    }
+
+    // Count an anomaly on an endpoint (or a good transfer, which starts the
+    // count over), taking it out of service once `error_limit` is reached
+    fn count_error(&self, cs: &CriticalSection, ep: &Endpoint, dir: UsbDirection, error: bool) {
+        let Some(limit) = self.config.error_limit else {
+            return;
+        };
+        let cell = self.ep_errors.borrow(cs);
+        let mut errors = cell.get();
+        let count = &mut errors[usize::from(ep.index())][usize::from(dir == UsbDirection::In)];
+        *count = match error {
+            true => count.saturating_add(1),
+            false => 0,
+        };
+        let tripped = *count == limit.errors.max(1);
+        cell.set(errors);
+        if !tripped {
+            return;
+        }
+
+        let usb = self.usb_regs.borrow(cs);
+        let eps = self.ep_regs.borrow(cs);
+        match limit.action {
+            ErrorLimitAction::Stall => {
+                // isochronous endpoints can't be stalled
+                if ep
+                    .set_stalled(cs, &usb.dev, eps, dir, true, usb.watchdog)
+                    .is_err()
+                {
+                    ep.disable(cs, &usb.dev, eps, dir, usb.watchdog);
+                }
+            }
+            ErrorLimitAction::Disable => ep.disable(cs, &usb.dev, eps, dir, usb.watchdog),
+        }
+        let ep_addr = EndpointAddress::from_parts(usize::from(ep.index()), dir);
+        self.report(cs, UsbHsError::ErrorLimitReached { ep_addr });
+    }
+
+    /// Have `listener` called on every [`BusEvent`] from now on, along with
+    /// the others, e.g. a power manager, a status LED and a logger. `false`
+    /// if [`MAX_LISTENERS`](crate::MAX_LISTENERS) are subscribed
+    /// already.
+    ///
+    /// Reset, suspend and resume are broadcast as `poll()` reports them,
+    /// whether or not `usb-device` acts on them.
+    pub fn subscribe(&self, listener: EventListener) -> bool {
+        interrupt::free(|cs| {
+            let cell = self.listeners.borrow(cs);
+            let mut listeners = cell.get();
+            let subscribed = listeners.subscribe(listener);
+            cell.set(listeners);
+            subscribed
+        })
+    }
+
+    pub fn unsubscribe(&self, listener: EventListener) {
+        interrupt::free(|cs| {
+            let cell = self.listeners.borrow(cs);
+            let mut listeners = cell.get();
+            listeners.unsubscribe(listener);
+            cell.set(listeners);
+        });
+    }
+
+    fn broadcast(&self, cs: &CriticalSection, event: BusEvent) {
+        self.listeners.borrow(cs).get().broadcast(event);
+    }
+
+    /// Let the next `poll()` from any execution context claim the events.
+    ///
+    /// Every poll runs in a critical section, but the events it returns are
+    /// consumed by whoever polled: with the main loop and the USB interrupt
+    /// both polling, each would see only part of them. So the first context
+    /// (thread mode or a particular exception) to poll owns the events, and
+    /// polls from anywhere else return nothing and report
+    /// [`UsbHsError::ForeignPoll`]. Call this to move polling, e.g. from the
+    /// main loop during bring-up to the interrupt handler.
+    pub fn release_poller(&self) {
+        interrupt::free(|cs| self.poller.borrow(cs).set(None));
+    }
+
+    /// Millisecond clock for the bus to time things with; it may wrap.
+    ///
+    /// With a clock, a suspend is only reported once `DSUS` stayed set for
+    /// another 3 ms of bus idle, which filters the spurious suspends some
+    /// hubs cause during enumeration. `poll()` then has to keep being called
+    /// while that is pending, e.g. by raising the USB interrupt from a
+    /// periodic timer with [`trigger_device_interrupt`](Self::trigger_device_interrupt),
+    /// see [`release_poller`](Self::release_poller).
+    pub fn set_clock(&self, now_ms: Clock) {
+        interrupt::free(|cs| self.clock.borrow(cs).set(Some(now_ms)));
+    }
+
+    fn now_ms(&self, cs: &CriticalSection) -> Option<u32> {
+        self.clock.borrow(cs).get().map(|now_ms| now_ms())
+    }
+
+    /// The last SETUP packets the host sent, oldest first.
+    #[cfg(feature = "setup-capture")]
+    pub fn captured_setups(&self) -> SetupCapture {
+        interrupt::free(|cs| *self.setup_capture.borrow(cs).borrow())
+    }
+
+    /// Re-arm latencies of a non-control IN endpoint since it was allocated
+    /// or last reset. Needs the DWT cycle counter to be enabled
+    /// (`DCB::enable_trace`, `DWT::enable_cycle_counter`).
+    #[cfg(feature = "metrics")]
+    pub fn rearm_latency(&self, ep_addr: EndpointAddress) -> Result<RearmLatency> {
+        match self.endpoints.get(ep_addr.index()) {
+            Some(ep) if ep_addr.is_in() && ep.index() != 0 => {
+                Ok(interrupt::free(|cs| ep.rearm_latency(cs)))
+            }
+            _ => Err(UsbError::InvalidEndpoint),
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn reset_rearm_latencies(&self) {
+        interrupt::free(|cs| {
+            for ep in self.endpoints.iter() {
+                ep.reset_rearm_latency(cs);
+            }
+        });
+    }
+
+    /// Packets and bytes moved by an endpoint since the bus was created, or
+    /// since [`reset_throughput`](Self::reset_throughput).
+    #[cfg(feature = "metrics")]
+    pub fn throughput(&self, ep_addr: EndpointAddress) -> Result<Throughput> {
+        let ep = self
+            .endpoints
+            .get(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+        Ok(interrupt::free(|cs| ep.throughput(cs, ep_addr.direction())))
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn reset_throughput(&self) {
+        interrupt::free(|cs| {
+            for ep in self.endpoints.iter() {
+                ep.reset_throughput(cs);
+            }
+        });
+    }
+
+    /// Bus resets since the bus was created, or since
+    /// [`clear_reset_history`](Self::clear_reset_history), timed with the
+    /// [`set_clock`](Self::set_clock) clock.
+    #[cfg(feature = "metrics")]
+    pub fn reset_history(&self) -> ResetHistory {
+        interrupt::free(|cs| self.reset_history.borrow(cs).get())
+    }
+
+    /// Forget the resets recorded so far, e.g. after reading them out, so
+    /// [`reset_history`](Self::reset_history) starts over.
+    #[cfg(feature = "metrics")]
+    pub fn clear_reset_history(&self) {
+        interrupt::free(|cs| self.reset_history.borrow(cs).set(ResetHistory::default()));
+    }
+
+    /// Core clock cycles the last return from a
+    /// [`SuspendMode::LowPower`] suspend took, PLL relock included; `None`
+    /// before the first. Needs the DWT cycle counter to be enabled.
+    #[cfg(feature = "metrics")]
+    pub fn resume_cycles(&self) -> Option<u32> {
+        interrupt::free(|cs| self.resume_cycles.borrow(cs).get())
+    }
+
+    /// The current frame number, e.g. for a [`FrameStopwatch`](crate::FrameStopwatch).
+    pub fn frame_number(&self) -> FrameNumber {
+        interrupt::free(|cs| {
+            FrameNumber(self.usb_regs.borrow(cs).dev.info.read().frame_nr().bits())
+        })
+    }
+
+    /// Speed negotiated at the last bus reset, `Unknown` until the host sent
+    /// the first SETUP after it.
+    ///
+    /// Full speed without [`force_full_speed`](crate::UsbHsBuilder::force_full_speed)
+    /// means the chirp failed, often a signal-integrity problem, or that the
+    /// host or a hub in between is full speed only; it is also reported as
+    /// [`UsbHsError::FullSpeedFallback`]. Firmware with full-speed
+    /// descriptors can switch to them then.
+    pub fn link_speed(&self) -> LinkSpeed {
+        interrupt::free(|cs| self.speed.borrow(cs).get())
+    }
+
+    /// Enumeration progress since the last bus reset.
+    pub fn enumeration(&self) -> EnumerationRecord {
+        interrupt::free(|cs| self.enumeration.borrow(cs).get())
+    }
+
+    /// Record that the device got configured, e.g. once `UsbDevice::state()`
+    /// reports it; `usb-device` doesn't tell the bus.
+    pub fn mark_configured(&self) {
+        interrupt::free(|cs| {
+            let enumeration = self.enumeration.borrow(cs);
+            let mut record = enumeration.get();
+            if record.state != EnumerationState::Configured {
+                record.state = EnumerationState::Configured;
+                record.configured_ms = self.now_ms(cs);
+                enumeration.set(record);
+                self.broadcast(cs, BusEvent::Configured);
+            }
+        });
+    }
+
+    // Follow VBUS for `vbus_detach`, `true` while detached
+    fn track_vbus(&self, cs: &CriticalSection, dev: &USB1, detach: VbusDetach) -> bool {
+        let vbus = dev.devcmdstat.read().vbus_debounced().bit_is_set();
+        let state = self.vbus.borrow(cs);
+        match (state.get(), vbus) {
+            (VbusState::Present, true) => false,
+            (VbusState::Present, false) => {
+                devcmdstat::modify(dev, |w| w.dcon().clear_bit());
+                state.set(VbusState::Lost);
+                self.broadcast(cs, BusEvent::Detached);
+                true
+            }
+            (_, false) => {
+                state.set(VbusState::Lost);
+                true
+            }
+            (VbusState::Lost, true) => {
+                state.set(VbusState::Returned {
+                    since: self.now_ms(cs),
+                });
+                self.track_vbus(cs, dev, detach)
+            }
+            (VbusState::Returned { since }, true) => {
+                let settled = match (since, self.now_ms(cs)) {
+                    (Some(since), Some(now)) => {
+                        now.wrapping_sub(since) >= detach.reconnect_debounce_ms
+                    }
+                    _ => true,
+                };
+                if settled {
+                    devcmdstat::modify(dev, |w| w.dcon().set_bit());
+                    state.set(VbusState::Present);
+                    self.broadcast(cs, BusEvent::Attached);
+                }
+                !settled
+            }
+        }
+    }
+
+    // Whether a raw `DSUS` has been debounced into a suspend
+    fn suspend_settled(&self, cs: &CriticalSection, dsus: bool) -> bool {
+        const SUSPEND_DEBOUNCE_MS: u32 = 3;
+
+        let since = self.suspend_since.borrow(cs);
+        if !dsus {
+            since.set(None);
+            return false;
+        }
+        let Some(now) = self.now_ms(cs) else {
+            return true;
+        };
+        let start = since.get().unwrap_or(now);
+        since.set(Some(start));
+        now.wrapping_sub(start) >= SUSPEND_DEBOUNCE_MS
+    }
+
+    /// Bus resets handled so far, wrapping.
+    pub(crate) fn reset_count(&self) -> u32 {
+        interrupt::free(|cs| self.resets.borrow(cs).get())
+    }
+
+    /// Expect a transfer of `len` bytes the next time the OUT endpoint is
+    /// armed, and on every re-arm after that; 0 restores the default of
+    /// filling the whole buffer.
+    ///
+    /// The hardware then only reports the endpoint once `len` bytes or a
+    /// short packet arrived, instead of after every packet. `len` must fit
+    /// into the buffer, see [`new_with_out_buffer_size`](Self::new_with_out_buffer_size).
+    pub fn prime_out(&self, ep_addr: EndpointAddress, len: usize) -> Result<()> {
+        if !ep_addr.is_out() || ep_addr.index() == 0 || ep_addr.index() >= NUM_ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        interrupt::free(|cs| self.endpoints[ep_addr.index()].set_out_transfer_len(cs, len))
+    }
+
+    /// Stop a non-control endpoint's completions from raising `USB1`, e.g.
+    /// an interrupt IN that is idle most of the time, or let them again.
+    ///
+    /// `poll()` still services the endpoint, whenever something else makes
+    /// it run. The setting survives bus resets and
+    /// [`enable_wakeup_interrupts`](Self::enable_wakeup_interrupts).
+    pub fn set_endpoint_interrupt(&self, ep_addr: EndpointAddress, enabled: bool) -> Result<()> {
+        let index = ep_addr.index();
+        if index == 0 || index >= NUM_ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let dir = ep_addr.direction();
+        interrupt::free(|cs| {
+            let bit = endpoint_registers::physical_endpoint_bit(index, dir);
+            let masked = self.ep_int_masked.borrow(cs);
+            masked.set(match enabled {
+                true => masked.get() & !bit,
+                false => masked.get() | bit,
+            });
+            if self.service_mode.borrow(cs).get() == ServiceMode::Interrupt {
+                let usb = self.usb_regs.borrow(cs);
+                self.endpoints[index].set_interrupt_enabled(&usb.dev, dir, enabled);
+            }
+        });
+        Ok(())
+    }
+
+    /// Length of the packet waiting on an OUT endpoint (8 for a SETUP on
+    /// EP0), leaving it for a later `read()`, e.g. to decide whether to
+    /// process it now or come back when there is room for it. `WouldBlock`
+    /// if there is none.
+    pub fn peek_len(&self, ep_addr: EndpointAddress) -> Result<usize> {
+        if !ep_addr.is_out() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let ep = self
+            .endpoints
+            .get(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            ep.peek(cs, &usb.dev, self.ep_regs.borrow(cs))
+                .map(|packet| packet.len)
+        })
+    }
+
+    /// Like [`UsbBus::read`], but also tells whether the packet was short,
+    /// i.e. ended the transfer. If the packet doesn't fit into `buf`, nothing
+    /// is consumed and `BufferOverflow` is returned.
+    pub fn read_packet(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<OutPacket> {
+        interrupt::free(|cs| self.read_packet_in(cs, ep_addr, buf))
+    }
+
+    /// `write()` a packet, retrying while the endpoint is busy, for up to
+    /// `timeout_ms` on the [`set_clock`](Self::set_clock) clock.
+    ///
+    /// Busy-waits with interrupts enabled between tries, so not from the
+    /// interrupt that polls the bus. `InvalidState` without a clock,
+    /// `WouldBlock` if the host didn't take the previous packet in time.
+    pub fn write_blocking(
+        &self,
+        ep_addr: EndpointAddress,
+        buf: &[u8],
+        timeout_ms: u32,
+    ) -> Result<usize> {
+        self.retry_until(timeout_ms, || {
+            interrupt::free(|cs| self.write_in(cs, ep_addr, buf))
+        })
+    }
+
+    /// Like [`write_blocking`](Self::write_blocking), waiting up to
+    /// `timeout_ms` for a packet to `read()`.
+    pub fn read_blocking(
+        &self,
+        ep_addr: EndpointAddress,
+        buf: &mut [u8],
+        timeout_ms: u32,
+    ) -> Result<usize> {
+        self.retry_until(timeout_ms, || {
+            interrupt::free(|cs| self.read_packet_in(cs, ep_addr, buf)).map(|packet| packet.len)
+        })
+    }
+
+    fn retry_until<R>(&self, timeout_ms: u32, mut f: impl FnMut() -> Result<R>) -> Result<R> {
+        let now_ms =
+            interrupt::free(|cs| self.clock.borrow(cs).get()).ok_or(UsbError::InvalidState)?;
+        let start = now_ms();
+        loop {
+            match f() {
+                Err(UsbError::WouldBlock) if now_ms().wrapping_sub(start) < timeout_ms => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Run `f` in a single critical section, for code that services many
+    /// endpoints per interrupt and would otherwise enter and leave one for
+    /// every `poll()`, `read()` and `write()`.
+    ///
+    /// Keep `f` short, interrupts stay masked until it returns.
+    pub fn with_bus<R>(&self, f: impl FnOnce(&mut BusTransaction<'_>) -> R) -> R {
+        interrupt::free(|cs| f(&mut BusTransaction { bus: self, cs }))
+    }
+
+    /// `poll()` without building a `PollResult`, for custom stacks and tight
+    /// interrupt handlers. Same events, same side effects.
+    pub fn poll_fast(&self) -> PollEvents {
+        interrupt::free(|cs| self.poll_in(cs))
+    }
+
+    pub(crate) fn read_packet_in(
+        &self,
+        cs: &CriticalSection,
+        ep_addr: EndpointAddress,
+        buf: &mut [u8],
+    ) -> Result<OutPacket> {
+        if !ep_addr.is_out() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let usb = self.usb_regs.borrow(cs);
+        let eps = self.ep_regs.borrow(cs);
+        let ep = self
+            .endpoints
+            .get(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+        ep.read(buf, cs, &usb.dev, eps)
+    }
+
+    pub(crate) fn write_in(
+        &self,
+        cs: &CriticalSection,
+        ep_addr: EndpointAddress,
+        buf: &[u8],
+    ) -> Result<usize> {
+        if !ep_addr.is_in() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+
+        let eps = self.ep_regs.borrow(cs);
+        let ep = self
+            .endpoints
+            .get(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+        let len = ep.write(buf, cs, eps)?;
+        if self.config.auto_zlp && ep_addr.index() != 0 {
+            ep.queue_zlp(cs, len);
+        }
+        if self.nak_masked.borrow(cs).get() != 0 {
+            self.unmask_nak_wake(cs, ep_addr.index());
+            self.update_inten(cs);
+        }
+        Ok(len)
+    }
+
+    pub(crate) fn stall_control(&self, cs: &CriticalSection) {
+        let usb = self.usb_regs.borrow(cs);
+        let eps = self.ep_regs.borrow(cs);
+        for dir in [UsbDirection::Out, UsbDirection::In] {
+            self.endpoints[0]
+                .set_stalled(cs, &usb.dev, eps, dir, true, usb.watchdog)
+                .ok();
+        }
+    }
+
+    pub(crate) fn ack_setup_in(&self, cs: &CriticalSection) {
+        let usb = self.usb_regs.borrow(cs);
+        let eps = self.ep_regs.borrow(cs);
+        self.endpoints[0].abort_control(cs, &usb.dev, eps);
+    }
+
+    /// Take the control transfers whose SETUP packet `bypass.matches` away
+    /// from `usb-device`, see [`ControlBypass`]. `None` hands all of them
+    /// back from the next SETUP on.
+    pub fn set_control_bypass(&self, bypass: Option<ControlBypass>) {
+        interrupt::free(|cs| self.control_bypass.borrow(cs).set(bypass));
+    }
+
+    // Hand the EP0 events of a bypassed control transfer to its handler,
+    // returning the ones that are left for usb-device.
+    fn bypass_control(
+        &self,
+        cs: &CriticalSection,
+        in_complete: bool,
+        setup: bool,
+        out: bool,
+    ) -> (bool, bool, bool) {
+        let active = self.bypass_active.borrow(cs);
+        let test_mode = self.config.test_mode_requests.then_some(TEST_MODE_BYPASS);
+        let user = self.control_bypass.borrow(cs).get();
+        if test_mode.is_none() && user.is_none() {
+            active.set(None);
+            return (in_complete, setup, out);
+        }
+        let ep0 = &self.endpoints[0];
+        let mut raw = RawControl { bus: self, cs };
+
+        // completes the stage before any new SETUP
+        let in_complete = match active.get() {
+            Some(bypass) if in_complete => {
+                (bypass.handler)(ControlEvent::InComplete, &mut raw);
+                false
+            }
+            _ => in_complete,
+        };
+        if setup {
+            let bypass = ep0.latched_setup(cs).and_then(|packet| {
+                [test_mode, user]
+                    .into_iter()
+                    .flatten()
+                    .find(|bypass| (bypass.matches)(&packet))
+            });
+            active.set(bypass);
+            if let Some(bypass) = bypass {
+                if let Some(packet) = ep0.take_setup(cs) {
+                    (bypass.handler)(ControlEvent::Setup(packet), &mut raw);
+                }
+                return (in_complete, false, false);
+            }
+        }
+        match active.get() {
+            Some(bypass) if out => {
+                (bypass.handler)(ControlEvent::Out, &mut raw);
+                (in_complete, setup, false)
+            }
+            _ => (in_complete, setup, out),
+        }
+    }
+
+    /// Put the PHY into a USB 2.0 test mode, for compliance testing. Only a
+    /// power cycle ends it, as the spec says.
+    ///
+    /// The host asks for one with `SET_FEATURE(TEST_MODE)`, which usb-device
+    /// refuses; see [`UsbConfig::test_mode_requests`] for answering it.
+    pub fn enter_test_mode(&self, mode: TestMode) {
+        interrupt::free(|cs| self.enter_test_mode_in(cs, mode));
+    }
+
+    pub(crate) fn enter_test_mode_in(&self, cs: &CriticalSection, mode: TestMode) {
+        let usb = self.usb_regs.borrow(cs);
+        devcmdstat::modify(&usb.dev, |w| unsafe { w.phy_test_mode().bits(mode as u8) });
+    }
+
+    fn poll_in(&self, cs: &CriticalSection) -> PollEvents {
+        let context = SCB::vect_active();
+        let poller = self.poller.borrow(cs);
+        match poller.get() {
+            Some(owner) if owner != context => {
+                self.report(cs, UsbHsError::ForeignPoll);
+                return PollEvents::default();
+            }
+            _ => poller.set(Some(context)),
+        }
+
+        let usb = self.usb_regs.borrow(cs);
+        let eps = self.ep_regs.borrow(cs);
+
+        let devcmdstat = &usb.dev.devcmdstat;
+        let intstat = &usb.dev.intstat;
+
+        // Low-power suspend: nothing to see until the PHY wakes up
+        if let Some(saved) = self.suspended_phy.borrow(cs).take() {
+            if !phy::wakeup_pending(&usb.phy) {
+                self.suspended_phy.borrow(cs).set(Some(saved));
+                return PollEvents::default();
+            }
+            self.exit_low_power(cs, usb, saved);
+        }
+        phy::clear_wakeup(&usb.phy);
+
+        // Acknowledge before looking at the flags, so that any change
+        // from here on raises the interrupt again
+        intstat.write(|w| w.dev_int().set_bit());
+
+        // first thing, so the phase to the SOF doesn't depend on the rest
+        if intstat.read().frame_int().bit_is_set() {
+            intstat.write(|w| w.frame_int().set_bit());
+            self.arm_deferred(cs);
+        }
+
+        if let Some(detach) = self.config.vbus_detach {
+            if self.track_vbus(cs, &usb.dev, detach) {
+                return PollEvents::default();
+            }
+        }
+
+        let link_suspended = self.link_suspended.borrow(cs);
+
+        // Bus reset flag?
+        if self.reset_latched.borrow(cs).replace(false) || devcmdstat.read().dres_c().bit_is_set() {
+            devcmdstat::modify(&usb.dev, |w| w.dres_c().set_bit());
+            link_suspended.set(false);
+            self.broadcast(cs, BusEvent::Reset);
+            return PollEvents::flag(PollEvents::RESET);
+        }
+
+        // Suspend, including L1, and resume
+        let devcmdstat_r = devcmdstat.read();
+        if devcmdstat_r.dsus_c().bit_is_set() {
+            devcmdstat::modify(&usb.dev, |w| w.dsus_c().set_bit());
+        }
+        let suspended = devcmdstat_r.lpm_sus().bit_is_set()
+            || self.suspend_settled(cs, devcmdstat_r.dsus().bit_is_set());
+        if link_suspended.replace(suspended) != suspended {
+            let in_l1 = self.in_l1.borrow(cs);
+            let (event, flag) = if suspended {
+                let request = LpmRequest::read(&usb.dev);
+                in_l1.set(request.is_some());
+                let event = request.map_or(BusEvent::Suspend, BusEvent::L1Entered);
+                (event, PollEvents::SUSPEND)
+            } else if in_l1.replace(false) {
+                let exit = match self.l1_wake_requested.borrow(cs).replace(false) {
+                    true => L1Exit::Device,
+                    false => L1Exit::Host,
+                };
+                self.last_l1_exit.borrow(cs).set(Some(exit));
+                (BusEvent::L1Exited(exit), PollEvents::RESUME)
+            } else {
+                (BusEvent::Resume, PollEvents::RESUME)
+            };
+            self.broadcast(cs, event);
+            return PollEvents::flag(flag);
+        }
+        if suspended {
+            return PollEvents::default();
+        }
+
+        let mut ep_out = 0;
+        let mut ep_in_complete = 0;
+        let mut ep_setup = 0;
+
+        let mut bit = 1;
+
+        // NB: these are not "reader objects", but the actual value
+        // of the registers at time of assignment :))
+        let intstat_r = intstat.read();
+
+        let devcmdstat_r = devcmdstat.read();
+        let nak_ci = devcmdstat_r.intonnak_ci().bit_is_set();
+        let nak_co = devcmdstat_r.intonnak_co().bit_is_set();
+        let nak_ai = devcmdstat_r.intonnak_ai().bit_is_set();
+        let nak_ao = devcmdstat_r.intonnak_ao().bit_is_set();
+        if self.error_handler.borrow(cs).get().is_some()
+            || !self.listeners.borrow(cs).get().is_empty()
+        {
+            let err_code = usb.dev.info.read().err_code().bits();
+            if self.err_code.borrow(cs).replace(err_code) != err_code && err_code != 0 {
+                self.report(cs, UsbHsError::TransactionError { err_code });
+            }
+        }
+
+        let ack_on_report = self.config.interrupt_ack == InterruptAck::OnReport;
+        let ep0 = &self.endpoints[0];
+        let mut naks = self.control_naks.borrow(cs).get();
+
+        // First handle endpoint 0 (the only control endpoint). IN goes
+        // first so a status stage completing just before a new SETUP is
+        // reported along with it, in the order usb-device expects.
+        if intstat_r.ep0in().bit_is_set() {
+            intstat.write(|w| w.ep0in().set_bit());
+            if ep0.take_in_completion(cs) || !nak_ci {
+                ep_in_complete |= bit;
+
+                // EP0 needs manual toggling of Active bits
+                // Weeelll interesting, not changing this makes no difference
+                ep0.regs(eps).ep_in[0].modify(|_, w| w.a().not_active());
+            } else {
+                naks.in_naks = naks.in_naks.wrapping_add(1);
+            }
+        }
+
+        // A SETUP is latched as soon as it is seen, and supersedes any
+        // data stage packet that is still sitting in the OUT buffer.
+        let new_setup = ep0.latch_setup(cs, &usb.dev, eps);
+        #[cfg(feature = "setup-capture")]
+        if let (true, Some(setup)) = (new_setup, ep0.latched_setup(cs)) {
+            self.setup_capture.borrow(cs).borrow_mut().push(setup);
+        }
+        // the reset and with it the chirp are over by the first SETUP
+        if new_setup && self.speed.borrow(cs).get() == LinkSpeed::Unknown {
+            let speed = LinkSpeed::read(&usb.dev);
+            self.speed.borrow(cs).set(speed);
+            if speed == LinkSpeed::Full && devcmdstat.read().force_fs().bit_is_clear() {
+                self.report(cs, UsbHsError::FullSpeedFallback);
+            }
+        }
+        if new_setup || ep0.is_setup_latched(cs) {
+            ep_setup |= bit;
+        } else if intstat_r.ep0out().bit_is_set() {
+            if ack_on_report {
+                intstat.write(|w| w.ep0out().set_bit());
+            }
+            // NAK while the last packet is still unread: report it
+            // again, read() will pick up the data either way
+            if ep0.take_out_completion(cs, eps) {
+                ep0.stamp_out(cs, &usb.dev);
+            } else if nak_co {
+                naks.out_naks = naks.out_naks.wrapping_add(1);
+            }
+            ep_out |= bit;
+        } else if ack_on_report && ep0.is_out_pending(cs, eps) {
+            ep_out |= bit;
+        }
+        self.control_naks.borrow(cs).set(naks);
+
+        let (in_complete, setup, out) =
+            self.bypass_control(cs, ep_in_complete != 0, ep_setup != 0, ep_out != 0);
+        ep_in_complete = u16::from(in_complete);
+        ep_setup = u16::from(setup);
+        ep_out = u16::from(out);
+
+        // non-CONTROL
+        for index in self.service_order.borrow(cs).get() {
+            let i = usize::from(index);
+            if i > self.max_endpoint {
+                continue;
+            }
+            let ep = &self.endpoints[i];
+            let regs = ep.regs(eps);
+            bit = 1 << i;
+
+            // OUT = READ
+            let out_offset = 2 * i;
+            let out_int = ((intstat_r.bits() >> out_offset) & 0x1) != 0;
+            let out_inactive = regs.ep_out[0].read().a().is_not_active();
+
+            // with INTONNAK_AO, an interrupt while still armed is a NAK
+            let out_nak = out_int && !out_inactive && nak_ao;
+            if out_nak {
+                usb.dev
+                    .intstat
+                    .write(|w| unsafe { w.bits(1u32 << out_offset) });
+            }
+
+            // a packet stays reported until read() consumes it
+            if (out_int && !out_nak) || ep.is_out_pending(cs, eps) {
+                if !out_inactive {
+                    self.report(cs, UsbHsError::OutInterruptWhileActive { index: i as u8 });
+                }
+                if out_int {
+                    self.count_error(cs, ep, UsbDirection::Out, !out_inactive);
+                }
+                if out_inactive {
+                    ep.stamp_out(cs, &usb.dev);
+                }
+                ep_out |= bit;
+                if out_int && ack_on_report {
+                    usb.dev
+                        .intstat
+                        .write(|w| unsafe { w.bits(1u32 << out_offset) });
+                }
+            }
+
+            // IN = WRITE
+            let in_offset = 2 * i + 1;
+            let in_int = ((intstat_r.bits() >> in_offset) & 0x1) != 0;
+            let in_inactive = regs.ep_in[0].read().a().is_not_active();
+            if in_int && !in_inactive {
+                // likewise with INTONNAK_AI
+                if nak_ai {
+                    usb.dev
+                        .intstat
+                        .write(|w| unsafe { w.bits(1u32 << in_offset) });
+                } else {
+                    self.report(cs, UsbHsError::InInterruptWhileActive { index: i as u8 });
+                    self.count_error(cs, ep, UsbDirection::In, true);
+                }
+            }
+            // nothing was armed, so the host got a NAK
+            let nak = in_int && in_inactive && nak_ai && !ep.is_in_armed(cs);
+            if nak {
+                usb.dev
+                    .intstat
+                    .write(|w| unsafe { w.bits(1u32 << in_offset) });
+                let wakes = self.nak_wakes.borrow(cs);
+                wakes.set(wakes.get() | 1 << i);
+                // one wake per endpoint is all `idle_nak_wake` is for, the
+                // host keeps NAK-polling a pending IN every microframe
+                if self.idle.borrow(cs).get() && !self.nak_in.borrow(cs).get() {
+                    let masked = self.nak_masked.borrow(cs);
+                    masked.set(masked.get() | 1u32 << in_offset);
+                    ep.set_interrupt_enabled(&usb.dev, UsbDirection::In, false);
+                }
+            }
+            if in_int && in_inactive && !nak {
+                // clear it
+                usb.dev
+                    .intstat
+                    .write(|w| unsafe { w.bits(1u32 << in_offset) });
+                debug_assert!(regs.ep_in[0].read().a().is_not_active());
+                ep.count_in_completion(cs);
+
+                // with auto-ZLP, a full packet completes with its ZLP
+                if !ep.take_zlp_pending(cs) {
+                    self.count_error(cs, ep, UsbDirection::In, false);
+                    if !self.refill_in(cs, ep) {
+                        ep.latch_in_complete(cs);
+                    }
+                } else if ep.write(&[], cs, eps).is_err() {
+                    self.report(cs, UsbHsError::AutoZlpFailed { index: i as u8 });
+                    self.count_error(cs, ep, UsbDirection::In, true);
+                    ep.latch_in_complete(cs);
+                }
+            };
+            // and a completion until the next write() re-arms
+            if ep.is_in_complete_latched(cs) {
+                ep_in_complete |= bit;
+            }
+        }
+
+        if self.config.idle_nak_wake {
+            self.update_idle(cs);
+        }
+
+        PollEvents {
+            ep_out,
+            ep_in_complete,
+            ep_setup,
+            flags: 0,
+        }
+    }
+
+    /// [`UsbBus::set_stalled`], with an error where that has to ignore
+    /// one: `InvalidEndpoint` for an endpoint number out of range or an
+    /// isochronous endpoint, which can't be stalled.
+    pub fn set_endpoint_stalled(&self, ep_addr: EndpointAddress, stalled: bool) -> Result<()> {
+        let ep = self
+            .endpoints
+            .get(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+        interrupt::free(|cs| {
+            let eps = self.ep_regs.borrow(cs);
+            if ep.is_stalled(cs, eps, ep_addr.direction()) == stalled {
+                return Ok(());
+            }
+
+            let usb = self.usb_regs.borrow(cs);
+            let dir = ep_addr.direction();
+            ep.set_stalled(cs, &usb.dev, eps, dir, stalled, usb.watchdog)
+        })
+    }
+
+    /// Stop one direction of a non-control endpoint, e.g. an isochronous
+    /// stream when `SET_INTERFACE` selects the zero-bandwidth alternate
+    /// setting. An armed buffer is taken back, an unread OUT packet or an
+    /// unreported IN completion dropped. The buffer stays allocated for
+    /// [`enable_endpoint`](Self::enable_endpoint).
+    pub fn disable_endpoint(&self, ep_addr: EndpointAddress) -> Result<()> {
+        let ep = self.allocated_endpoint(ep_addr)?;
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            let eps = self.ep_regs.borrow(cs);
+            ep.disable(cs, &usb.dev, eps, ep_addr.direction(), usb.watchdog);
+        });
+        Ok(())
+    }
+
+    /// Start one direction of a non-control endpoint again after
+    /// [`disable_endpoint`](Self::disable_endpoint), with empty buffers and
+    /// the data toggle at DATA0, as `SET_INTERFACE` requires. OUT is armed
+    /// right away, IN waits for the next `write()`.
+    pub fn enable_endpoint(&self, ep_addr: EndpointAddress) -> Result<()> {
+        let ep = self.allocated_endpoint(ep_addr)?;
+        interrupt::free(|cs| {
+            let dir = ep_addr.direction();
+            let errors = self.ep_errors.borrow(cs);
+            let mut counts = errors.get();
+            counts[ep_addr.index()][usize::from(dir == UsbDirection::In)] = 0;
+            errors.set(counts);
+            ep.enable(cs, self.ep_regs.borrow(cs), dir);
+        });
+        Ok(())
+    }
 
-impl UsbHSBus {
-    pub fn new(usb_device: UsbHS) -> UsbBusAllocator<UsbHSBus> {
-        let bus = UsbHSBus {
-            usb_regs: Mutex::new(usb_device),
-            ep_regs: Mutex::new(endpoint_registers::attach().unwrap()),
-            ep_allocator: EndpointMemoryAllocator::new(),
-            max_endpoint: 0,
-            endpoints: {
-                let mut endpoints: [core::mem::MaybeUninit<Endpoint>; NUM_ENDPOINTS] =
-                    unsafe { core::mem::MaybeUninit::uninit().assume_init() };
+    /// Arm the OUT endpoints held back by
+    /// [`UsbConfig::hold_endpoints`], once the application is ready for
+    /// data. Endpoints that are stalled or disabled at the time are armed
+    /// when unstalled or enabled again.
+    pub fn open_endpoints(&self) {
+        interrupt::free(|cs| {
+            let eps = self.ep_regs.borrow(cs);
+            for ep in &self.endpoints[1..] {
+                ep.open_out(cs, eps);
+            }
+        });
+    }
 
-                for (i, endpoint) in endpoints.iter_mut().enumerate() {
-                    *endpoint = core::mem::MaybeUninit::new(Endpoint::new(i as u8));
-                }
+    // A direction of a non-control endpoint that has a buffer
+    fn allocated_endpoint(&self, ep_addr: EndpointAddress) -> Result<&Endpoint> {
+        let ep = match self.endpoints.get(ep_addr.index()) {
+            Some(ep) if ep.index() != 0 => ep,
+            _ => return Err(UsbError::InvalidEndpoint),
+        };
+        let allocated = match ep_addr.direction() {
+            UsbDirection::Out => ep.is_out_buf_set(),
+            UsbDirection::In => ep.is_in_buf_set(),
+        };
+        match allocated {
+            true => Ok(ep),
+            false => Err(UsbError::InvalidEndpoint),
+        }
+    }
 
-                unsafe { core::mem::transmute::<_, _>(endpoints) }
-            },
+    /// Reset the data toggle of a non-control endpoint to DATA0, e.g. for a
+    /// class-specific reset such as the MSC Bulk-Only Mass Storage Reset, or
+    /// to resume transfers after error recovery.
+    ///
+    /// The hardware can only reset the toggle of these endpoints to zero,
+    /// see [`data_toggle`](Self::data_toggle) for reading it. `WouldBlock`
+    /// while a buffer is handed to the hardware.
+    pub fn reset_data_toggle(&self, ep_addr: EndpointAddress) -> Result<()> {
+        let ep = match self.endpoints.get(ep_addr.index()) {
+            Some(ep) if ep.index() != 0 => ep,
+            _ => return Err(UsbError::InvalidEndpoint),
+        };
+        interrupt::free(|cs| {
+            let regs = ep.regs(self.ep_regs.borrow(cs));
+            let reg = match ep_addr.direction() {
+                UsbDirection::Out => &regs.ep_out[0],
+                UsbDirection::In => &regs.ep_in[0],
+            };
+            if reg.read().a().is_active() {
+                return Err(UsbError::WouldBlock);
+            }
+            // TV has to be 0 outside of EP0 (and for interrupt endpoints
+            // selects toggle mode rather than rate feedback)
+            reg.modify(|_, w| w.rftv().clear_bit().tr().set_bit());
+            Ok(())
+        })
+    }
+
+    /// Current data toggle of a non-control endpoint, `true` for DATA1.
+    pub fn data_toggle(&self, ep_addr: EndpointAddress) -> Result<bool> {
+        let index = ep_addr.index();
+        if index == 0 || index >= NUM_ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            Ok(endpoint_registers::data_toggle(
+                &usb.dev,
+                index,
+                ep_addr.direction(),
+            ))
+        })
+    }
+
+    /// The `bInterval` an allocated endpoint was requested with, `None` for
+    /// endpoints that weren't allocated.
+    ///
+    /// The bus doesn't schedule by it, the value is for layers above that
+    /// reason about how often an endpoint is serviced.
+    pub fn endpoint_interval(&self, ep_addr: EndpointAddress) -> Option<u8> {
+        let ep = self.endpoints.get(ep_addr.index())?;
+        let allocated = match ep_addr.direction() {
+            UsbDirection::Out => ep.is_out_buf_set(),
+            UsbDirection::In => ep.is_in_buf_set(),
         };
+        allocated.then(|| ep.interval(ep_addr.direction()))
+    }
+
+    /// The SETUP packet `poll()` reported on EP0, apart from the data stage
+    /// packets that [`read`](UsbBus::read) multiplexes it with; `WouldBlock`
+    /// if there is none. The packet is consumed, so `read()` won't return it
+    /// again.
+    pub fn read_setup(&self, ep_addr: EndpointAddress) -> Result<[u8; 8]> {
+        if !ep_addr.is_out() || ep_addr.index() != 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        interrupt::free(|cs| self.endpoints[0].take_setup(cs).ok_or(UsbError::WouldBlock))
+    }
+
+    /// See [`UsbHS::phy_power_down`].
+    pub fn phy_power_down(&self) -> PhyPowerDown {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).phy_power_down())
+    }
+
+    /// See [`UsbHS::set_phy_power_down`].
+    pub fn set_phy_power_down(&self, power_down: PhyPowerDown) {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).set_phy_power_down(power_down));
+    }
+
+    /// See [`UsbHS::phy_clock_gating`].
+    pub fn phy_clock_gating(&self) -> PhyClockGating {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).phy_clock_gating())
+    }
+
+    /// See [`UsbHS::set_phy_clock_gating`].
+    pub fn set_phy_clock_gating(&self, gating: PhyClockGating) {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).set_phy_clock_gating(gating));
+    }
+
+    /// What to do with the PHY on the next bus suspend, see [`SuspendMode`].
+    pub fn set_suspend_mode(&self, mode: SuspendMode) {
+        interrupt::free(|cs| self.suspend_mode.borrow(cs).set(mode));
+    }
+
+    pub fn suspend_mode(&self) -> SuspendMode {
+        interrupt::free(|cs| self.suspend_mode.borrow(cs).get())
+    }
+
+    /// The host's LPM attributes while the link is in L1, `None` otherwise.
+    ///
+    /// Useful after `poll()` reported a suspend, to pick a sleep depth that
+    /// can still be left within [`LpmRequest::besl_us`].
+    pub fn lpm_request(&self) -> Option<LpmRequest> {
+        interrupt::free(|cs| LpmRequest::read(&self.usb_regs.borrow(cs).dev))
+    }
+
+    /// Who ended the most recent L1 sleep, `None` if the link never left one.
+    pub fn last_l1_exit(&self) -> Option<L1Exit> {
+        interrupt::free(|cs| self.last_l1_exit.borrow(cs).get())
+    }
+
+    /// Change how LPM tokens are answered from now on.
+    pub fn set_lpm_policy(&self, policy: LpmPolicy) {
+        interrupt::free(|cs| policy.write(&self.usb_regs.borrow(cs).dev));
+    }
+
+    pub fn lpm_policy(&self) -> LpmPolicy {
+        interrupt::free(|cs| LpmPolicy::read(&self.usb_regs.borrow(cs).dev))
+    }
+
+    /// Raise EP0 interrupts on NAKed IN/OUT tokens (`INTONNAK_CI`/`INTONNAK_CO`)
+    /// and count them in [`control_nak_counts`](Self::control_nak_counts).
+    ///
+    /// NAK interrupts are not reported to `usb-device` as completions.
+    pub fn set_control_nak_interrupts(&self, on_in: bool, on_out: bool) {
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            devcmdstat::modify(&usb.dev, |w| {
+                w.intonnak_ci().bit(on_in).intonnak_co().bit(on_out)
+            });
+        });
+    }
+
+    /// Raise interrupts on NAKed IN/OUT tokens of the non-control endpoints
+    /// (`INTONNAK_AI`/`INTONNAK_AO`), e.g. to learn that the host is polling
+    /// an IN endpoint nothing was written to yet. `poll()` acknowledges them
+    /// without reporting anything to `usb-device`, see
+    /// [`take_nak_wakes`](Self::take_nak_wakes).
+    pub fn set_nak_interrupts(&self, on_in: bool, on_out: bool) {
+        interrupt::free(|cs| {
+            self.nak_in.borrow(cs).set(on_in);
+            if on_in {
+                self.nak_masked.borrow(cs).set(0);
+                self.update_inten(cs);
+            }
+            let on_in = on_in || self.idle.borrow(cs).get();
+            let usb = self.usb_regs.borrow(cs);
+            devcmdstat::modify(&usb.dev, |w| {
+                w.intonnak_ai().bit(on_in).intonnak_ao().bit(on_out)
+            });
+        });
+    }
+
+    /// The non-control IN endpoints (bit n for endpoint n) the host polled
+    /// while nothing was armed on them since the last call, with NAK
+    /// interrupts on, e.g. to learn which one to write to after waking up
+    /// from [`idle_nak_wake`](crate::UsbConfig::idle_nak_wake).
+    pub fn take_nak_wakes(&self) -> u16 {
+        interrupt::free(|cs| self.nak_wakes.borrow(cs).replace(0))
+    }
+
+    // Keep `INTONNAK_AI` on while nothing is in flight, see
+    // `UsbConfig::idle_nak_wake`
+    fn update_idle(&self, cs: &CriticalSection) {
+        let eps = self.ep_regs.borrow(cs);
+        let ep0 = &self.endpoints[0];
+        let busy = ep0.is_in_pending(cs)
+            || ep0.is_setup_latched(cs)
+            || self.endpoints[1..=self.max_endpoint]
+                .iter()
+                .any(|ep| ep.is_in_pending(cs) || ep.is_out_pending(cs, eps));
+        let idle = !busy;
+        if self.idle.borrow(cs).replace(idle) != idle {
+            let nak_in = idle || self.nak_in.borrow(cs).get();
+            let usb = self.usb_regs.borrow(cs);
+            devcmdstat::modify(&usb.dev, |w| w.intonnak_ai().bit(nak_in));
+            if !idle {
+                self.nak_masked.borrow(cs).set(0);
+            }
+            self.update_inten(cs);
+        }
+    }
+
+    // Give an IN endpoint its interrupt back once something is written to
+    // it; `update_inten` still has to apply it
+    fn unmask_nak_wake(&self, cs: &CriticalSection, index: usize) {
+        let bit = endpoint_registers::physical_endpoint_bit(index, UsbDirection::In);
+        let masked = self.nak_masked.borrow(cs);
+        masked.set(masked.get() & !bit);
+    }
+
+    /// Service the non-control endpoints in `poll()` in this order, e.g.
+    /// interrupt endpoints before bulk ones, so their interrupts are
+    /// acknowledged and their auto-ZLPs armed first. Endpoints left out
+    /// follow in index order; EP0 always comes first.
+    ///
+    /// `usb-device` hands the results to the classes in the order they are
+    /// passed to `UsbDevice::poll`, which is what to reorder for the classes'
+    /// own processing. `InvalidEndpoint` for EP0, indices out of range or
+    /// given twice.
+    pub fn set_service_order(&self, order: &[u8]) -> Result<()> {
+        let mut service_order = [0; NUM_ENDPOINTS - 1];
+        if order.len() > service_order.len() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let mut listed = 0u32;
+        for (slot, &index) in service_order.iter_mut().zip(order) {
+            if index == 0 || usize::from(index) >= NUM_ENDPOINTS || listed & (1 << index) != 0 {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            listed |= 1 << index;
+            *slot = index;
+        }
+        let rest = DEFAULT_SERVICE_ORDER
+            .into_iter()
+            .filter(|index| listed & (1 << index) == 0);
+        for (slot, index) in service_order[order.len()..].iter_mut().zip(rest) {
+            *slot = index;
+        }
+        interrupt::free(|cs| self.service_order.borrow(cs).set(service_order));
+        Ok(())
+    }
+
+    pub fn control_nak_counts(&self) -> ControlNakCounts {
+        interrupt::free(|cs| self.control_naks.borrow(cs).get())
+    }
+
+    pub fn reset_control_nak_counts(&self) {
+        interrupt::free(|cs| {
+            self.control_naks
+                .borrow(cs)
+                .set(ControlNakCounts::default())
+        });
+    }
+
+    /// Set the interrupt status bit of an endpoint as the hardware would on a
+    /// completed transfer (`INTSETSTAT`), which also raises the USB interrupt.
+    ///
+    /// `poll()` decodes it like the real thing, so this is for exercising
+    /// the poll path on target and for recovery code that needs an endpoint
+    /// looked at again. Nothing is transferred: on an IN endpoint with no
+    /// buffer in flight, it is reported as a completion.
+    pub fn trigger_endpoint_interrupt(&self, ep_addr: EndpointAddress) -> Result<()> {
+        let index = ep_addr.index();
+        if index >= NUM_ENDPOINTS {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let bit = 2 * index + usize::from(ep_addr.is_in());
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            usb.dev.intsetstat.write(|w| unsafe { w.bits(1 << bit) });
+        });
+        Ok(())
+    }
+
+    /// Raise the USB interrupt through `DEV_INT`, to have `poll()` run e.g.
+    /// while a suspend is being debounced, see [`set_clock`](Self::set_clock).
+    pub fn trigger_device_interrupt(&self) {
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            usb.dev.intsetstat.write(|w| w.dev_set_int().set_bit());
+        });
+    }
+
+    /// Snapshot of the link status, for bring-up of new hardware.
+    ///
+    /// Clears `DEVCMDSTAT.DRES_C`, so [`LineStatus::reset_seen`] only covers
+    /// the time since the previous snapshot. `poll()` still reports the reset.
+    pub fn line_status(&self) -> LineStatus {
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            let status = LineStatus::read(&usb.dev, &usb.phy);
+            if status.reset_seen {
+                devcmdstat::modify(&usb.dev, |w| w.dres_c().set_bit());
+                self.reset_latched.borrow(cs).set(true);
+            }
+            status
+        })
+    }
+
+    /// See [`UsbHS::quirks`].
+    pub fn quirks(&self) -> Quirks {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).quirks())
+    }
+
+    /// See [`UsbHS::with_registers`].
+    pub fn with_registers<R>(&self, f: impl FnOnce(&USBPHY, &USB1, &USBHSH) -> R) -> R {
+        interrupt::free(|cs| {
+            let usb = self.usb_regs.borrow(cs);
+            f(&usb.phy, &usb.dev, &usb.host)
+        })
+    }
+
+    /// See [`UsbHS::reinit_phy`]. The delays run with interrupts masked.
+    pub fn reinit_phy(&self, delay_us: impl FnMut(u32)) {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).reinit_phy(delay_us));
+    }
+
+    /// See [`UsbHS::is_pll_locked`].
+    pub fn is_pll_locked(&self) -> bool {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).is_pll_locked())
+    }
+
+    /// See [`UsbHS::dump_phy`].
+    pub fn dump_phy(&self) -> PhyRegisters {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).dump_phy())
+    }
 
-        UsbBusAllocator::new(bus)
+    /// See [`UsbHS::set_phy_clock_gated`].
+    pub fn set_phy_clock_gated(&self, gated: bool) {
+        interrupt::free(|cs| self.usb_regs.borrow(cs).set_phy_clock_gated(gated));
+    }
+}
+
+/// The bus within one critical section, see [`UsbHSBus::with_bus`].
+pub struct BusTransaction<'a> {
+    bus: &'a UsbHSBus,
+    cs: &'a CriticalSection,
+}
+
+impl BusTransaction<'_> {
+    /// See [`UsbBus::poll`].
+    pub fn poll(&mut self) -> PollResult {
+        self.bus.poll_in(self.cs).into()
+    }
+
+    /// See [`UsbHSBus::poll_fast`].
+    pub fn poll_fast(&mut self) -> PollEvents {
+        self.bus.poll_in(self.cs)
+    }
+
+    /// See [`UsbBus::read`].
+    pub fn read(&mut self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        self.read_packet(ep_addr, buf).map(|packet| packet.len)
+    }
+
+    /// See [`UsbHSBus::read_packet`].
+    pub fn read_packet(&mut self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<OutPacket> {
+        self.bus.read_packet_in(self.cs, ep_addr, buf)
+    }
+
+    /// See [`UsbBus::write`].
+    pub fn write(&mut self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        self.bus.write_in(self.cs, ep_addr, buf)
+    }
+
+    /// Hand EP0 back to the hardware the way the user manual asks for after
+    /// a SETUP: both buffers deactivated and unstalled, `DEVCMDSTAT.SETUP`
+    /// cleared, OUT re-armed for the data stage.
+    ///
+    /// `poll()` does this as soon as it sees a SETUP, after copying it out.
+    /// Called directly, it aborts the current control transfer: a SETUP
+    /// that no poll has picked up yet is dropped, and so is the data stage
+    /// the bus still expected. `usb-device` isn't told, so only call it
+    /// while it has no control transfer going, or it stalls the next stage.
+    pub fn ack_setup(&mut self) {
+        self.bus.ack_setup_in(self.cs);
+    }
+}
+
+fn new_allocator(config: &UsbConfig) -> EndpointMemoryAllocator {
+    let mut ep_allocator = EndpointMemoryAllocator::new();
+    if let Some(size) = config.usb_ram_size {
+        ep_allocator.limit(size);
+    }
+    ep_allocator
+}
+
+fn new_endpoints(ep_regs: &endpoint_registers::Instance, hold: bool) -> [Endpoint; NUM_ENDPOINTS] {
+    let mut endpoints: [core::mem::MaybeUninit<Endpoint>; NUM_ENDPOINTS] =
+        unsafe { core::mem::MaybeUninit::uninit().assume_init() };
+
+    for (i, endpoint) in endpoints.iter_mut().enumerate() {
+        *endpoint = core::mem::MaybeUninit::new(Endpoint::new(i as u8, ep_regs, hold));
+    }
+
+    unsafe {
+        core::mem::transmute::<
+            [core::mem::MaybeUninit<Endpoint>; NUM_ENDPOINTS],
+            [Endpoint; NUM_ENDPOINTS],
+        >(endpoints)
+    }
+}
+
+impl Drop for UsbHSBus {
+    fn drop(&mut self) {
+        self.disable();
+        // SAFETY: the fields are never used again, and `ManuallyDrop` keeps
+        // the controller from being dropped along with them
+        let usb = interrupt::free(|cs| unsafe {
+            endpoint_registers::detach();
+            ManuallyDrop::into_inner(ptr::read(self.usb_regs.borrow(cs)))
+        });
+        usb.release();
     }
 }
 
@@ -57,20 +2019,30 @@ impl UsbBus for UsbHSBus {
         ep_addr: Option<EndpointAddress>,
         ep_type: EndpointType,
         max_packet_size: u16,
-        _interval: u8,
+        interval: u8,
     ) -> Result<EndpointAddress> {
+        self.start_session();
+        if ep_type == EndpointType::Control {
+            self.check_control_max_packet_size(max_packet_size)?;
+        }
+
         let addr_range = if let Some(addr) = ep_addr {
+            if addr.index() >= NUM_ENDPOINTS {
+                return Err(UsbError::InvalidEndpoint);
+            }
             addr.index()..addr.index() + 1
         } else {
             1..NUM_ENDPOINTS
         };
 
         for index in addr_range {
+            let addr = EndpointAddress::from_parts(index, ep_dir);
             let ep = &mut self.endpoints[index];
 
             match ep.ep_type() {
                 None => {
                     ep.set_ep_type(ep_type);
+                    ep.set_max_packet_size(max_packet_size);
                 }
                 Some(t) if t != ep_type => {
                     continue;
@@ -80,29 +2052,37 @@ impl UsbBus for UsbHSBus {
 
             match ep_dir {
                 UsbDirection::Out if !ep.is_out_buf_set() => {
-                    let mut size = max_packet_size;
-                    // ZLP NYET Fix
-                    if index == 0 {
-                        size += 1;
-                    }
-                    let buffer = self.ep_allocator.allocate_buffer(size as _)?;
+                    let spare =
+                        interrupt::free(|cs| self.usb_regs.borrow(cs).quirks()).ep0_out_spare_byte;
+                    let size = match index {
+                        0 => max_packet_size as usize + usize::from(spare),
+                        _ => budget::buffer_size(max_packet_size, self.config.out_buffer_size),
+                    };
+                    let buffer = allocate_buffer(&mut self.ep_allocator, addr, size)?;
                     ep.set_out_buf(buffer);
                     debug_assert!(ep.is_out_buf_set());
 
                     if index == 0 {
-                        let setup = self.ep_allocator.allocate_buffer(8)?;
+                        let setup = allocate_buffer(&mut self.ep_allocator, addr, 8)?;
                         ep.set_setup_buf(setup);
                     }
 
-                    return Ok(EndpointAddress::from_parts(index, ep_dir));
+                    ep.set_interval(ep_dir, interval);
+                    self.track_allocation(index, ep_dir);
+                    return Ok(addr);
                 }
 
                 UsbDirection::In if !ep.is_in_buf_set() => {
-                    let size = max_packet_size;
-                    let buffer = self.ep_allocator.allocate_buffer(size as _)?;
+                    let size = match index {
+                        0 => max_packet_size as usize,
+                        _ => budget::buffer_size(max_packet_size, self.config.in_buffer_size),
+                    };
+                    let buffer = allocate_buffer(&mut self.ep_allocator, addr, size)?;
                     ep.set_in_buf(buffer);
 
-                    return Ok(EndpointAddress::from_parts(index, ep_dir));
+                    ep.set_interval(ep_dir, interval);
+                    self.track_allocation(index, ep_dir);
+                    return Ok(addr);
                 }
 
                 _ => {}
@@ -116,30 +2096,21 @@ impl UsbBus for UsbHSBus {
     }
 
     fn enable(&mut self) {
+        self.start_session();
+        self.enabled = true;
+        match self.config.power_profile {
+            Some(profile) => (profile.acquire)(),
+            None => self.acquire_power(),
+        }
+        interrupt::free(|cs| self.power_held.borrow(cs).set(true));
         interrupt::free(|cs| {
             let usb = self.usb_regs.borrow(cs);
             let eps = self.ep_regs.borrow(cs);
 
-            let mut max = 0;
-            for (index, ep) in self.endpoints.iter().enumerate() {
-                if ep.is_out_buf_set() || ep.is_in_buf_set() {
-                    max = index;
-
-                    // not sure this is needed
-                    if ep.is_out_buf_set() {
-                        ep.reset_out_buf(cs, eps);
-                        if index == 0 {
-                            ep.reset_setup_buf(cs, eps);
-                        }
-                        // ep.enable_out_interrupt(usb);
-                    }
-                    if ep.is_in_buf_set() {
-                        ep.reset_in_buf(cs, eps);
-                        // ep.enable_in_interrupt(usb);
-                    }
-                }
+            // not sure this is needed
+            for ep in self.endpoints.iter() {
+                ep.reset_buffers(cs, eps);
             }
-            self.max_endpoint = max;
 
             // DATABUFSTART
             unsafe {
@@ -152,15 +2123,30 @@ impl UsbBus for UsbHSBus {
 
             // EPLISTSTART
             unsafe {
+                // 256 byte aligned, see `endpoint_registers`
                 let epliststart = eps.addr;
-                debug_assert!(epliststart as u8 == 0); // needs to be 256 byte aligned
                 usb.dev
                     .epliststart
                     .modify(|_, w| w.ep_list().bits(epliststart >> 8));
             }
 
+            // Session policy
+            let config = &self.config;
+            devcmdstat::modify(&usb.dev, |w| {
+                w.intonnak_ci()
+                    .bit(config.control_nak_in)
+                    .intonnak_co()
+                    .bit(config.control_nak_out)
+                    .intonnak_ai()
+                    .bit(config.nak_in)
+                    .intonnak_ao()
+                    .bit(config.nak_out)
+            });
+            config.lpm.write(&usb.dev);
+            self.suspend_mode.borrow(cs).set(config.suspend_mode);
+
             // Clear PHY gate
-            usb.phy.ctrl_clr.write(|w| w.clkgate().set_bit());
+            usb.set_phy_clock_gated(false);
 
             // ENABLE + CONNECT
             usb.dev
@@ -168,10 +2154,7 @@ impl UsbBus for UsbHSBus {
                 .modify(|_, w| w.dev_en().set_bit().dcon().set_bit());
 
             // Enable Interrupts
-            usb.dev
-                .inten
-                .modify(|r, w| unsafe { w.bits(r.bits() | ((1 << 11) - 1)) });
-            usb.dev.inten.modify(|_, w| w.dev_int_en().set_bit());
+            self.update_inten(cs);
         });
     }
 
@@ -180,9 +2163,7 @@ impl UsbBus for UsbHSBus {
             // Set device address to 0
             let usb = self.usb_regs.borrow(cs);
             let eps = self.ep_regs.borrow(cs);
-            usb.dev
-                .devcmdstat
-                .modify(|_, w| unsafe { w.dev_addr().bits(0) });
+            devcmdstat::modify(&usb.dev, |w| unsafe { w.dev_addr().bits(0) });
 
             // Reset EPs
             for ep in self.endpoints.iter() {
@@ -191,205 +2172,108 @@ impl UsbBus for UsbHSBus {
 
             // Clear all interrupts
             usb.dev.intstat.write(|w| unsafe { w.bits(!0) });
+
+            self.speed.borrow(cs).set(LinkSpeed::Unknown);
+            self.bypass_active.borrow(cs).set(None);
+            self.pending_test_mode.borrow(cs).set(None);
+            self.in_l1.borrow(cs).set(false);
+            self.l1_wake_requested.borrow(cs).set(false);
+            self.ep_errors.borrow(cs).set([[0; 2]; NUM_ENDPOINTS]);
+
+            let resets = self.resets.borrow(cs);
+            resets.set(resets.get().wrapping_add(1));
+            #[cfg(feature = "metrics")]
+            {
+                let history = self.reset_history.borrow(cs);
+                let mut updated = history.get();
+                updated.record(self.now_ms(cs));
+                history.set(updated);
+            }
+            self.enumeration.borrow(cs).set(EnumerationRecord {
+                reset_ms: self.now_ms(cs),
+                ..EnumerationRecord::default()
+            });
         });
     }
 
     fn set_device_address(&self, addr: u8) {
         interrupt::free(|cs| {
-            self.usb_regs
-                .borrow(cs)
-                .dev
-                .devcmdstat
-                .modify(|_, w| unsafe { w.dev_addr().bits(addr) });
+            if addr != 0 {
+                let enumeration = self.enumeration.borrow(cs);
+                enumeration.set(EnumerationRecord {
+                    state: EnumerationState::Addressed,
+                    addressed_ms: self.now_ms(cs),
+                    configured_ms: None,
+                    ..enumeration.get()
+                });
+            }
+
+            let usb = self.usb_regs.borrow(cs);
+            devcmdstat::modify(&usb.dev, |w| unsafe { w.dev_addr().bits(addr) });
         });
     }
 
     fn poll(&self) -> PollResult {
-        interrupt::free(|cs| {
-            let usb = self.usb_regs.borrow(cs);
-            let eps = self.ep_regs.borrow(cs);
-
-            let devcmdstat = &usb.dev.devcmdstat;
-            let intstat = &usb.dev.intstat;
-
-            // Bus reset flag?
-            if devcmdstat.read().dres_c().bit_is_set() {
-                devcmdstat.modify(|_, w| w.dres_c().set_bit());
-                return PollResult::Reset;
-            }
-
-            // Suspend flag
-            if devcmdstat.read().dsus_c().bit_is_set() || devcmdstat.read().lpm_sus().bit_is_set() {
-                return PollResult::Suspend;
-            }
-
-            let mut ep_out = 0;
-            let mut ep_in_complete = 0;
-            let mut ep_setup = 0;
-
-            let mut bit = 1;
-
-            // NB: these are not "reader objects", but the actual value
-            // of the registers at time of assignment :))
-            let intstat_r = intstat.read();
-
-            // First handle endpoint 0 (the only control endpoint)
-            if intstat_r.ep0out().bit_is_set() {
-                if devcmdstat.read().setup().bit_is_set() {
-                    ep_setup |= bit;
-                } else {
-                    ep_out |= bit;
-                }
-            }
-
-            if intstat_r.ep0in().bit_is_set() {
-                intstat.write(|w| w.ep0in().set_bit());
-                ep_in_complete |= bit;
-
-                // EP0 needs manual toggling of Active bits
-                // Weeelll interesting, not changing this makes no difference
-                eps.eps[0].ep_in[0].modify(|_, w| w.a().not_active());
-            }
-
-            // non-CONTROL
-            for ep in &self.endpoints[1..=self.max_endpoint] {
-                bit <<= 1;
-                let i = ep.index() as usize;
-
-                // OUT = READ
-                let out_offset = 2 * i;
-                let out_int = ((intstat_r.bits() >> out_offset) & 0x1) != 0;
-                let out_inactive = eps.eps[i].ep_out[0].read().a().is_not_active();
-
-                if out_int {
-                    debug_assert!(out_inactive);
-                    ep_out |= bit;
-                    // EXPERIMENTAL: clear interrupt
-                    // usb.intstat.write(|w| unsafe { w.bits(1u32 << out_offset) } );
-
-                    // let err_code = usb.info.read().err_code().bits();
-                    // let addr_set = devcmdstat.read().dev_addr().bits() > 0;
-                    // if addr_set && err_code > 0 {
-                    //     hprintln!("error {}", err_code).ok();
-                    // }
-                }
-
-                // IN = WRITE
-                let in_offset = 2 * i + 1;
-                let in_int = ((intstat_r.bits() >> in_offset) & 0x1) != 0;
-                // WHYY is this sometimes still active?
-                let in_inactive = eps.eps[i].ep_in[0].read().a().is_not_active();
-                if in_int && !in_inactive {
-                    // cortex_m_semihosting::hprintln!(
-                    //     "IN is active for EP {}, but an IN interrupt fired", i,
-                    // ).ok();
-                    // cortex_m_semihosting::hprintln!(
-                    //     "IntOnNAK_AI = {}, IntOnNAK_AO = {}",
-                    //     devcmdstat.read().intonnak_ai().is_enabled(),
-                    //     devcmdstat.read().intonnak_ao().is_enabled(),
-                    // ).ok();
-
-                    // debug_assert!(in_inactive);
-                }
-                if in_int && in_inactive {
-                    ep_in_complete |= bit;
-                    // clear it
-                    usb.dev
-                        .intstat
-                        .write(|w| unsafe { w.bits(1u32 << in_offset) });
-                    debug_assert!(eps.eps[i].ep_in[0].read().a().is_not_active());
-
-                    // let err_code = usb.info.read().err_code().bits();
-                    // let addr_set = devcmdstat.read().dev_addr().bits() > 0;
-                    // if addr_set && err_code > 0 {
-                    //     hprintln!("error {}", err_code).ok();
-                    // }
-                };
-            }
-
-            usb.dev.intstat.write(|w| w.dev_int().set_bit());
-            if (ep_out | ep_in_complete | ep_setup) != 0 {
-                PollResult::Data {
-                    ep_out,
-                    ep_in_complete,
-                    ep_setup,
-                }
-            } else {
-                PollResult::None
-            }
-        })
+        interrupt::free(|cs| self.poll_in(cs)).into()
     }
 
     fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
-        if !ep_addr.is_out() {
-            return Err(UsbError::InvalidEndpoint);
-        }
-
-        interrupt::free(|cs| {
-            let usb = self.usb_regs.borrow(cs);
-            let eps = self.ep_regs.borrow(cs);
-            self.endpoints[ep_addr.index()].read(buf, cs, &usb.dev, eps)
-        })
+        self.read_packet(ep_addr, buf).map(|packet| packet.len)
     }
 
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
-        if !ep_addr.is_in() {
-            return Err(UsbError::InvalidEndpoint);
-        }
-
-        interrupt::free(|cs| {
-            let eps = self.ep_regs.borrow(cs);
-            self.endpoints[ep_addr.index()].write(buf, cs, eps)
-        })
+        interrupt::free(|cs| self.write_in(cs, ep_addr, buf))
     }
 
     fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
-        interrupt::free(|cs| {
-            if self.is_stalled(ep_addr) == stalled {
-                return;
-            }
-
-            let i = ep_addr.index();
-            let ep = &self.ep_regs.borrow(cs).eps[i];
-
-            if i > 0 {
-                match ep_addr.direction() {
-                    UsbDirection::In => while ep.ep_in[0].read().a().is_active() {},
-                    UsbDirection::Out => while ep.ep_out[0].read().a().is_active() {},
-                }
-            }
-
-            match (stalled, ep_addr.direction()) {
-                (true, UsbDirection::In) => ep.ep_in[0].modify(|_, w| w.s().stalled()),
-                (true, UsbDirection::Out) => ep.ep_out[0].modify(|_, w| w.s().stalled()),
-
-                (false, UsbDirection::In) => ep.ep_in[0].modify(|_, w| w.s().not_stalled()),
-                (false, UsbDirection::Out) => ep.ep_out[0].modify(|_, w| w.s().not_stalled()),
-            };
-        });
+        self.set_endpoint_stalled(ep_addr, stalled).ok();
     }
 
     fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let Some(ep) = self.endpoints.get(ep_addr.index()) else {
+            return false;
+        };
+        interrupt::free(|cs| ep.is_stalled(cs, self.ep_regs.borrow(cs), ep_addr.direction()))
+    }
+
+    fn suspend(&self) {
         interrupt::free(|cs| {
-            let ep = &self.ep_regs.borrow(cs).eps[ep_addr.index()];
-            match ep_addr.direction() {
-                UsbDirection::In => ep.ep_in[0].read().s().is_stalled(),
-                UsbDirection::Out => ep.ep_out[0].read().s().is_stalled(),
+            if self.suspend_mode.borrow(cs).get() == SuspendMode::LowPower {
+                let usb = self.usb_regs.borrow(cs);
+                let saved = phy::enter_low_power(&usb.phy);
+                self.suspended_phy.borrow(cs).set(Some(saved));
             }
-        })
+        });
     }
 
-    fn suspend(&self) {}
-
     fn resume(&self) {
         interrupt::free(|cs| {
             let usb = self.usb_regs.borrow(cs);
-            let devcmdstat = &usb.dev.devcmdstat;
-
-            if devcmdstat.read().lpm_rewp().bit_is_set() {
-                devcmdstat.modify(|_, w| w.lpm_sus().clear_bit());
+            if let Some(saved) = self.suspended_phy.borrow(cs).take() {
+                self.exit_low_power(cs, usb, saved);
             }
-            devcmdstat.modify(|_, w| w.dsus().clear_bit());
+
+            // The link is up again by the time usb-device calls this, L1 exits
+            // being done by the hardware (host) or `remote_wakeup` (device).
+            // A reset right after the resume, as hosts do when they suspended
+            // in the middle of enumeration, must not be acknowledged here.
+            devcmdstat::modify(&usb.dev, |w| w.dsus().clear_bit());
         });
     }
 }
+
+// Allocate, and record what didn't fit for `debug::allocation_failure`
+fn allocate_buffer(
+    allocator: &mut EndpointMemoryAllocator,
+    ep_addr: EndpointAddress,
+    size: usize,
+) -> Result<EndpointBuffer> {
+    let remaining = allocator.remaining();
+    allocator.allocate_buffer(size).inspect_err(|_| {
+        debug::record_allocation_failure(AllocationFailure {
+            ep_addr,
+            size,
+            remaining,
+        })
+    })
+}
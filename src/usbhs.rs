@@ -1,39 +1,115 @@
 use lpc55_hal::{
     drivers::timer::Timer,
     peripherals::ctimer,
-    raw::{USB1, USBHSH, USBPHY},
-    time::DurationExtensions,
-    traits::wg::timer::CountDown,
+    raw::{Interrupt, USB1, USBHSH, USBPHY},
     typestates::init_state,
     Anactrl, Pmc, Syscon, Usbhs,
 };
 
+use core::cell::RefCell;
+use cortex_m::{
+    interrupt::{self, Mutex},
+    peripheral::{syst::SystClkSource, NVIC, SYST},
+};
+
+use crate::{
+    builder::{self, UsbHsBuilder, WatchdogFeed},
+    debug::PhyRegisters,
+    error::ClockTooSlow,
+    hal::{devcmdstat, endpoint_registers},
+    marker::NotSync,
+    phy::{self, ClockInfo, Crystal, PhyClockGating, PhyPowerDown, PhyTuning},
+    power::{DefaultPowerSequencer, PowerSequencer},
+    quirks::{Quirks, SiliconRevision},
+};
+
+// Controller left behind by a dropped `UsbHSBus`, see `UsbHS::reclaim`.
+static RELEASED: Mutex<RefCell<Option<UsbHS>>> = Mutex::new(RefCell::new(None));
+
+/// The powered-up USB1 controller and its HS PHY.
+///
+/// `Send` but not `Sync`, see the [`marker`](crate::marker) module.
 pub struct UsbHS {
     pub(crate) phy: USBPHY,
     pub(crate) dev: USB1,
-    pub(crate) _host: USBHSH,
+    pub(crate) host: USBHSH,
+    quirks: Quirks,
+    // for `reinit_phy`
+    crystal: Crystal,
+    phy_tuning: Option<PhyTuning>,
+    pub(crate) watchdog: Option<WatchdogFeed>,
+    // system clock as of init, which the PLL lock timeout is counted in
+    pub(crate) core_hz: u32,
+    _not_sync: NotSync,
 }
 
 impl UsbHS {
+    /// Bring up the controller and PHY with the default [`UsbHsBuilder`],
+    /// failing if the system clock is too slow, see
+    /// [`UsbHsBuilder::build`].
     pub fn new(
         usb: Usbhs,
         syscon: &mut Syscon,
         pmc: &mut Pmc,
-        _anactrl: &Anactrl,
+        anactrl: &Anactrl,
         timer: &mut Timer<impl ctimer::Ctimer<init_state::Enabled>>,
-    ) -> Self {
-        // SAFTEY: We can have two references to the same peripheral, there aren't any mut references alive
-        let pmc_raw = unsafe { &lpc55_hal::raw::Peripherals::steal().PMC };
-        // SAFTEY: We can have two references to the same peripheral, there aren't any mut references alive
-        let anactrl_raw = unsafe { &lpc55_hal::raw::Peripherals::steal().ANACTRL };
+    ) -> Result<Self, ClockTooSlow> {
+        UsbHsBuilder::new().build(usb, syscon, pmc, anactrl, timer)
+    }
+
+    /// Like [`new`](Self::new), but time the PHY settling delays with
+    /// SysTick instead of a CTIMER, for projects that have none to spare
+    /// during early boot.
+    ///
+    /// `core_hz` is the frequency of the core clock. SysTick is reconfigured
+    /// and left disabled, set it up again afterwards.
+    pub fn new_with_systick(
+        usb: Usbhs,
+        syscon: &mut Syscon,
+        pmc: &mut Pmc,
+        anactrl: &Anactrl,
+        syst: &mut SYST,
+        core_hz: u32,
+    ) -> Result<Self, ClockTooSlow> {
+        UsbHsBuilder::new().build_with_systick(usb, syscon, pmc, anactrl, syst, core_hz)
+    }
 
+    /// Like [`new`](Self::new), but busy-wait the PHY settling delays,
+    /// for bootloaders that bring up USB before any timekeeping exists.
+    ///
+    /// `cpu_hz` is the core clock frequency; overstating it only makes the
+    /// delays longer.
+    pub fn new_with_cpu_freq(
+        usb: Usbhs,
+        syscon: &mut Syscon,
+        pmc: &mut Pmc,
+        anactrl: &Anactrl,
+        cpu_hz: u32,
+    ) -> Result<Self, ClockTooSlow> {
+        UsbHsBuilder::new().build_with_cpu_freq(usb, syscon, pmc, anactrl, cpu_hz)
+    }
+
+    pub(crate) fn init(
+        usb: Usbhs,
+        syscon: &mut Syscon,
+        pmc: &mut Pmc,
+        config: &mut UsbHsBuilder<impl PowerSequencer>,
+        core_hz: u32,
+        delay_us: impl FnMut(u32),
+    ) -> Self {
         let _ = usb;
+        let mut delay_us = feeding(config.watchdog, delay_us);
+        let quirks = Quirks::select(SiliconRevision::read());
         let (mut phy, mut dev, mut host) = {
             // SAFTEY: The required peripherals were dropped above
             let pac = unsafe { lpc55_hal::raw::Peripherals::steal() };
             (pac.USBPHY, pac.USB1, pac.USBHSH)
         };
 
+        if config.take_over_from_rom {
+            quiesce_rom(syscon, &mut dev, &mut delay_us);
+        }
+
         // Reset devices
         syscon.reset(&mut host);
         syscon.reset(&mut dev);
@@ -47,64 +123,262 @@ impl UsbHS {
         syscon.disable_clock(&mut host);
 
         // Power on 32M crystal for HS PHY and connect to USB PLL
-        pmc_raw
-            .pdruncfg0
-            .modify(|_, w| w.pden_xtal32m().poweredon());
-        pmc_raw
-            .pdruncfg0
-            .modify(|_, w| w.pden_ldoxo32m().poweredon());
-        anactrl_raw
-            .xo32m_ctrl
-            .modify(|_, w| w.enable_pll_usb_out().set_bit());
-
-        pmc.power_on(&mut phy);
-
-        // Give long delay for PHY to be ready
-        timer.start((5u32 * 1000).microseconds());
-        nb::block!(timer.wait()).ok();
+        let power = &mut config.power;
+        power.power_up_xtal32m();
+        power.enable_usb_pll_clock();
+        power.power_up_phy(pmc, &mut phy, &mut delay_us);
 
         syscon.enable_clock(&mut phy);
 
-        // Initial config of PHY control registers
-        phy.ctrl.write(|w| w.sftrst().clear_bit());
-
-        phy.pll_sic.modify(|_, w| {
-            w.pll_div_sel()
-                .bits(6) /* 16MHz = xtal32m */
-                .pll_reg_enable()
-                .set_bit()
-        });
-
-        phy.pll_sic_clr.write(|w| unsafe {
-            // must be done, according to SDK.
-            w.bits(1 << 16 /* mystery bit */)
-        });
-
-        // Must wait at least 15 us for pll-reg to stabilize
-        timer.start(15u32.microseconds());
-        nb::block!(timer.wait()).ok();
-
-        phy.pll_sic
-            .modify(|_, w| w.pll_power().set_bit().pll_en_usb_clks().set_bit());
-
-        phy.ctrl.modify(|_, w| {
-            w.enautoclr_clkgate()
-                .set_bit()
-                .enautoclr_phy_pwd()
-                .clear_bit()
-        });
-
-        // Turn on everything in PHY
-        phy.pwd.write(|w| unsafe { w.bits(0) });
+        start_phy(&phy, config.crystal, config.phy_tuning, &mut delay_us);
 
         // turn on USB1 device controller access
         syscon.enable_clock(&mut dev);
 
+        devcmdstat::modify(&dev, |w| w.force_fs().bit(config.force_fs));
+
         //
         Self {
             phy,
             dev,
-            _host: host,
+            host,
+            quirks,
+            crystal: config.crystal,
+            phy_tuning: config.phy_tuning,
+            watchdog: config.watchdog,
+            core_hz,
+            _not_sync: NotSync::default(),
+        }
+    }
+
+    /// Conjure up the handle of a controller that was already brought up,
+    /// without the HAL singletons and without touching the hardware, e.g.
+    /// in a bootloader handing over or on a panic-recovery path.
+    ///
+    /// Like the PAC's `steal()`, this bypasses ownership. It also frees the
+    /// endpoint list for the next `UsbHSBus`.
+    ///
+    /// # Safety
+    /// The controller and PHY must have been initialized by one of the
+    /// constructors, and no other `UsbHS` or `UsbHSBus` may be used anymore.
+    pub unsafe fn steal() -> Self {
+        endpoint_registers::detach();
+        let pac = lpc55_hal::raw::Peripherals::steal();
+        Self {
+            phy: pac.USBPHY,
+            dev: pac.USB1,
+            host: pac.USBHSH,
+            quirks: Quirks::select(SiliconRevision::read()),
+            crystal: Crystal::default(),
+            phy_tuning: None,
+            watchdog: None,
+            core_hz: builder::system_clock_hz(Crystal::default())
+                .unwrap_or(UsbHsBuilder::<DefaultPowerSequencer>::MIN_SYSTEM_HZ),
+            _not_sync: NotSync::default(),
         }
     }
+
+    /// Take back the controller of a dropped [`UsbHSBus`](crate::UsbHSBus),
+    /// to build a new bus for another session.
+    ///
+    /// `None` until the `UsbBusAllocator` holding the bus has been dropped,
+    /// which in turn needs the `UsbDevice` and all classes to be gone.
+    pub fn reclaim() -> Option<Self> {
+        interrupt::free(|cs| RELEASED.borrow(cs).take())
+    }
+
+    pub(crate) fn release(self) {
+        interrupt::free(|cs| RELEASED.borrow(cs).replace(Some(self)));
+    }
+
+    /// Reset the PHY and bring it and the USB PLL up again, as during init,
+    /// e.g. after the PLL lost lock (see [`is_pll_locked`](Self::is_pll_locked))
+    /// or an ESD event upset the PHY. The controller, and with it the
+    /// device address, configuration and endpoints, is left alone; the
+    /// host sees at most a short glitch or has to reset the bus.
+    ///
+    /// PHY settings made after init, such as the power-down and clock
+    /// gating setters or the wakeup interrupts, are back to their defaults.
+    /// After [`steal`](Self::steal), this assumes the default crystal and no
+    /// tuning.
+    pub fn reinit_phy(&self, delay_us: impl FnMut(u32)) {
+        let mut delay_us = feeding(self.watchdog, delay_us);
+        self.phy.ctrl_set.write(|w| w.sftrst().set_bit());
+        start_phy(&self.phy, self.crystal, self.phy_tuning, &mut delay_us);
+    }
+
+    /// Whether the USB PLL is locked (`PLL_SIC.PLL_LOCK`).
+    pub fn is_pll_locked(&self) -> bool {
+        self.phy.pll_sic.read().pll_lock().bit_is_set()
+    }
+
+    /// USB PLL configuration and status, for bring-up logs.
+    ///
+    /// The frequencies are computed from the crystal given to the builder;
+    /// after [`steal`](Self::steal), that is the default one.
+    pub fn clock_info(&self) -> ClockInfo {
+        ClockInfo::read(&self.phy, self.crystal)
+    }
+
+    /// Silicon revision of this part, and the workarounds selected for it.
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Current power-down state of the PHY blocks.
+    pub fn phy_power_down(&self) -> PhyPowerDown {
+        PhyPowerDown::read(&self.phy)
+    }
+
+    /// Power individual PHY blocks up or down.
+    ///
+    /// Anything other than [`PhyPowerDown::ALL_ON`] breaks communication with
+    /// the host, so this is meant for suspend, or for when the device is
+    /// disconnected.
+    pub fn set_phy_power_down(&self, power_down: PhyPowerDown) {
+        power_down.write(&self.phy);
+    }
+
+    /// Current wakeup behaviour of the PHY clock gate.
+    pub fn phy_clock_gating(&self) -> PhyClockGating {
+        PhyClockGating::read(&self.phy)
+    }
+
+    /// Change the wakeup behaviour of the PHY clock gate, see [`PhyClockGating`].
+    pub fn set_phy_clock_gating(&self, gating: PhyClockGating) {
+        gating.write(&self.phy);
+    }
+
+    /// Whether the UTMI clocks are currently gated.
+    pub fn is_phy_clock_gated(&self) -> bool {
+        phy::is_clock_gated(&self.phy)
+    }
+
+    /// Snapshot of the PHY configuration and status registers.
+    ///
+    /// A misconfigured PHY is the most common reason for a device that never
+    /// enumerates without any other sign of life.
+    pub fn dump_phy(&self) -> PhyRegisters {
+        PhyRegisters::read(&self.phy)
+    }
+
+    /// Run `f` on the raw PHY, device and host controller registers, within
+    /// a critical section, for tweaks the driver has no API for.
+    ///
+    /// Whatever `f` changes, the driver doesn't know about it. The host
+    /// controller is left unclocked after init, so enable its clock in
+    /// SYSCON before touching `USBHSH`.
+    pub fn with_registers<R>(&self, f: impl FnOnce(&USBPHY, &USB1, &USBHSH) -> R) -> R {
+        interrupt::free(|_| f(&self.phy, &self.dev, &self.host))
+    }
+
+    /// Gate or ungate the UTMI clocks. The bus ungates them in `enable()`.
+    pub fn set_phy_clock_gated(&self, gated: bool) {
+        phy::set_clock_gated(&self.phy, gated);
+    }
+}
+
+// Longest stretch of a delay between two watchdog feeds
+const WATCHDOG_FEED_US: u32 = 1000;
+
+// Split `delay_us` into steps with a watchdog feed before each
+pub(crate) fn feeding(
+    feed: Option<WatchdogFeed>,
+    mut delay_us: impl FnMut(u32),
+) -> impl FnMut(u32) {
+    move |mut us| {
+        let Some(feed) = feed else {
+            return delay_us(us);
+        };
+        while us > 0 {
+            feed();
+            let step = us.min(WATCHDOG_FEED_US);
+            delay_us(step);
+            us -= step;
+        }
+    }
+}
+
+// PHY and USB PLL part of the bring-up, from out of reset
+fn start_phy(
+    phy: &USBPHY,
+    crystal: Crystal,
+    tuning: Option<PhyTuning>,
+    delay_us: &mut impl FnMut(u32),
+) {
+    // Initial config of PHY control registers
+    phy.ctrl.write(|w| w.sftrst().clear_bit());
+
+    phy.pll_sic.modify(|_, w| {
+        w.pll_div_sel()
+            .bits(crystal.pll_div_sel())
+            .pll_reg_enable()
+            .set_bit()
+    });
+
+    phy.pll_sic_clr.write(|w| unsafe {
+        // must be done, according to SDK.
+        w.bits(1 << 16 /* mystery bit */)
+    });
+
+    // Must wait at least 15 us for pll-reg to stabilize
+    delay_us(15);
+
+    phy.pll_sic
+        .modify(|_, w| w.pll_power().set_bit().pll_en_usb_clks().set_bit());
+
+    PhyClockGating::default().write(phy);
+
+    // Turn on everything in PHY
+    PhyPowerDown::ALL_ON.write(phy);
+
+    if let Some(tuning) = tuning {
+        tuning.write(phy);
+    }
+}
+
+// How long to stay disconnected after taking over from the ROM, so the host
+// notices the device went away; it debounces the reconnect for 100 ms anyway.
+const ROM_DISCONNECT_US: u32 = 10_000;
+
+fn quiesce_rom(syscon: &mut Syscon, dev: &mut USB1, delay_us: &mut impl FnMut(u32)) {
+    const IRQS: [Interrupt; 3] = [
+        Interrupt::USB1,
+        Interrupt::USB1_NEEDCLK,
+        Interrupt::USB1_PHY,
+    ];
+
+    for irq in IRQS {
+        NVIC::mask(irq);
+    }
+
+    syscon.enable_clock(dev);
+    dev.inten.write(|w| unsafe { w.bits(0) });
+    // also clears the change flags
+    dev.devcmdstat
+        .modify(|_, w| w.dev_en().clear_bit().dcon().clear_bit());
+    dev.intstat.write(|w| unsafe { w.bits(!0) });
+
+    for irq in IRQS {
+        NVIC::unpend(irq);
+    }
+
+    delay_us(ROM_DISCONNECT_US);
+}
+
+pub(crate) fn systick_delay(syst: &mut SYST, core_hz: u32, us: u32) {
+    const MAX_RELOAD: u32 = 0x00ff_ffff;
+
+    let mut ticks = u64::from(core_hz) * u64::from(us) / 1_000_000;
+    syst.disable_counter();
+    syst.set_clock_source(SystClkSource::Core);
+    while ticks > 0 {
+        let chunk = ticks.min(u64::from(MAX_RELOAD)) as u32;
+        syst.set_reload(chunk);
+        syst.clear_current();
+        syst.enable_counter();
+        while !syst.has_wrapped() {}
+        syst.disable_counter();
+        ticks -= u64::from(chunk);
+    }
 }
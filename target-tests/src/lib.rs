@@ -0,0 +1,2 @@
+//! Empty: everything is in `tests/`, a package needs a library or binary.
+#![no_std]
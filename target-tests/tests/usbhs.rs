@@ -0,0 +1,225 @@
+//! On-target tests of the driver, for an LPC55S69-EVK, run over the debug
+//! probe with `cargo test` from `target-tests/` (needs `probe-run`).
+//!
+//! `loopback` needs the high-speed port (P9) connected to a host running
+//! `usb-device`'s own host side of `TestClass`, i.e.
+//! `cargo test --test test_class_host --features test-class-high-speed` in a
+//! `usb-device` 0.2.9 checkout, started within `ENUMERATION_TIMEOUT_S` of
+//! the test. The others don't need a host, except `stall_while_active`,
+//! which runs on the enumerated device after `loopback`.
+
+#![no_std]
+#![no_main]
+
+use defmt_rtt as _;
+use panic_probe as _;
+
+#[defmt_test::tests]
+mod tests {
+    use cortex_m::peripheral::DWT;
+    use lpc55_hal::{self as hal, prelude::*};
+    use lpc55_usbhs::{UsbConfig, UsbHS, UsbHSBus};
+    use usb_device::{
+        bus::{UsbBus, UsbBusAllocator},
+        device::{UsbDevice, UsbDeviceState},
+        endpoint::{EndpointAddress, EndpointType, In, Out},
+        test_class::TestClass,
+        UsbDirection, UsbError,
+    };
+
+    // what `init` configures
+    const CORE_HZ: u32 = 96_000_000;
+    // for the host to enumerate the device and start its tests
+    const ENUMERATION_TIMEOUT_S: u32 = 30;
+    // for the host side to run all of its tests
+    const LOOPBACK_S: u32 = 60;
+
+    type Endpoint<D> = usb_device::endpoint::Endpoint<'static, UsbHSBus, D>;
+
+    struct State {
+        allocator: &'static UsbBusAllocator<UsbHSBus>,
+        test_class: Option<TestClass<'static, UsbHSBus>>,
+        spare: Option<(Endpoint<In>, Endpoint<Out>)>,
+        iso: Option<Endpoint<In>>,
+        device: Option<UsbDevice<'static, UsbHSBus>>,
+    }
+
+    // Seconds of DWT cycles, across counter wraps as long as it's called at
+    // least every 44 s
+    struct Stopwatch {
+        last: u32,
+        cycles: u64,
+    }
+
+    impl Stopwatch {
+        fn start() -> Self {
+            Self {
+                last: DWT::cycle_count(),
+                cycles: 0,
+            }
+        }
+
+        fn seconds(&mut self) -> u32 {
+            let now = DWT::cycle_count();
+            self.cycles += u64::from(now.wrapping_sub(self.last));
+            self.last = now;
+            (self.cycles / u64::from(CORE_HZ)) as u32
+        }
+    }
+
+    #[init]
+    fn init() -> State {
+        let mut core = cortex_m::Peripherals::take().unwrap();
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+
+        let hal = hal::new();
+        let mut anactrl = hal.anactrl;
+        let mut pmc = hal.pmc;
+        let mut syscon = hal.syscon;
+        hal::ClockRequirements::default()
+            .system_frequency(96.MHz())
+            .configure(&mut anactrl, &mut pmc, &mut syscon)
+            .unwrap();
+
+        let usb = UsbHS::new_with_systick(
+            hal.usbhs,
+            &mut syscon,
+            &mut pmc,
+            &anactrl,
+            &mut core.SYST,
+            CORE_HZ,
+        )
+        .unwrap();
+        State {
+            allocator: UsbHSBus::init_static(usb, UsbConfig::default())
+                .unwrap()
+                .unwrap(),
+            test_class: None,
+            spare: None,
+            iso: None,
+            device: None,
+        }
+    }
+
+    #[test]
+    fn allocates_test_class_and_spare_pair(state: &mut State) {
+        let test_class = TestClass::new(state.allocator);
+        let bulk_in: Endpoint<In> = state.allocator.bulk(512);
+        let bulk_out: Endpoint<Out> = state.allocator.bulk(512);
+        // TestClass takes 1 and 2, spare numbers are handed out in order
+        defmt::assert_eq!(bulk_in.address().index(), 3);
+        defmt::assert_eq!(bulk_out.address().index(), 3);
+        let iso: Endpoint<In> = state
+            .allocator
+            .alloc(None, EndpointType::Isochronous, 64, 1)
+            .unwrap();
+        defmt::assert_eq!(iso.address().index(), 4);
+
+        state.device = Some(test_class.make_device(state.allocator));
+        state.test_class = Some(test_class);
+        state.spare = Some((bulk_in, bulk_out));
+        state.iso = Some(iso);
+    }
+
+    #[test]
+    fn pll_locks(state: &mut State) {
+        assert!(state.device.as_ref().unwrap().bus().is_pll_locked());
+    }
+
+    #[test]
+    fn stall_toggles(state: &mut State) {
+        let bus = state.device.as_ref().unwrap().bus();
+        let (bulk_in, bulk_out) = state.spare.as_ref().unwrap();
+
+        bulk_in.stall();
+        assert!(UsbBus::is_stalled(bus, bulk_in.address()));
+        bulk_out.stall();
+        assert!(UsbBus::is_stalled(bus, bulk_out.address()));
+
+        bulk_in.unstall();
+        bulk_out.unstall();
+        for ep_addr in [bulk_in.address(), bulk_out.address()] {
+            assert!(!UsbBus::is_stalled(bus, ep_addr));
+            // clearing a halt restarts the data toggle
+            assert!(matches!(bus.data_toggle(ep_addr), Ok(false)));
+        }
+    }
+
+    #[test]
+    fn isochronous_refuses_stall(state: &mut State) {
+        let bus = state.device.as_ref().unwrap().bus();
+        let iso = state.iso.as_ref().unwrap();
+
+        assert!(matches!(
+            bus.set_endpoint_stalled(iso.address(), true),
+            Err(UsbError::InvalidEndpoint)
+        ));
+        assert!(!UsbBus::is_stalled(bus, iso.address()));
+    }
+
+    #[test]
+    fn loopback(state: &mut State) {
+        let device = state.device.as_mut().unwrap();
+        let test_class = state.test_class.as_mut().unwrap();
+
+        let mut stopwatch = Stopwatch::start();
+        while device.state() != UsbDeviceState::Configured {
+            if device.poll(&mut [&mut *test_class]) {
+                test_class.poll();
+            }
+            assert!(
+                stopwatch.seconds() < ENUMERATION_TIMEOUT_S,
+                "not enumerated, is the host side running?"
+            );
+        }
+        defmt::info!("configured, looping back for {:u32} s", LOOPBACK_S);
+
+        let mut stopwatch = Stopwatch::start();
+        while stopwatch.seconds() < LOOPBACK_S {
+            if device.poll(&mut [&mut *test_class]) {
+                test_class.poll();
+            }
+        }
+
+        // TestClass's bulk pair is endpoint 1
+        for dir in [UsbDirection::In, UsbDirection::Out] {
+            let ep_addr = EndpointAddress::from_parts(1, dir);
+            assert!(device.bus().throughput(ep_addr).unwrap().bytes > 0);
+        }
+    }
+
+    #[test]
+    fn stall_while_active(state: &mut State) {
+        let device = state.device.as_mut().unwrap();
+        let (bulk_in, bulk_out) = state.spare.as_ref().unwrap();
+
+        // the spare pair isn't in the descriptors, so the host never sends
+        // its tokens and both buffers stay with the hardware
+        bulk_in.write(&[0x55; 64]).unwrap();
+        assert!(matches!(bulk_in.write(&[0; 64]), Err(UsbError::WouldBlock)));
+
+        bulk_in.stall();
+        bulk_out.stall();
+        let bus = device.bus();
+        assert!(UsbBus::is_stalled(bus, bulk_in.address()));
+        assert!(UsbBus::is_stalled(bus, bulk_out.address()));
+
+        bulk_in.unstall();
+        bulk_out.unstall();
+        for ep_addr in [bulk_in.address(), bulk_out.address()] {
+            assert!(!UsbBus::is_stalled(bus, ep_addr));
+            assert!(matches!(bus.data_toggle(ep_addr), Ok(false)));
+        }
+        // the skipped IN packet was dropped, so a new one is taken
+        bulk_in.write(&[0xaa; 64]).unwrap();
+        // and nothing was received in between
+        assert!(matches!(
+            bulk_out.read(&mut [0; 512]),
+            Err(UsbError::WouldBlock)
+        ));
+        // the device keeps working
+        device.poll(&mut [state.test_class.as_mut().unwrap()]);
+        assert!(device.state() == UsbDeviceState::Configured);
+    }
+}
@@ -0,0 +1,121 @@
+//! Host side of the `sim` tests: control transfers driven through a
+//! [`SimHost`], polling the device between every step.
+
+#![allow(dead_code)]
+
+use lpc55_usbhs::sim::{SimError, SimHost};
+
+pub const EP0_SIZE: usize = 64;
+
+// Polls without the device answering before a transfer is given up on
+const MAX_POLLS: usize = 16;
+
+pub fn setup_packet(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let [value_lo, value_hi] = value.to_le_bytes();
+    let [index_lo, index_hi] = index.to_le_bytes();
+    let [length_lo, length_hi] = length.to_le_bytes();
+    [
+        request_type,
+        request,
+        value_lo,
+        value_hi,
+        index_lo,
+        index_hi,
+        length_lo,
+        length_hi,
+    ]
+}
+
+/// Collect the next IN packet on `ep`, polling while the device NAKs.
+pub fn take_in(host: &SimHost, poll: &mut impl FnMut(), ep: u8) -> Result<Vec<u8>, SimError> {
+    for _ in 0..MAX_POLLS {
+        match host.take_in(ep) {
+            Err(SimError::Nak) => poll(),
+            result => return result,
+        }
+    }
+    Err(SimError::Nak)
+}
+
+/// Send an OUT packet on `ep`, polling while the device NAKs.
+pub fn send_out(
+    host: &SimHost,
+    poll: &mut impl FnMut(),
+    ep: u8,
+    data: &[u8],
+) -> Result<(), SimError> {
+    for _ in 0..MAX_POLLS {
+        match host.out(ep, data) {
+            Err(SimError::Nak) => poll(),
+            result => return result,
+        }
+    }
+    Err(SimError::Nak)
+}
+
+/// A control read, returning the data stage packet by packet. It ends on a
+/// short packet (ZLP included) or once `wLength` bytes are in, as a host ends
+/// it.
+pub fn control_in(
+    host: &SimHost,
+    poll: &mut impl FnMut(),
+    setup: [u8; 8],
+) -> Result<Vec<Vec<u8>>, SimError> {
+    let length = usize::from(u16::from_le_bytes([setup[6], setup[7]]));
+    host.setup(setup);
+    poll();
+
+    let mut packets = Vec::new();
+    let mut total = 0;
+    loop {
+        let packet = take_in(host, poll, 0)?;
+        total += packet.len();
+        let short = packet.len() < EP0_SIZE;
+        packets.push(packet);
+        if short || total >= length {
+            break;
+        }
+    }
+    poll();
+
+    // status stage
+    send_out(host, poll, 0, &[])?;
+    poll();
+    Ok(packets)
+}
+
+/// A control write with `data` as the data stage, in max-size packets.
+pub fn control_out(
+    host: &SimHost,
+    poll: &mut impl FnMut(),
+    setup: [u8; 8],
+    data: &[u8],
+) -> Result<(), SimError> {
+    host.setup(setup);
+    poll();
+    for chunk in data.chunks(EP0_SIZE) {
+        send_out(host, poll, 0, chunk)?;
+        poll();
+    }
+
+    // status stage
+    let status = take_in(host, poll, 0)?;
+    assert!(status.is_empty(), "status stage with data: {status:?}");
+    poll();
+    Ok(())
+}
+
+/// Reset the bus and take the device through `SET_ADDRESS` and
+/// `SET_CONFIGURATION`, returning its device descriptor.
+pub fn enumerate(host: &SimHost, poll: &mut impl FnMut(), address: u8) -> Vec<u8> {
+    host.reset();
+    poll();
+
+    let descriptor: Vec<u8> = control_in(host, poll, setup_packet(0x80, 6, 0x0100, 0, 18))
+        .expect("GET_DESCRIPTOR(DEVICE)")
+        .concat();
+    control_out(host, poll, setup_packet(0x00, 5, address.into(), 0, 0), &[]).expect("SET_ADDRESS");
+    assert_eq!(host.address(), address);
+    control_out(host, poll, setup_packet(0x00, 9, 1, 0, 0), &[]).expect("SET_CONFIGURATION");
+    descriptor
+}
@@ -0,0 +1,176 @@
+//! Multi-packet control transfers on EP0, through the `sim` bus.
+
+#![cfg(feature = "sim")]
+
+mod common;
+
+use common::{control_in, control_out, enumerate, setup_packet, EP0_SIZE};
+use lpc55_usbhs::sim::{SimBus, SimError};
+use usb_device::{
+    bus::UsbBusAllocator,
+    class::{ControlIn, ControlOut, UsbClass},
+    control::RequestType,
+    device::{UsbDeviceBuilder, UsbVidPid},
+};
+
+const VENDOR_IN: u8 = 0xc0;
+const VENDOR_OUT: u8 = 0x40;
+// IN: the first wValue bytes of `BLOB`
+const READ_BLOB: u8 = 1;
+// OUT: kept in `Blob::received`
+const WRITE_BLOB: u8 = 2;
+
+static BLOB: [u8; 255] = {
+    let mut blob = [0; 255];
+    let mut i = 0;
+    while i < blob.len() {
+        blob[i] = i as u8;
+        i += 1;
+    }
+    blob
+};
+
+#[derive(Default)]
+struct Blob {
+    received: Vec<u8>,
+}
+
+impl UsbClass<SimBus> for Blob {
+    fn control_in(&mut self, xfer: ControlIn<SimBus>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Vendor || req.request != READ_BLOB {
+            return;
+        }
+        xfer.accept_with_static(&BLOB[..usize::from(req.value)])
+            .unwrap();
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<SimBus>) {
+        let req = *xfer.request();
+        if req.request_type != RequestType::Vendor || req.request != WRITE_BLOB {
+            return;
+        }
+        self.received = xfer.data().to_vec();
+        xfer.accept().unwrap();
+    }
+}
+
+fn lengths(packets: &[Vec<u8>]) -> Vec<usize> {
+    packets.iter().map(Vec::len).collect()
+}
+
+#[test]
+fn control_in_data_stages() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut blob = Blob::default();
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001))
+        .max_packet_size_0(EP0_SIZE as u8)
+        .build();
+    let mut poll = || {
+        dev.poll(&mut [&mut blob]);
+    };
+    enumerate(&host, &mut poll, 7);
+
+    // ends on a short packet
+    let packets = control_in(
+        &host,
+        &mut poll,
+        setup_packet(VENDOR_IN, READ_BLOB, 200, 0, 255),
+    )
+    .unwrap();
+    assert_eq!(lengths(&packets), [64, 64, 64, 8]);
+    assert_eq!(packets.concat(), BLOB[..200]);
+
+    // a whole number of packets, shorter than wLength: ends on a ZLP
+    let packets = control_in(
+        &host,
+        &mut poll,
+        setup_packet(VENDOR_IN, READ_BLOB, 128, 0, 255),
+    )
+    .unwrap();
+    assert_eq!(lengths(&packets), [64, 64, 0]);
+    assert_eq!(packets.concat(), BLOB[..128]);
+
+    // exactly wLength: the host moves on to the status stage after the
+    // second packet, whether or not a ZLP follows
+    let packets = control_in(
+        &host,
+        &mut poll,
+        setup_packet(VENDOR_IN, READ_BLOB, 128, 0, 128),
+    )
+    .unwrap();
+    assert_eq!(lengths(&packets), [64, 64]);
+
+    // more than the host asked for is cut at wLength
+    let packets = control_in(
+        &host,
+        &mut poll,
+        setup_packet(VENDOR_IN, READ_BLOB, 200, 0, 100),
+    )
+    .unwrap();
+    assert_eq!(lengths(&packets), [64, 36]);
+}
+
+#[test]
+fn control_out_data_stages() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut blob = Blob::default();
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001))
+        .max_packet_size_0(EP0_SIZE as u8)
+        .build();
+    let mut poll = || {
+        dev.poll(&mut [&mut blob]);
+    };
+    enumerate(&host, &mut poll, 7);
+
+    // two full packets and a short one
+    control_out(
+        &host,
+        &mut poll,
+        setup_packet(VENDOR_OUT, WRITE_BLOB, 0, 0, 100),
+        &BLOB[..100],
+    )
+    .unwrap();
+    // a whole number of packets: wLength ends it, without a ZLP
+    control_out(
+        &host,
+        &mut poll,
+        setup_packet(VENDOR_OUT, WRITE_BLOB, 0, 0, 128),
+        &BLOB[..128],
+    )
+    .unwrap();
+    assert_eq!(blob.received, BLOB[..128]);
+}
+
+#[test]
+fn unhandled_requests_stall() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut blob = Blob::default();
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001))
+        .max_packet_size_0(EP0_SIZE as u8)
+        .build();
+    let mut poll = || {
+        dev.poll(&mut [&mut blob]);
+    };
+    enumerate(&host, &mut poll, 7);
+
+    let read = setup_packet(VENDOR_IN, 0x7f, 0, 0, 64);
+    assert_eq!(control_in(&host, &mut poll, read), Err(SimError::Stall));
+    let write = setup_packet(VENDOR_OUT, 0x7f, 0, 0, 100);
+    assert_eq!(
+        control_out(&host, &mut poll, write, &BLOB[..100]),
+        Err(SimError::Stall)
+    );
+
+    // the next SETUP clears the stall
+    let packets = control_in(
+        &host,
+        &mut poll,
+        setup_packet(VENDOR_IN, READ_BLOB, 10, 0, 10),
+    )
+    .unwrap();
+    assert_eq!(packets.concat(), BLOB[..10]);
+}
@@ -0,0 +1,125 @@
+//! The `UsbHSBus` extensions the `sim` bus has, on an enumerated device.
+
+#![cfg(feature = "sim")]
+
+mod common;
+
+use common::{enumerate, send_out, setup_packet, take_in, EP0_SIZE};
+use lpc55_usbhs::{
+    sim::{SimBus, SimError},
+    FrameNumber,
+};
+use std::sync::atomic::{AtomicU8, Ordering};
+use usb_device::{
+    bus::UsbBusAllocator,
+    class::UsbClass,
+    device::{UsbDeviceBuilder, UsbDeviceState, UsbVidPid},
+    endpoint::{EndpointAddress, EndpointIn, EndpointOut},
+    UsbDirection,
+};
+
+const BULK_SIZE: u16 = 512;
+
+// A bulk pair the test drives through the bus extensions
+struct Bulk<'a> {
+    out: EndpointOut<'a, SimBus>,
+    in_: EndpointIn<'a, SimBus>,
+}
+
+impl UsbClass<SimBus> for Bulk<'_> {}
+
+impl<'a> Bulk<'a> {
+    fn new(alloc: &'a UsbBusAllocator<SimBus>) -> Self {
+        Self {
+            out: alloc.bulk(BULK_SIZE),
+            in_: alloc.bulk(BULK_SIZE),
+        }
+    }
+}
+
+static REFILLS: AtomicU8 = AtomicU8::new(0);
+
+// three packets numbered 1 to 3, then idle
+fn refill(buf: &mut [u8]) -> Option<usize> {
+    let n = REFILLS.fetch_add(1, Ordering::Relaxed) + 1;
+    if n > 3 {
+        return None;
+    }
+    buf[0] = n;
+    Some(1)
+}
+
+#[test]
+fn extensions_after_enumeration() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut class = Bulk::new(&alloc);
+    let (out_addr, in_addr): (EndpointAddress, EndpointAddress) =
+        (class.out.address(), class.in_.address());
+    let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001))
+        .max_packet_size_0(EP0_SIZE as u8)
+        .build();
+    let mut poll = || _ = dev.poll(&mut [&mut class]);
+    enumerate(&host, &mut poll, 5);
+    assert_eq!(dev.state(), UsbDeviceState::Configured);
+    let out_ep = out_addr.index() as u8;
+    let in_ep = in_addr.index() as u8;
+
+    // a SETUP reads as a short packet without a frame, as on the target
+    host.setup(setup_packet(0x80, 0, 0, 0, 2));
+    let ep0_out = EndpointAddress::from_parts(0, UsbDirection::Out);
+    let mut setup = [0; 8];
+    let packet = dev.bus().read_packet(ep0_out, &mut setup).unwrap();
+    assert_eq!((packet.len, packet.short, packet.frame), (8, true, None));
+    assert_eq!(setup, setup_packet(0x80, 0, 0, 0, 2));
+
+    // frame numbers, and the frame OUT packets arrive in
+    host.sof(FrameNumber::WRAP - 1);
+    assert_eq!(dev.bus().frame_number(), FrameNumber(FrameNumber::WRAP - 1));
+    host.sof(3);
+    assert_eq!(dev.bus().frame_number(), FrameNumber(2));
+
+    let mut buf = [0; 512];
+    host.out(out_ep, &[7; 512]).unwrap();
+    assert_eq!(dev.bus().peek_len(out_addr).unwrap(), 512);
+    let packet = dev.bus().read_packet(out_addr, &mut buf).unwrap();
+    assert_eq!(
+        (packet.len, packet.short, packet.frame),
+        (512, false, Some(2))
+    );
+    assert!(matches!(
+        dev.bus().peek_len(out_addr),
+        Err(usb_device::UsbError::WouldBlock)
+    ));
+
+    // too small a buffer leaves the packet where it is
+    host.out(out_ep, &[1, 2, 3]).unwrap();
+    assert!(matches!(
+        dev.bus().read_packet(out_addr, &mut buf[..2]),
+        Err(usb_device::UsbError::BufferOverflow)
+    ));
+    let packet = dev.bus().read_packet(out_addr, &mut buf).unwrap();
+    assert_eq!((packet.len, packet.short), (3, true));
+
+    // a ZLP is short too
+    host.out(out_ep, &[]).unwrap();
+    assert!(dev.bus().read_packet(out_addr, &mut buf).unwrap().short);
+
+    // refills keep the endpoint going without completions
+    dev.bus().set_in_refill(in_addr, Some(refill)).unwrap();
+    class.in_.write(&[0]).unwrap();
+    let mut poll = || _ = dev.poll(&mut [&mut class]);
+    let packets: Vec<u8> = (0..4)
+        .map(|_| take_in(&host, &mut poll, in_ep).unwrap()[0])
+        .collect();
+    assert_eq!(packets, [0, 1, 2, 3]);
+    assert_eq!(take_in(&host, &mut poll, in_ep), Err(SimError::Nak));
+    assert!(dev.bus().set_in_refill(out_addr, Some(refill)).is_err());
+
+    dev.bus().set_endpoint_stalled(out_addr, true).unwrap();
+    let mut poll = || _ = dev.poll(&mut [&mut class]);
+    assert_eq!(
+        send_out(&host, &mut poll, out_ep, &[1]),
+        Err(SimError::Stall)
+    );
+}
@@ -0,0 +1,127 @@
+//! The host suspending the bus in the middle of enumeration, as some hubs
+//! and laptops do, through the `sim` bus.
+
+#![cfg(feature = "sim")]
+
+mod common;
+
+use common::{control_in, control_out, enumerate, setup_packet, take_in, EP0_SIZE};
+use lpc55_usbhs::sim::SimBus;
+use usb_device::{
+    bus::UsbBusAllocator,
+    device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid},
+};
+
+const GET_DEVICE_DESCRIPTOR: [u8; 8] = [0x80, 6, 0x00, 0x01, 0, 0, 18, 0];
+
+fn device(alloc: &UsbBusAllocator<SimBus>) -> UsbDevice<'_, SimBus> {
+    UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x0001))
+        .max_packet_size_0(EP0_SIZE as u8)
+        .build()
+}
+
+#[test]
+fn suspend_between_requests() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut dev = device(&alloc);
+
+    host.reset();
+    dev.poll(&mut []);
+    let descriptor = control_in(&host, &mut || _ = dev.poll(&mut []), GET_DEVICE_DESCRIPTOR)
+        .unwrap()
+        .concat();
+    assert_eq!(descriptor.len(), 18);
+
+    host.set_suspended(true);
+    dev.poll(&mut []);
+    assert_eq!(dev.state(), UsbDeviceState::Suspend);
+    host.set_suspended(false);
+    dev.poll(&mut []);
+    assert_eq!(dev.state(), UsbDeviceState::Default);
+
+    // enumeration goes on where it stopped
+    let mut poll = || _ = dev.poll(&mut []);
+    control_out(&host, &mut poll, setup_packet(0x00, 5, 3, 0, 0), &[]).unwrap();
+    control_out(&host, &mut poll, setup_packet(0x00, 9, 1, 0, 0), &[]).unwrap();
+    assert_eq!(host.address(), 3);
+    assert_eq!(dev.state(), UsbDeviceState::Configured);
+}
+
+#[test]
+fn suspend_in_the_data_stage() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut dev = device(&alloc);
+
+    host.reset();
+    dev.poll(&mut []);
+    host.setup(GET_DEVICE_DESCRIPTOR);
+    dev.poll(&mut []);
+
+    // suspended before the host collected the data stage
+    host.set_suspended(true);
+    dev.poll(&mut []);
+    host.set_suspended(false);
+    dev.poll(&mut []);
+
+    // the armed packet is still there, and the transfer completes
+    let mut poll = || _ = dev.poll(&mut []);
+    let packet = take_in(&host, &mut poll, 0).unwrap();
+    assert_eq!(packet.len(), 18);
+    common::send_out(&host, &mut poll, 0, &[]).unwrap();
+    poll();
+
+    control_out(&host, &mut poll, setup_packet(0x00, 5, 3, 0, 0), &[]).unwrap();
+    assert_eq!(host.address(), 3);
+}
+
+#[test]
+fn setup_after_resume_supersedes_the_transfer() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut dev = device(&alloc);
+
+    host.reset();
+    dev.poll(&mut []);
+    host.setup(GET_DEVICE_DESCRIPTOR);
+    dev.poll(&mut []);
+    host.set_suspended(true);
+    dev.poll(&mut []);
+    host.set_suspended(false);
+    dev.poll(&mut []);
+
+    // the host gave up on the transfer and starts over
+    let mut poll = || _ = dev.poll(&mut []);
+    let descriptor = control_in(&host, &mut poll, GET_DEVICE_DESCRIPTOR)
+        .unwrap()
+        .concat();
+    assert_eq!(descriptor.len(), 18);
+    control_out(&host, &mut poll, setup_packet(0x00, 5, 3, 0, 0), &[]).unwrap();
+    assert_eq!(host.address(), 3);
+}
+
+#[test]
+fn reset_after_suspend_restarts_enumeration() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let mut dev = device(&alloc);
+
+    let mut poll = || _ = dev.poll(&mut []);
+    host.reset();
+    poll();
+    control_in(&host, &mut poll, GET_DEVICE_DESCRIPTOR).unwrap();
+    control_out(&host, &mut poll, setup_packet(0x00, 5, 3, 0, 0), &[]).unwrap();
+    assert_eq!(host.address(), 3);
+
+    host.set_suspended(true);
+    dev.poll(&mut []);
+    assert_eq!(dev.state(), UsbDeviceState::Suspend);
+
+    // the host resets instead of resuming
+    let mut poll = || _ = dev.poll(&mut []);
+    let descriptor = enumerate(&host, &mut poll, 9);
+    assert_eq!(descriptor.len(), 18);
+    assert_eq!(host.address(), 9);
+    assert_eq!(dev.state(), UsbDeviceState::Configured);
+}
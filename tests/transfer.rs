@@ -0,0 +1,120 @@
+//! `TransferReader` on the `sim` bus, packet by packet and on a primed
+//! endpoint that hands over several packets per read.
+
+#![cfg(feature = "sim")]
+
+use lpc55_usbhs::{sim::SimBus, TransferReader};
+use usb_device::{
+    bus::UsbBusAllocator,
+    class::UsbClass,
+    device::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+    endpoint::EndpointOut,
+    UsbError,
+};
+
+const BULK_SIZE: u16 = 512;
+
+struct Sink<'a> {
+    out: EndpointOut<'a, SimBus>,
+}
+
+impl UsbClass<SimBus> for Sink<'_> {}
+
+// endpoints can only be read once the device froze the allocator
+fn device<'a>(alloc: &'a UsbBusAllocator<SimBus>) -> (Sink<'a>, UsbDevice<'a, SimBus>) {
+    let sink = Sink {
+        out: alloc.bulk(BULK_SIZE),
+    };
+    let dev = UsbDeviceBuilder::new(alloc, UsbVidPid(0x1209, 0x0001))
+        .max_packet_size_0(64)
+        .build();
+    (sink, dev)
+}
+
+#[test]
+fn short_packet_ends_the_transfer() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let (sink, _dev) = device(&alloc);
+    let ep = sink.out.address().index() as u8;
+    let mut buf = [0; 2048];
+    let mut reader = TransferReader::new(&mut buf);
+
+    assert!(matches!(reader.read(&sink.out), Err(UsbError::WouldBlock)));
+    host.out(ep, &[1; 512]).unwrap();
+    assert!(!reader.read(&sink.out).unwrap());
+    host.out(ep, &[2; 100]).unwrap();
+    assert!(reader.read(&sink.out).unwrap());
+    assert_eq!(reader.len(), 612);
+    assert_eq!(reader.data()[511..513], [1, 2]);
+
+    // complete until reset, without touching the endpoint
+    host.out(ep, &[3; 4]).unwrap();
+    assert!(reader.read(&sink.out).unwrap());
+    reader.reset();
+    assert!(reader.read(&sink.out).unwrap());
+    assert_eq!(reader.data(), [3; 4]);
+}
+
+#[test]
+fn full_buffer_waits_for_the_zlp() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let (sink, _dev) = device(&alloc);
+    let ep = sink.out.address().index() as u8;
+    let mut buf = [0; 1024];
+    let mut reader = TransferReader::new(&mut buf);
+
+    for _ in 0..2 {
+        host.out(ep, &[7; 512]).unwrap();
+        assert!(!reader.read(&sink.out).unwrap());
+    }
+    host.out(ep, &[]).unwrap();
+    assert!(reader.read(&sink.out).unwrap());
+    assert_eq!(reader.len(), 1024);
+}
+
+#[test]
+fn expected_length_ends_the_transfer() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let (sink, _dev) = device(&alloc);
+    let ep = sink.out.address().index() as u8;
+    let mut buf = [0; 2048];
+    let mut reader = TransferReader::with_length(&mut buf, 1024);
+
+    host.out(ep, &[7; 512]).unwrap();
+    assert!(!reader.read(&sink.out).unwrap());
+    host.out(ep, &[8; 512]).unwrap();
+    assert!(reader.read(&sink.out).unwrap());
+    assert!(reader.is_complete());
+
+    reader.reset_with_length(0);
+    assert!(reader.is_complete());
+}
+
+#[test]
+fn primed_reads_are_short_off_a_packet_boundary() {
+    let (bus, host) = SimBus::new();
+    let alloc = UsbBusAllocator::new(bus);
+    let (sink, dev) = device(&alloc);
+    let addr = sink.out.address();
+    let ep = addr.index() as u8;
+    let mut buf = [0; 4096];
+    let mut reader = TransferReader::new(&mut buf);
+    dev.bus().prime_out(addr, 1536).unwrap();
+
+    // a whole number of packets, longer than one: the transfer goes on
+    host.out(ep, &[1; 512]).unwrap();
+    host.out(ep, &[1; 512]).unwrap();
+    host.out(ep, &[1; 512]).unwrap();
+    assert!(!reader.read(&sink.out).unwrap());
+    assert_eq!(reader.len(), 1536);
+
+    // ended by a short packet, so the read isn't a multiple of 512
+    host.out(ep, &[2; 512]).unwrap();
+    host.out(ep, &[2; 100]).unwrap();
+    assert!(matches!(dev.bus().peek_len(addr), Ok(612)));
+    assert!(reader.read(&sink.out).unwrap());
+    assert_eq!(reader.len(), 2148);
+}